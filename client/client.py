@@ -1,72 +1,93 @@
-import socket
 import sys
 import threading
 import json
+import http.client
 
-table_amount = 100
 item_amount = 20
 num_thread = 10
 
 
+def send_recv(conn, method, path, body=None):
+    headers = {"Content-Type": "application/json"} if body is not None else {}
+    conn.request(method, path, body=body, headers=headers)
+    res = conn.getresponse()
+    res.read()
+    return res.status
 
-def send_recv(socket, msg):
-    socket.send(msg)
-    _ = socket.recv(512)
+def send_recv_json(conn, method, path):
+    conn.request(method, path)
+    res = conn.getresponse()
+    data = res.read()
 
-def send_recv_json(socket, msg):
-    socket.send(msg)
-    res = ""
-    data = socket.recv(512)
-    res += data.decode("utf-8")
-    flag = len(data) == 512
-    while flag:
-        data = socket.recv(512)
-        res += data.decode("utf-8")
-        flag = len(data) == 512  
+    return json.loads(data.decode("utf-8"))
 
-    return json.loads(res)
-
-
-def run_client_add(host, port, thread_id):
-    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+def connect(host, port):
+    conn = http.client.HTTPConnection(host, port)
 
     try:
-        s.connect((host, port))
+        conn.connect()
         print('Connect to %s:%d' % (host, port))
     except:
         print('Unable to connect %s:%d' % (host, port))
         exit(1)
 
-    for table_id in range(0, table_amount):
+    return conn
+
+
+def fetch_menu_ids(host, port):
+    conn = connect(host, port)
+    menu = send_recv_json(conn, "GET", "/menu")
+    conn.close()
+
+    return [item["item_id"] for item in menu["data"] if item["available"]]
+
+
+def fetch_table_ids(host, port):
+    conn = connect(host, port)
+    tables = send_recv_json(conn, "GET", "/tables")
+    conn.close()
+
+    table_ids = [table["table_id"] for table in tables["data"] if not table["disabled"]]
+
+    # orders are only taken at tables with a party seated; a table that is
+    # still taken from an earlier run answers 409, which is fine here
+    conn = connect(host, port)
+    for table_id in table_ids:
+        send_recv(conn, "POST", "/seat/{}".format(table_id), json.dumps({"party_size": 1}))
+    conn.close()
+
+    return table_ids
+
+
+def run_client_add(host, port, thread_id, menu_ids, table_ids):
+    conn = connect(host, port)
+
+    for table_id in table_ids:
         item_id_start = item_amount * thread_id
         item_id_end = item_amount * (thread_id + 1)
 
-        for item_id in range(item_id_start, item_id_end):
-            send_recv(s, "POST /add/{}/{}".format(table_id, item_id).encode())
+        # the same dish may be ordered many times on one table
+        items = [{"item_id": menu_ids[n % len(menu_ids)]}
+                 for n in range(item_id_start, item_id_end)]
+        body = json.dumps({"items": items})
+        send_recv(conn, "POST", "/batch/{}".format(table_id), body)
 
-    s.close()
+    conn.close()
 
-def run_client_check_all(host, port):
-    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-
-    try:
-        s.connect((host, port))
-        print('Connect to %s:%d' % (host, port))
-    except:
-        print('Unable to connect %s:%d' % (host, port))
-        exit(1)
+def run_client_check_all(host, port, table_ids):
+    conn = connect(host, port)
 
     print("=== Checking ===")
 
-    for table_id in range(0, table_amount):
-        response = send_recv_json(s, "GET /query/{}".format(table_id).encode())
-        if(len(response) != item_amount * num_thread):
+    for table_id in table_ids:
+        response = send_recv_json(conn, "GET", "/query/{}".format(table_id))
+        if(len(response["data"]["items"]) != item_amount * num_thread):
             print("table {} has incorrect amount of items".format(table_id))
             exit(1)
 
     print("All table has correct amount of items")
 
-    s.close()
+    conn.close()
 
 
 if __name__ == '__main__':
@@ -75,7 +96,7 @@ if __name__ == '__main__':
 
     if len(sys.argv) == 3:
         host = sys.argv[1]
-        port = sys.argv[2]
+        port = int(sys.argv[2])
     elif len(sys.argv) == 1:
         pass
     else:
@@ -83,11 +104,13 @@ if __name__ == '__main__':
         exit(1)
 
     threads = []
+    menu_ids = fetch_menu_ids(host, port)
+    table_ids = fetch_table_ids(host, port)
 
     print("Running {} threads...".format(num_thread))
-    print("Each thread adds {} items for each {} tables.".format(item_amount, table_amount))
+    print("Each thread adds {} items for each {} tables.".format(item_amount, len(table_ids)))
     for i in range(0, num_thread):
-        t = threading.Thread(target=run_client_add, args=(host, port, i))
+        t = threading.Thread(target=run_client_add, args=(host, port, i, menu_ids, table_ids))
         threads.append(t)
         t.start()
 
@@ -95,4 +118,4 @@ if __name__ == '__main__':
         t = threads[i]
         t.join()
 
-    run_client_check_all(host, port)
+    run_client_check_all(host, port, table_ids)
@@ -1,69 +1,1027 @@
-use super::restaurant::Restaurant;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str;
 
-pub fn add_item(tid: u32, item_data: &str, restaurant: Restaurant) -> String {
-    let data = item_data.split(",").collect::<Vec<&str>>();
-    let iid = data[0].parse::<u32>().unwrap();
+use super::bill::{Bill, Split};
+use super::error::{Error, ErrorBody};
+use super::http::{self, Response};
+use super::item::{Item, ItemState, LineSpec, LineUpdate};
+use super::kitchen::Ticket;
+use super::layout::{TableConfig, TableUpdate};
+use super::menu::{Menu, MenuItem, MenuItemUpdate};
+use super::reservation::{ReservationRequest, ReservationUpdate};
+use super::restaurant::{undo_on_error, undo_on_error_tables, Restaurant};
+use super::storage::Op;
+use super::table::{self, Table, TableStatus};
+use super::waitlist::{Occupancy, WaitlistRequest, DEFAULT_DINING_MS};
 
-    let t = restaurant.get_table(tid);
-    t.lock().unwrap().add_item(iid);
+/// The most entries one batch may hold.
+pub const MAX_BATCH: usize = 100;
 
-    "{ msg: \"success\"}".to_owned()
+/// Body of `POST /add/:table_id`, and one entry of a batch.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct AddItemRequest {
+    pub item_id: u32,
+    #[serde(flatten)]
+    pub spec: LineSpec,
 }
-pub fn remove_item(tid: u32, iid: u32, restaurant: Restaurant) -> String {
-    let t = restaurant.get_table(tid);
-    let result = t.lock().unwrap().remove_item(iid);
-    match result {
-        Some(_) => "{ \"msg\": \"success\"}".to_owned(),
-        None => {
-            return "{ \"msg\": \"cannot remove, not exist\"}".to_owned();
+
+/// How a batch goes when one of its entries cannot be applied.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    /// One bad entry fails the whole batch and nothing is applied.
+    #[default]
+    AllOrNothing,
+    /// The good entries are applied and each one reports how it went.
+    PerItem,
+}
+
+/// Body of `POST /batch/:table_id`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct BatchAddRequest {
+    pub items: Vec<AddItemRequest>,
+    #[serde(default)]
+    pub mode: BatchMode,
+}
+
+/// One entry of `DELETE /batch/:table_id`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct RemoveRequest {
+    pub item_id: u32,
+    /// Cancel only this serving, otherwise every one not served yet.
+    #[serde(default)]
+    pub line_id: Option<u32>,
+}
+
+/// Body of `DELETE /batch/:table_id`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct BatchRemoveRequest {
+    pub items: Vec<RemoveRequest>,
+    #[serde(default)]
+    pub mode: BatchMode,
+}
+
+/// Body of `PUT /status/:table_id/:item_id/:line_id`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct SetStateRequest {
+    pub state: ItemState,
+}
+
+/// Body of `POST /seat/:table_id`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct SeatRequest {
+    pub party_size: u32,
+    /// Who waits on the table.
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+/// Body of `POST /reservations/:reservation_id/seat`, which may also be
+/// left empty.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct ArrivalRequest {
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+/// Body of `POST /waitlist/:entry_id/seat`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct WaitlistSeatRequest {
+    pub table_id: u32,
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+/// Body of `POST /transfer/:from_table_id/:to_table_id`, which may also be
+/// left empty to move everything.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct TransferRequest {
+    /// Move only the servings of this item.
+    #[serde(default)]
+    pub item_id: Option<u32>,
+    /// Move only this serving of `item_id`.
+    #[serde(default)]
+    pub line_id: Option<u32>,
+}
+
+/// Body of `POST /merge`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct MergeRequest {
+    /// The tables to push together, the first one taking the orders.
+    pub tables: Vec<u32>,
+}
+
+/// Body of `POST /split/:table_id`, which may also be left empty to leave
+/// every line where it is.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct SplitRequest {
+    /// The table each line moves to.
+    #[serde(default)]
+    pub lines: BTreeMap<u32, u32>,
+}
+
+/// Body of `POST /checkout/:table_id`, which may also be left empty.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct CheckoutRequest {
+    /// How to share the bill out, if at all.
+    #[serde(default)]
+    pub split: Option<Split>,
+}
+
+#[derive(Serialize)]
+struct AddItemResponse {
+    line_id: u32,
+}
+
+/// How one entry of a batch went, in the order the entries came in.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Added { line_id: u32 },
+    Removed { line_ids: Vec<u32> },
+    Failed { error: ErrorBody },
+}
+
+#[derive(Serialize)]
+struct TransferResponse {
+    table_id: u32,
+    line_ids: Vec<u32>,
+}
+
+/// Decodes a JSON request body.
+pub fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    let s = str::from_utf8(body).map_err(|_| Error::InvalidUtf8)?;
+    serde_json::from_str(s).map_err(|e| Error::MalformedBody(e.to_string()))
+}
+
+pub fn add_item(tid: u32, req: AddItemRequest, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let menu_item = orderable(&restaurant.menu().read().unwrap(), &req)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    t.check_open()?;
+    let now = restaurant.now();
+    let line_id = undo_on_error(&mut *t, |t| {
+        let item = place(&restaurant, t, &menu_item, req.spec, now)?;
+        let line_id = item.line_id();
+        log_placed(&restaurant, Op::Add { item }, &[line_id])?;
+        Ok(line_id)
+    })?;
+
+    Ok(tagged(Response::created(&AddItemResponse { line_id }), &t))
+}
+/// Adds several lines to a table under one lock, so nobody sees half of
+/// them. They are logged as one change.
+pub fn add_batch(
+    tid: u32,
+    req: BatchAddRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    check_batch(req.items.len())?;
+    let t = restaurant.session_of(tid)?;
+    // everything the menu can refuse is checked before the table is locked
+    let menu_items = {
+        let menu = restaurant.menu().read().unwrap();
+        req.items
+            .iter()
+            .map(|i| orderable(&menu, i))
+            .collect::<Vec<_>>()
+    };
+    if req.mode == BatchMode::AllOrNothing {
+        if let Some(Err(e)) = menu_items.iter().find(|m| m.is_err()) {
+            return Err(e.clone());
+        }
+    }
+
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    t.check_open()?;
+    let now = restaurant.now();
+    let results = undo_on_error(&mut *t, |t| {
+        let mut results = vec![];
+        let mut ops = vec![];
+        let mut line_ids = vec![];
+        for (req, menu_item) in req.items.into_iter().zip(menu_items) {
+            match menu_item {
+                Ok(menu_item) => {
+                    let item = place(&restaurant, t, &menu_item, req.spec, now)?;
+                    results.push(BatchResult::Added {
+                        line_id: item.line_id(),
+                    });
+                    line_ids.push(item.line_id());
+                    ops.push(Op::Add { item });
+                }
+                Err(e) => results.push(BatchResult::Failed { error: e.body() }),
+            }
+        }
+        if !ops.is_empty() {
+            log_placed(&restaurant, Op::Batch { ops }, &line_ids)?;
+        }
+        Ok(results)
+    })?;
+
+    let res = match req.mode {
+        BatchMode::AllOrNothing => Response::created(&results),
+        BatchMode::PerItem => Response::ok(&results),
+    };
+    Ok(tagged(res, &t))
+}
+/// Checks a line before any table is locked and returns the dish it is for.
+fn orderable(menu: &Menu, req: &AddItemRequest) -> Result<MenuItem, Error> {
+    req.spec.validate()?;
+    let menu_item = menu.orderable(req.item_id)?;
+    menu_item.check_modifiers(&req.spec.modifiers)?;
+    Ok(menu_item.clone())
+}
+/// Adds a line to the locked table and sends it to the kitchen.
+fn place(
+    restaurant: &Restaurant,
+    t: &mut Table,
+    menu_item: &MenuItem,
+    spec: LineSpec,
+    now: u64,
+) -> Result<Item, Error> {
+    let line_id = t.add_line(menu_item, spec, now);
+
+    // the ready time follows from where the ticket lands in the queue
+    let ticket = Ticket::new(t.check_line(menu_item.item_id, line_id).unwrap());
+    let ready_at = restaurant.kitchen().lock().unwrap().enqueue(ticket, now);
+    t.set_ready_at(line_id, ready_at)?;
+
+    Ok(t.check_line(menu_item.item_id, line_id).unwrap().clone())
+}
+/// Logs `op` for the lines `place` just sent to the kitchen, taking them
+/// off the queue again if it cannot be logged.
+fn log_placed(restaurant: &Restaurant, op: Op, line_ids: &[u32]) -> Result<(), Error> {
+    let logged = restaurant.log(op);
+    if logged.is_err() {
+        let mut kitchen = restaurant.kitchen().lock().unwrap();
+        for line_id in line_ids {
+            kitchen.remove(*line_id);
+        }
+    }
+    logged
+}
+/// Frees the cooks of lines that are done with, once that is logged.
+fn unqueue(restaurant: &Restaurant, line_ids: impl IntoIterator<Item = u32>) {
+    let mut kitchen = restaurant.kitchen().lock().unwrap();
+    for line_id in line_ids {
+        kitchen.remove(line_id);
+    }
+}
+/// Tells the client the version `t` is at now, to send back in `If-Match`.
+fn tagged(res: Response, t: &Table) -> Response {
+    res.with_header("ETag", &http::etag(t.version()))
+}
+fn check_batch(len: usize) -> Result<(), Error> {
+    if len == 0 || len > MAX_BATCH {
+        return Err(Error::MalformedBody(format!(
+            "a batch takes between 1 and {} items",
+            MAX_BATCH
+        )));
+    }
+    Ok(())
+}
+/// Cancels every serving of the item that has not been served yet.
+pub fn remove_item(tid: u32, iid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let now = restaurant.now();
+    let cancelled = undo_on_error(&mut *t, |t| {
+        let cancelled = t
+            .cancel_item(iid, now)?
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let ops = cancelled.iter().map(|i| Op::set_state(i, now)).collect();
+        restaurant.log(Op::Batch { ops })?;
+        Ok(cancelled)
+    })?;
+    unqueue(&restaurant, cancelled.iter().map(|i| i.line_id()));
+
+    let views = cancelled.iter().map(|i| i.view(now)).collect::<Vec<_>>();
+    let res = Response::ok(&views);
+    Ok(tagged(res, &t))
+}
+pub fn remove_line(
+    tid: u32,
+    iid: u32,
+    line_id: u32,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let now = restaurant.now();
+    let item = undo_on_error(&mut *t, |t| {
+        let item = t.cancel_line(iid, line_id, now)?.clone();
+        restaurant.log(Op::set_state(&item, now))?;
+        Ok(item)
+    })?;
+    unqueue(&restaurant, [line_id]);
+
+    let res = Response::ok(&item.view(now));
+    Ok(tagged(res, &t))
+}
+/// Cancels several items under one lock, logged as one change.
+pub fn remove_batch(
+    tid: u32,
+    req: BatchRemoveRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    check_batch(req.items.len())?;
+    let t = restaurant.session_of(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let picked = req
+        .items
+        .iter()
+        .map(|i| t.cancellable(i.item_id, i.line_id))
+        .collect::<Vec<_>>();
+    if req.mode == BatchMode::AllOrNothing {
+        if let Some(Err(e)) = picked.iter().find(|p| p.is_err()) {
+            return Err(e.clone());
+        }
+    }
+
+    let now = restaurant.now();
+    let mut cancelled = vec![];
+    let results = undo_on_error(&mut *t, |t| {
+        let mut results = vec![];
+        let mut ops = vec![];
+        for lines in picked {
+            match lines {
+                Ok(lines) => {
+                    // an earlier entry may have taken some of the same lines
+                    let line_ids = lines
+                        .into_iter()
+                        .filter(|l| !cancelled.contains(l))
+                        .collect::<Vec<_>>();
+                    for item in t.cancel_lines(&line_ids, now) {
+                        ops.push(Op::set_state(item, now));
+                    }
+                    cancelled.extend_from_slice(&line_ids);
+                    results.push(BatchResult::Removed { line_ids });
+                }
+                Err(e) => results.push(BatchResult::Failed { error: e.body() }),
+            }
+        }
+        if !ops.is_empty() {
+            restaurant.log(Op::Batch { ops })?;
         }
+        Ok(results)
+    })?;
+    unqueue(&restaurant, cancelled);
+
+    Ok(tagged(Response::ok(&results), &t))
+}
+/// Changes a line the kitchen has not started on, without cancelling it.
+pub fn update_line(
+    tid: u32,
+    iid: u32,
+    line_id: u32,
+    update: LineUpdate,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let menu_item = restaurant.menu().read().unwrap().get(iid)?.clone();
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let now = restaurant.now();
+    let mut item = t
+        .check_line(iid, line_id)
+        .ok_or(Error::ItemNotFound(iid))?
+        .updated(&menu_item, update, now)?;
+
+    // a cook may have picked the ticket up before the table heard of it
+    let mut kitchen = restaurant.kitchen().lock().unwrap();
+    let ready_at = kitchen
+        .update(Ticket::new(&item), now)
+        .ok_or(Error::LineClosed {
+            line_id,
+            state: ItemState::Cooking,
+        })?;
+    item.set_ready_at(ready_at);
+    if let Err(e) = restaurant.log(Op::Update { item: item.clone() }) {
+        // the kitchen is still locked, so the ticket is still queued
+        kitchen.update(Ticket::new(t.check_line(iid, line_id).unwrap()), now);
+        return Err(e);
+    }
+    drop(kitchen);
+    let item = t.replace(item);
+
+    let res = Response::ok(&item.view(now));
+    Ok(tagged(res, &t))
+}
+pub fn set_state(
+    tid: u32,
+    iid: u32,
+    line_id: u32,
+    req: SetStateRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let now = restaurant.now();
+    let item = undo_on_error(&mut *t, |t| {
+        let item = t.set_state(iid, line_id, req.state, now)?.clone();
+        restaurant.log(Op::set_state(&item, now))?;
+        Ok(item)
+    })?;
+    // marking a line done by hand frees its cook
+    if matches!(req.state, ItemState::Ready | ItemState::Cancelled) {
+        unqueue(&restaurant, [line_id]);
     }
+
+    let res = Response::ok(&item.view(now));
+    Ok(tagged(res, &t))
+}
+/// Moves lines to another table as they are, in one step: both tables are
+/// locked for the whole move.
+pub fn transfer(
+    from: u32,
+    to: u32,
+    req: TransferRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    // between tables pushed together the lines go to the ones holding the
+    // sessions
+    let from = restaurant.session_of(from)?.lock().unwrap().id();
+    let to = restaurant.session_of(to)?.lock().unwrap().id();
+    restaurant.with_table_pair(from, to, |src, dst| {
+        restaurant.check_version(src)?;
+        dst.check_open()?;
+        let line_ids = src.pick_lines(req.item_id, req.line_id)?;
+        undo_on_error_tables(&mut [src, dst], |tables| {
+            let lines = tables[0].take_lines(&line_ids);
+            tables[1].put_lines(lines);
+            restaurant.log(Op::Transfer {
+                from,
+                to,
+                line_ids: line_ids.clone(),
+            })
+        })?;
+
+        let mut kitchen = restaurant.kitchen().lock().unwrap();
+        for line_id in line_ids.iter() {
+            kitchen.retarget(*line_id, to);
+        }
+        drop(kitchen);
+
+        Ok(Response::ok(&TransferResponse {
+            table_id: to,
+            line_ids,
+        }))
+    })
+}
+/// Pushes tables together into one session: the first table takes over the
+/// orders of the others and seats their guests in its party.
+pub fn merge_tables(req: MergeRequest, restaurant: Restaurant) -> Result<Response, Error> {
+    restaurant.with_tables(&req.tables, |tables| {
+        if let Some(lead) = tables.first() {
+            restaurant.check_version(lead)?;
+        }
+        let moved = undo_on_error_tables(tables, |tables| {
+            let moved = table::merge(tables)?;
+            restaurant.log(Op::Merge {
+                table_ids: req.tables.clone(),
+            })?;
+            Ok(moved)
+        })?;
+        let mut kitchen = restaurant.kitchen().lock().unwrap();
+        for line_id in moved {
+            kitchen.retarget(line_id, tables[0].id());
+        }
+        drop(kitchen);
+
+        let floor = tables.iter().map(|t| t.floor()).collect::<Vec<_>>();
+        Ok(Response::ok(&floor))
+    })
+}
+/// Takes the tables pushed together with `tid` apart again, moving lines
+/// as the request maps them.
+pub fn split_tables(
+    tid: u32,
+    req: SplitRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    restaurant.with_session(tid, |tables| {
+        restaurant.check_version(tables[0])?;
+        let moved = undo_on_error_tables(tables, |tables| {
+            let moved = table::split(tables, &req.lines)?;
+            restaurant.log(Op::Split {
+                table_id: tables[0].id(),
+                lines: req.lines.clone(),
+            })?;
+            Ok(moved)
+        })?;
+        let mut kitchen = restaurant.kitchen().lock().unwrap();
+        for (line_id, to) in moved {
+            kitchen.retarget(line_id, to);
+        }
+        drop(kitchen);
+
+        let floor = tables.iter().map(|t| t.floor()).collect::<Vec<_>>();
+        Ok(Response::ok(&floor))
+    })
+}
+/// Bills the served items, archives the session and clears the table for
+/// the next party.
+pub fn checkout(tid: u32, req: CheckoutRequest, restaurant: Restaurant) -> Result<Response, Error> {
+    // tables pushed together get one bill and are all cleared
+    restaurant.with_session(tid, |tables| {
+        restaurant.check_version(tables[0])?;
+        let session = tables[0].session()?;
+
+        let mut bills = restaurant.bills().lock().unwrap();
+        let bill_id = bills.next_id();
+        let mut bill = Bill::new(
+            bill_id,
+            tables[0].id(),
+            session,
+            restaurant.charges(),
+            restaurant.now(),
+        )
+        .with_party(tables[0].party().cloned());
+        if let Some(split) = &req.split {
+            bill = bill.split(split)?;
+        }
+        restaurant.log(Op::Checkout { bill: bill.clone() })?;
+        bills.insert(bill);
+        tables.iter_mut().for_each(|t| t.clear());
+
+        Ok(Response::created(bills.get(bill_id)?))
+    })
 }
-pub fn query_all(tid: u32, restaurant: Restaurant) -> String {
-    let t = restaurant.get_table(tid);
-    let output = t.lock().unwrap().print_items();
+pub fn seat_table(tid: u32, req: SeatRequest, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.get_table(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let party = undo_on_error(&mut *t, |t| {
+        let party = t
+            .seat(req.party_size, req.server, restaurant.now())?
+            .clone();
+        restaurant.log(Op::Seat {
+            party: party.clone(),
+        })?;
+        Ok(party)
+    })?;
 
-    output
+    Ok(tagged(Response::created(&party), &t))
 }
-pub fn query_one(tid: u32, iid: u32, restaurant: Restaurant) -> String {
-    let t = restaurant.get_table(tid);
-    let s = t.lock().unwrap().print_item(iid);
-    s
+pub fn unseat_table(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.get_table(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let party = undo_on_error(&mut *t, |t| {
+        let party = t.unseat()?;
+        restaurant.log(Op::Unseat { table_id: tid })?;
+        Ok(party)
+    })?;
+
+    Ok(tagged(Response::ok(&party), &t))
+}
+pub fn list_reservations(restaurant: Restaurant) -> Result<Response, Error> {
+    let reservations = restaurant.reservations().lock().unwrap();
+    Ok(Response::ok(&reservations.list().collect::<Vec<_>>()))
+}
+pub fn query_reservation(rid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let reservations = restaurant.reservations().lock().unwrap();
+    Ok(Response::ok(reservations.get(rid)?))
+}
+pub fn create_reservation(
+    req: ReservationRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let tables = restaurant.layout();
+    let mut reservations = restaurant.reservations().lock().unwrap();
+    let reservation = undo_on_error(&mut *reservations, |reservations| {
+        let reservation = reservations.book(req, &tables, restaurant.now())?;
+        restaurant.log(Op::Reservation {
+            reservation: reservation.clone(),
+        })?;
+        Ok(reservation)
+    })?;
+
+    Ok(Response::created(&reservation))
+}
+pub fn update_reservation(
+    rid: u32,
+    update: ReservationUpdate,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let tables = restaurant.layout();
+    let mut reservations = restaurant.reservations().lock().unwrap();
+    let reservation = undo_on_error(&mut *reservations, |reservations| {
+        let reservation = reservations.modify(rid, update, &tables, restaurant.now())?;
+        restaurant.log(Op::Reservation {
+            reservation: reservation.clone(),
+        })?;
+        Ok(reservation)
+    })?;
+
+    Ok(Response::ok(&reservation))
+}
+pub fn cancel_reservation(rid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let mut reservations = restaurant.reservations().lock().unwrap();
+    let reservation = undo_on_error(&mut *reservations, |reservations| {
+        let reservation = reservations.cancel(rid)?;
+        restaurant.log(Op::Reservation {
+            reservation: reservation.clone(),
+        })?;
+        Ok(reservation)
+    })?;
+
+    Ok(Response::ok(&reservation))
+}
+/// Seats the party of a booking at its table.
+pub fn seat_reservation(
+    rid: u32,
+    req: ArrivalRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let tid = restaurant.reservations().lock().unwrap().get(rid)?.table_id;
+    let t = restaurant.get_table(tid)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let mut reservations = restaurant.reservations().lock().unwrap();
+    let booking = reservations.booked(rid)?;
+    // the booking may have moved to another table before the lock was taken
+    if booking.table_id != tid {
+        return Err(Error::ReservationConflict(tid));
+    }
+
+    let party_size = booking.party_size;
+    let now = restaurant.now();
+    let reservation = undo_on_error(&mut *t, |t| {
+        undo_on_error(&mut *reservations, |reservations| {
+            let party = t.seat(party_size, req.server, now)?.clone();
+            let reservation = reservations.seat(rid, now)?;
+            // the table and the booking change together or not at all
+            let ops = vec![
+                Op::Seat { party },
+                Op::Reservation {
+                    reservation: reservation.clone(),
+                },
+            ];
+            restaurant.log(Op::Batch { ops })?;
+            Ok(reservation)
+        })
+    })?;
+
+    Ok(Response::created(&reservation))
+}
+pub fn list_waitlist(restaurant: Restaurant) -> Result<Response, Error> {
+    let waitlist = restaurant.waitlist().lock().unwrap();
+    Ok(Response::ok(&waitlist.list().collect::<Vec<_>>()))
+}
+pub fn query_waitlist_entry(eid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let waitlist = restaurant.waitlist().lock().unwrap();
+    Ok(Response::ok(waitlist.get(eid)?))
+}
+/// Adds a walk-in party to the waitlist, quoting a wait from how long the
+/// tables have been taken.
+pub fn join_waitlist(req: WaitlistRequest, restaurant: Restaurant) -> Result<Response, Error> {
+    let tables = restaurant
+        .tables()
+        .iter()
+        .filter_map(|t| {
+            let t = t.lock().unwrap();
+            let occupancy = Occupancy {
+                capacity: t.config().capacity,
+                seated_at: match t.status() {
+                    TableStatus::Free => None,
+                    // a table still clearing up counts as just taken
+                    _ => Some(t.party().map_or(restaurant.now(), |p| p.seated_at)),
+                },
+            };
+            (!t.config().disabled).then_some(occupancy)
+        })
+        .collect::<Vec<_>>();
+    let dining_ms = restaurant
+        .bills()
+        .lock()
+        .unwrap()
+        .dining_time()
+        .unwrap_or(DEFAULT_DINING_MS);
+
+    let mut waitlist = restaurant.waitlist().lock().unwrap();
+    let entry = undo_on_error(&mut *waitlist, |waitlist| {
+        let entry = waitlist.join(req, &tables, dining_ms, restaurant.now())?;
+        restaurant.log(Op::Waitlist {
+            entry: entry.clone(),
+        })?;
+        Ok(entry)
+    })?;
+
+    Ok(Response::created(&entry))
+}
+pub fn leave_waitlist(eid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let mut waitlist = restaurant.waitlist().lock().unwrap();
+    let entry = undo_on_error(&mut *waitlist, |waitlist| {
+        let entry = waitlist.leave(eid, restaurant.now())?;
+        restaurant.log(Op::Waitlist {
+            entry: entry.clone(),
+        })?;
+        Ok(entry)
+    })?;
+
+    Ok(Response::ok(&entry))
+}
+/// The party that has waited longest and fits at the free table `tid`, or
+/// `null` if nobody does.
+pub fn next_on_waitlist(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.get_table(tid)?;
+    let t = t.lock().unwrap();
+    if t.config().disabled {
+        return Err(Error::TableDisabled(tid));
+    }
+    if t.status() != TableStatus::Free {
+        return Err(Error::TableOccupied(tid));
+    }
+
+    let waitlist = restaurant.waitlist().lock().unwrap();
+    Ok(Response::ok(&waitlist.next_for(t.config().capacity)))
+}
+/// Seats a waiting party and records how long it waited.
+pub fn seat_from_waitlist(
+    eid: u32,
+    req: WaitlistSeatRequest,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let t = restaurant.get_table(req.table_id)?;
+    let mut t = t.lock().unwrap();
+    restaurant.check_version(&t)?;
+    let mut waitlist = restaurant.waitlist().lock().unwrap();
+    let party_size = waitlist.waiting(eid)?.party_size;
+
+    let now = restaurant.now();
+    let entry = undo_on_error(&mut *t, |t| {
+        undo_on_error(&mut *waitlist, |waitlist| {
+            let party = t.seat(party_size, req.server, now)?.clone();
+            let entry = waitlist.seat(eid, req.table_id, now)?;
+            let ops = vec![
+                Op::Seat { party },
+                Op::Waitlist {
+                    entry: entry.clone(),
+                },
+            ];
+            restaurant.log(Op::Batch { ops })?;
+            Ok(entry)
+        })
+    })?;
+
+    Ok(Response::created(&entry))
+}
+pub fn query_floor(restaurant: Restaurant) -> Result<Response, Error> {
+    let floor = restaurant
+        .tables()
+        .iter()
+        .map(|t| t.lock().unwrap().floor())
+        .collect::<Vec<_>>();
+
+    Ok(Response::ok(&floor))
+}
+pub fn query_bill(bid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let bills = restaurant.bills().lock().unwrap();
+    Ok(Response::ok(bills.get(bid)?))
+}
+pub fn query_all(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let t = t.lock().unwrap();
+
+    Ok(tagged(Response::ok(&t.view(restaurant.now())), &t))
+}
+pub fn query_history(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let t = t.lock().unwrap();
+
+    Ok(tagged(Response::ok(&t.history(restaurant.now())), &t))
+}
+pub fn query_one(tid: u32, iid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let t = t.lock().unwrap();
+    let items = t.check_item(iid);
+    if items.is_empty() {
+        return Err(Error::ItemNotFound(iid));
+    }
+
+    let now = restaurant.now();
+    let views = items.iter().map(|i| i.view(now)).collect::<Vec<_>>();
+    Ok(Response::ok(&views))
+}
+pub fn query_line(
+    tid: u32,
+    iid: u32,
+    line_id: u32,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let t = restaurant.session_of(tid)?;
+    let t = t.lock().unwrap();
+    match t.check_line(iid, line_id) {
+        Some(item) => Ok(Response::ok(&item.view(restaurant.now()))),
+        None => Err(Error::ItemNotFound(iid)),
+    }
+}
+/// Cooks, how busy they are and the wait for a new order.
+pub fn query_kitchen(restaurant: Restaurant) -> Result<Response, Error> {
+    let kitchen = restaurant.kitchen().lock().unwrap();
+    Ok(Response::ok(&kitchen.status(restaurant.now())))
+}
+/// Tickets waiting for a cook, in the order they will be cooked.
+pub fn query_kitchen_queue(restaurant: Restaurant) -> Result<Response, Error> {
+    let kitchen = restaurant.kitchen().lock().unwrap();
+    Ok(Response::ok(&kitchen.queue(restaurant.now())))
+}
+pub fn list_menu(restaurant: Restaurant) -> Result<Response, Error> {
+    let menu = restaurant.menu().read().unwrap();
+    Ok(Response::ok(&menu.list()))
+}
+pub fn query_menu_item(iid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let menu = restaurant.menu().read().unwrap();
+    Ok(Response::ok(menu.get(iid)?))
+}
+pub fn create_menu_item(item: MenuItem, restaurant: Restaurant) -> Result<Response, Error> {
+    let item = change_menu(&restaurant, |menu| menu.create(item))?;
+    Ok(Response::created(&item))
+}
+pub fn update_menu_item(
+    iid: u32,
+    update: MenuItemUpdate,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    let item = change_menu(&restaurant, |menu| menu.update(iid, update))?;
+    Ok(Response::ok(&item))
+}
+pub fn retire_menu_item(iid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let item = change_menu(&restaurant, |menu| menu.retire(iid))?;
+    Ok(Response::ok(&item))
+}
+/// Makes one change to a menu entry and logs the entry as it is after.
+fn change_menu(
+    restaurant: &Restaurant,
+    change: impl FnOnce(&mut Menu) -> Result<&MenuItem, Error>,
+) -> Result<MenuItem, Error> {
+    let mut menu = restaurant.menu().write().unwrap();
+    undo_on_error(&mut *menu, |menu| {
+        let item = change(menu)?.clone();
+        restaurant.log(Op::Menu { item: item.clone() })?;
+        Ok(item)
+    })
+}
+pub fn list_tables(restaurant: Restaurant) -> Result<Response, Error> {
+    Ok(Response::ok(&restaurant.layout()))
+}
+pub fn query_table(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let t = restaurant.get_table(tid)?;
+    let t = t.lock().unwrap();
+    Ok(Response::ok(t.config()))
+}
+pub fn create_table(table: TableConfig, restaurant: Restaurant) -> Result<Response, Error> {
+    Ok(Response::created(&restaurant.add_table(table)?))
+}
+pub fn update_table(
+    tid: u32,
+    update: TableUpdate,
+    restaurant: Restaurant,
+) -> Result<Response, Error> {
+    Ok(Response::ok(&restaurant.update_table(tid, update)?))
+}
+pub fn disable_table(tid: u32, restaurant: Restaurant) -> Result<Response, Error> {
+    let update = TableUpdate {
+        disabled: Some(true),
+        ..Default::default()
+    };
+    Ok(Response::ok(&restaurant.update_table(tid, update)?))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bill::Charges;
+    use crate::clock::{ManualClock, MS_PER_MINUTE};
+    use crate::http::StatusCode;
+    use crate::kitchen::{self, Kitchen};
+    use crate::layout::Layout;
+    use crate::menu::{Menu, Modifier};
+    use crate::reservation;
+    use crate::storage::{self, FailingStorage, StorageKind, TestDir};
+    use serde_json::Value;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    /// Runs `test` against a fresh restaurant on every storage backend, with
+    /// a party at every table and `item_n` items already on table 0.
+    fn each_backend<F: Fn(Restaurant)>(table_n: u32, item_n: usize, test: F) {
+        for kind in StorageKind::all() {
+            let dir = TestDir::new("api");
+            let (storage, _) = storage::open(kind, &dir.0).unwrap();
+            let r =
+                Restaurant::new(Layout::sample(table_n), Menu::sample(1000)).with_storage(storage);
+            seat_all(&r);
+            let t = r.get_table(0).unwrap();
+            for i in 0..item_n {
+                t.lock()
+                    .unwrap()
+                    .add_item(&MenuItem::sample(i as u32), None, 0);
+            }
+            test(r);
+        }
+    }
+
+    /// Seats a party of two at every free table.
+    fn seat_all(r: &Restaurant) {
+        for config in r.layout() {
+            let req = SeatRequest {
+                party_size: 2,
+                server: None,
+            };
+            let _ = seat_table(config.table_id, req, r.clone());
+        }
+    }
+
+    fn data(res: Response) -> Value {
+        let v: Value = serde_json::from_str(&res.body).unwrap();
+        v["data"].clone()
+    }
 
-    fn create_restaurant(table_n: usize, item_n: usize) -> Restaurant {
-        let r = Restaurant::new(table_n);
-        let t = r.get_table(0);
-        for i in 0..item_n {
-            t.lock().unwrap().add_item(i as u32);
+    fn add(iid: u32) -> AddItemRequest {
+        AddItemRequest {
+            item_id: iid,
+            spec: LineSpec::default(),
+        }
+    }
+
+    fn line_of(res: Response) -> u32 {
+        data(res)["line_id"].as_u64().unwrap() as u32
+    }
+
+    fn serve(r: &Restaurant, tid: u32, iid: u32, line_id: u32) {
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            set_state(tid, iid, line_id, SetStateRequest { state }, r.clone()).unwrap();
+        }
+    }
+
+    /// Runs `test` on every backend that keeps data, handing it a function
+    /// that opens the same storage again, as after a restart, and seats a
+    /// party at every table that is free then.
+    fn each_persistent_backend<F: Fn(&dyn Fn() -> Restaurant)>(test: F) {
+        for kind in StorageKind::all() {
+            if kind == StorageKind::Memory {
+                continue;
+            }
+            let dir = TestDir::new("api-recovery");
+            let open = || {
+                let (storage, recovery) = storage::open(kind, &dir.0).unwrap();
+                let r = Restaurant::new(Layout::sample(2), Menu::sample(5)).with_storage(storage);
+                storage::restore(&r, recovery).unwrap();
+                seat_all(&r);
+                r
+            };
+            test(&open);
         }
-        r
     }
 
     #[test]
     fn test_api_query_all() {
-        let r = create_restaurant(1, 2);
+        each_backend(1, 2, |r| {
+            let output = data(query_all(0, r).unwrap());
 
-        let output = query_all(0, r);
+            assert_eq!(output["table_id"], 0);
+            assert_eq!(output["items"][0]["item_id"], 0);
+            assert_eq!(output["items"][1]["item_id"], 1);
+            assert_eq!(output["items"][1]["name"], "dish 1");
+            assert_eq!(output["items"][1]["price"], 200);
+        });
+    }
 
-        assert_eq!(output.contains("\"item_id\": 0"), true);
-        assert_eq!(output.contains("\"item_id\": 1"), true);
+    #[test]
+    fn test_api_query_empty_table() {
+        each_backend(1, 0, |r| {
+            let res = query_all(0, r).unwrap();
+            // seating the party was the one change so far
+            assert_eq!(
+                res.body,
+                "{\"data\":{\"table_id\":0,\"version\":1,\"items\":[]}}"
+            );
+        });
     }
 
     #[test]
     fn test_api_query_one() {
-        let r = create_restaurant(1, 2);
-        let r2 = r.clone();
+        each_backend(1, 2, |r| {
+            let r2 = r.clone();
 
-        let output = query_one(0, 1, r);
-        assert_eq!(output.contains("\"item_id\": 1"), true);
+            let output = data(query_one(0, 1, r).unwrap());
+            assert_eq!(output[0]["item_id"], 1);
 
-        let output2 = query_one(0, 3, r2);
-        assert_eq!(output2.contains("{\"msg\": \"not found\"}"), true);
+            let output2 = query_one(0, 3, r2);
+            assert_eq!(output2, Err(Error::ItemNotFound(3)));
+        });
     }
 
     #[test]
@@ -71,40 +1029,1273 @@ mod tests {
         let item_amount = 5;
         let item_id = 1;
 
-        let r = create_restaurant(1, item_amount);
-        let r2 = r.clone();
-        let r3 = r.clone();
+        each_backend(1, item_amount, |r| {
+            let r2 = r.clone();
+            let r3 = r.clone();
 
-        let output = remove_item(0, item_id, r);
-        assert_eq!(output.contains("success"), true);
+            let output = data(remove_item(0, item_id, r).unwrap());
+            assert_eq!(output[0]["item_id"], item_id);
 
-        assert_eq!(
-            r2.get_table(0).lock().unwrap().items_size(),
-            item_amount - 1
-        );
+            assert_eq!(
+                r2.get_table(0).unwrap().lock().unwrap().items_size(),
+                item_amount - 1
+            );
 
-        let output2 = remove_item(0, item_id, r3);
-        assert_eq!(output2.contains("cannot remove"), true);
+            let output2 = remove_item(0, item_id, r3);
+            assert_eq!(output2, Err(Error::ItemNotFound(item_id)));
+        });
+    }
+
+    #[test]
+    fn test_api_one_serving() {
+        each_backend(1, 0, |r| {
+            let first = add_item(0, add(3), r.clone()).unwrap();
+            assert_eq!(first.status, StatusCode::Created);
+            let line_id = data(first)["line_id"].as_u64().unwrap() as u32;
+            add_item(0, add(3), r.clone()).unwrap();
+
+            let output = data(query_line(0, 3, line_id, r.clone()).unwrap());
+            assert_eq!(output["line_id"], line_id);
+
+            remove_line(0, 3, line_id, r.clone()).unwrap();
+            assert_eq!(r.get_table(0).unwrap().lock().unwrap().items_size(), 1);
+
+            // a cancelled line is kept on record
+            let output = data(query_line(0, 3, line_id, r.clone()).unwrap());
+            assert_eq!(output["state"], "cancelled");
+            assert_eq!(output["history"].as_array().unwrap().len(), 2);
+
+            let output = data(query_history(0, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 2);
+            let output = data(query_all(0, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 1);
+        });
     }
 
     #[test]
     fn test_api_add_item() {
         let item_amount = 5;
 
-        let r = create_restaurant(1, item_amount);
+        each_backend(1, item_amount, |r| {
+            add_item(0, add(999), r.clone()).unwrap();
 
-        add_item(0, "999,", r.clone());
+            assert_eq!(
+                r.clone().get_table(0).unwrap().lock().unwrap().items_size(),
+                item_amount + 1
+            );
 
-        assert_eq!(
-            r.clone().get_table(0).lock().unwrap().items_size(),
-            item_amount + 1
-        );
+            add_item(0, add(777), r.clone()).unwrap();
 
-        add_item(0, "777", r.clone());
+            assert_eq!(
+                r.clone().get_table(0).unwrap().lock().unwrap().items_size(),
+                item_amount + 2
+            );
+        });
+    }
 
+    #[test]
+    fn test_api_parse_json() {
         assert_eq!(
-            r.clone().get_table(0).lock().unwrap().items_size(),
-            item_amount + 2
+            parse_json::<AddItemRequest>(b"{\"item_id\": 7}"),
+            Ok(add(7))
+        );
+        let req = parse_json::<AddItemRequest>(
+            b"{\"item_id\": 7, \"quantity\": 2, \"note\": \"no salt\"}",
         );
+        assert_eq!(req.unwrap().spec.quantity, 2);
+        assert_eq!(
+            parse_json::<AddItemRequest>(b"\xff"),
+            Err(Error::InvalidUtf8)
+        );
+        assert!(matches!(
+            parse_json::<AddItemRequest>(b"7,name"),
+            Err(Error::MalformedBody(_))
+        ));
+    }
+
+    #[test]
+    fn test_api_modifiers() {
+        each_backend(1, 0, |r| {
+            let update = MenuItemUpdate {
+                modifiers: Some(vec![Modifier {
+                    name: "extra pork".to_string(),
+                    price: 300,
+                    prep_time: 2,
+                }]),
+                ..Default::default()
+            };
+            update_menu_item(1, update, r.clone()).unwrap();
+
+            let req = parse_json(
+                br#"{"item_id": 1, "quantity": 2, "modifiers": ["extra pork"], "note": "no rush"}"#,
+            )
+            .unwrap();
+            let line_id = line_of(add_item(0, req, r.clone()).unwrap());
+            let output = data(query_line(0, 1, line_id, r.clone()).unwrap());
+            assert_eq!(output["price"], 200 + 300);
+            assert_eq!(output["modifiers"][0]["name"], "extra pork");
+            assert_eq!(output["modifiers"][0]["price"], 300);
+            let prepare_time = output["prepare_time"].as_u64().unwrap();
+            assert!((5 + 2..=14 + 2).contains(&prepare_time));
+
+            // the cooks see what to make and how
+            let queue = data(query_kitchen_queue(r.clone()).unwrap());
+            assert_eq!(queue[0]["quantity"], 2);
+            assert_eq!(queue[0]["modifiers"], serde_json::json!(["extra pork"]));
+            assert_eq!(queue[0]["note"], "no rush");
+
+            let req = parse_json(br#"{"item_id": 2, "modifiers": ["extra pork"]}"#).unwrap();
+            assert_eq!(
+                add_item(0, req, r.clone()),
+                Err(Error::ModifierNotAllowed {
+                    item_id: 2,
+                    modifier: "extra pork".to_string()
+                })
+            );
+        });
+    }
+
+    fn change(quantity: u32) -> LineUpdate {
+        LineUpdate {
+            quantity: Some(quantity),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_api_update_line() {
+        each_backend(1, 0, |r| {
+            let r = r.with_kitchen(Kitchen::new(1));
+            let first = line_of(add_item(0, add(1), r.clone()).unwrap());
+            let second = line_of(add_item(0, add(2), r.clone()).unwrap());
+            let third = line_of(add_item(0, add(3), r.clone()).unwrap());
+            kitchen::tick(&r);
+
+            let update = LineUpdate {
+                seat: Some(Some(2)),
+                note: Some("well done".to_string()),
+                ..change(3)
+            };
+            let output = data(update_line(0, 2, second, update, r.clone()).unwrap());
+            assert_eq!(output["quantity"], 3);
+            assert_eq!(output["seat"], 2);
+            assert_eq!(output["state"], "ordered");
+            assert_eq!(output["history"][1]["update"]["note"], "well done");
+            let queue = data(query_kitchen_queue(r.clone()).unwrap());
+            assert_eq!(queue[0]["line_id"], second);
+            assert_eq!(queue[0]["quantity"], 3);
+
+            assert_eq!(
+                update_line(0, 1, first, change(2), r.clone()),
+                Err(Error::LineClosed {
+                    line_id: first,
+                    state: ItemState::Cooking
+                })
+            );
+            assert_eq!(
+                update_line(0, 1, second, change(2), r.clone()),
+                Err(Error::ItemNotFound(1))
+            );
+            let update = LineUpdate {
+                modifiers: Some(vec!["extra pork".to_string()]),
+                ..Default::default()
+            };
+            assert!(matches!(
+                update_line(0, 3, third, update, r.clone()),
+                Err(Error::ModifierNotAllowed { .. })
+            ));
+
+            // the kitchen took the ticket but the table has not heard yet
+            remove_line(0, 1, first, r.clone()).unwrap();
+            r.kitchen().lock().unwrap().step(r.now());
+            assert_eq!(
+                update_line(0, 2, second, change(1), r.clone()),
+                Err(Error::LineClosed {
+                    line_id: second,
+                    state: ItemState::Cooking
+                })
+            );
+            let output = data(query_line(0, 2, second, r).unwrap());
+            assert_eq!(output["quantity"], 3);
+        });
+    }
+
+    #[test]
+    fn test_api_update_line_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            let line_id = line_of(add_item(0, add(1), r.clone()).unwrap());
+            update_line(0, 1, line_id, change(4), r.clone()).unwrap();
+            let before = data(query_line(0, 1, line_id, r.clone()).unwrap());
+            drop(r);
+
+            let r = open();
+            let output = data(query_line(0, 1, line_id, r).unwrap());
+            assert_eq!(output["quantity"], 4);
+            assert_eq!(output["history"], before["history"]);
+        });
+    }
+
+    #[test]
+    fn test_api_bad_input() {
+        each_backend(1, 0, |r| {
+            assert_eq!(
+                add_item(9999, add(1), r.clone()),
+                Err(Error::TableNotFound(9999))
+            );
+            assert_eq!(query_all(9999, r), Err(Error::TableNotFound(9999)));
+        });
+    }
+
+    #[test]
+    fn test_api_query_ready_time() {
+        each_backend(1, 0, |r| {
+            let clock = Arc::new(ManualClock::new(0));
+            let r = r.with_clock(clock.clone());
+
+            add_item(0, add(0), r.clone()).unwrap();
+
+            let output = data(query_all(0, r.clone()).unwrap());
+            let item = &output["items"][0];
+            assert_eq!(item["status"], "pending");
+            let prepare_time = item["prepare_time"].as_u64().unwrap();
+            assert_eq!(item["remaining_minutes"], prepare_time);
+
+            clock.advance_minutes(prepare_time - 1);
+            let output = data(query_one(0, 0, r.clone()).unwrap());
+            assert_eq!(output[0]["remaining_minutes"], 1);
+
+            clock.advance_minutes(1);
+            let output = data(query_one(0, 0, r).unwrap());
+            assert_eq!(output[0]["status"], "ready");
+            assert_eq!(output[0]["remaining_minutes"], 0);
+        });
+    }
+
+    #[test]
+    fn test_api_set_state() {
+        each_backend(1, 1, |r| {
+            let line_id = r.get_table(0).unwrap().lock().unwrap().check_item(0)[0].line_id();
+            let to = |state| SetStateRequest { state };
+
+            let output = data(set_state(0, 0, line_id, to(ItemState::Cooking), r.clone()).unwrap());
+            assert_eq!(output["state"], "cooking");
+
+            let res = set_state(0, 0, line_id, to(ItemState::Served), r.clone());
+            assert_eq!(res.err().unwrap().status(), StatusCode::Conflict);
+
+            set_state(0, 0, line_id, to(ItemState::Ready), r.clone()).unwrap();
+            set_state(0, 0, line_id, to(ItemState::Served), r.clone()).unwrap();
+
+            let res = remove_line(0, 0, line_id, r.clone());
+            assert!(matches!(res, Err(Error::InvalidTransition { .. })));
+        });
+    }
+
+    #[test]
+    fn test_api_menu() {
+        each_backend(1, 0, |r| {
+            assert_eq!(
+                add_item(0, add(1000), r.clone()),
+                Err(Error::MenuItemNotFound(1000))
+            );
+
+            let mut item = MenuItem::sample(1000);
+            item.name = "Gyoza".to_string();
+            let res = create_menu_item(item, r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            add_item(0, add(1000), r.clone()).unwrap();
+
+            let update = MenuItemUpdate {
+                price: Some(450),
+                ..Default::default()
+            };
+            let output = data(update_menu_item(1000, update, r.clone()).unwrap());
+            assert_eq!(output["price"], 450);
+
+            retire_menu_item(1000, r.clone()).unwrap();
+            assert_eq!(
+                add_item(0, add(1000), r.clone()),
+                Err(Error::MenuItemUnavailable(1000))
+            );
+
+            let output = data(query_menu_item(1000, r.clone()).unwrap());
+            assert_eq!(output["retired"], true);
+            assert_eq!(data(list_menu(r).unwrap()).as_array().unwrap().len(), 1001);
+        });
+    }
+
+    #[test]
+    fn test_api_menu_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            create_menu_item(MenuItem::sample(10), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            let update = MenuItemUpdate {
+                price: Some(450),
+                ..Default::default()
+            };
+            update_menu_item(10, update, r.clone()).unwrap();
+            retire_menu_item(2, r.clone()).unwrap();
+            let before = data(list_menu(r.clone()).unwrap());
+            drop(r);
+
+            // runtime edits win over the menu the server starts with
+            let r = open();
+            assert_eq!(data(list_menu(r.clone()).unwrap()), before);
+            assert_eq!(data(query_menu_item(10, r.clone()).unwrap())["price"], 450);
+            assert_eq!(
+                add_item(0, add(2), r.clone()),
+                Err(Error::MenuItemUnavailable(2))
+            );
+        });
+    }
+
+    #[test]
+    fn test_api_kitchen() {
+        each_backend(1, 0, |r| {
+            let clock = Arc::new(ManualClock::new(0));
+            let r = r.with_clock(clock.clone()).with_kitchen(Kitchen::new(1));
+
+            add_item(0, add(0), r.clone()).unwrap();
+            add_item(0, add(1), r.clone()).unwrap();
+
+            let output = data(query_all(0, r.clone()).unwrap());
+            let first = output["items"][0]["prepare_time"].as_u64().unwrap();
+            let second = output["items"][1]["prepare_time"].as_u64().unwrap();
+            // one cook, so the second dish waits for the first
+            assert_eq!(output["items"][1]["remaining_minutes"], first + second);
+
+            let output = data(query_kitchen_queue(r.clone()).unwrap());
+            assert_eq!(output.as_array().unwrap().len(), 2);
+
+            kitchen::tick(&r);
+            let output = data(query_kitchen(r.clone()).unwrap());
+            assert_eq!(output["busy_cooks"], 1);
+            assert_eq!(output["queue_length"], 1);
+            assert_eq!(output["estimated_wait_minutes"], first + second);
+            let output = data(query_all(0, r.clone()).unwrap());
+            assert_eq!(output["items"][0]["state"], "cooking");
+
+            clock.advance_minutes(first);
+            kitchen::tick(&r);
+            let output = data(query_all(0, r.clone()).unwrap());
+            assert_eq!(output["items"][0]["state"], "ready");
+            assert_eq!(output["items"][1]["state"], "cooking");
+
+            // cancelling the dish being cooked frees the cook
+            let line_id = output["items"][1]["line_id"].as_u64().unwrap() as u32;
+            remove_line(0, 1, line_id, r.clone()).unwrap();
+            let output = data(query_kitchen(r).unwrap());
+            assert_eq!(output["busy_cooks"], 0);
+            assert_eq!(output["utilisation"], 0.0);
+        });
+    }
+
+    #[test]
+    fn test_api_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            add_item(1, add(2), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            let res = add_item(1, add(3), r.clone()).unwrap();
+            let line_id = data(res)["line_id"].as_u64().unwrap() as u32;
+            add_item(1, add(4), r.clone()).unwrap();
+            remove_item(1, 4, r.clone()).unwrap();
+            let before = data(query_history(1, r.clone()).unwrap());
+            drop(r);
+
+            let r = open();
+            let after = data(query_history(1, r.clone()).unwrap());
+            assert_eq!(after["items"].as_array().unwrap().len(), 3);
+            for (a, b) in before["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .zip(after["items"].as_array().unwrap())
+            {
+                assert_eq!(a["line_id"], b["line_id"]);
+                assert_eq!(a["state"], b["state"]);
+                assert_eq!(a["history"], b["history"]);
+            }
+            // unfinished lines are back in the kitchen
+            let output = data(query_kitchen(r.clone()).unwrap());
+            assert_eq!(output["queue_length"], 2);
+
+            // new lines do not reuse recovered ids
+            let res = add_item(1, add(3), r).unwrap();
+            assert!(data(res)["line_id"].as_u64().unwrap() as u32 > line_id);
+        });
+    }
+
+    #[test]
+    fn test_api_checkout() {
+        each_backend(2, 0, |r| {
+            let r = r.with_charges(Charges {
+                service_bps: 1000,
+                tax_bps: 1000,
+            });
+            assert_eq!(
+                checkout(1, CheckoutRequest::default(), r.clone()),
+                Err(Error::NothingToBill(1))
+            );
+
+            let first = line_of(add_item(1, add(1), r.clone()).unwrap());
+            let second = line_of(add_item(1, add(3), r.clone()).unwrap());
+            add_item(1, add(4), r.clone()).unwrap();
+            remove_item(1, 4, r.clone()).unwrap();
+            serve(&r, 1, 1, first);
+            assert_eq!(
+                checkout(1, CheckoutRequest::default(), r.clone()),
+                Err(Error::ItemsPending(1))
+            );
+
+            serve(&r, 1, 3, second);
+            let res = checkout(1, CheckoutRequest::default(), r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            let bill = data(res);
+            assert_eq!(bill["items"].as_array().unwrap().len(), 2);
+            assert_eq!(bill["subtotal"], 200 + 400);
+            assert_eq!(bill["service_charge"], 60);
+            assert_eq!(bill["tax"], 66);
+            assert_eq!(bill["total"], 726);
+            assert_eq!(bill["session"].as_array().unwrap().len(), 3);
+
+            // the table is ready for the next party
+            let output = data(query_history(1, r.clone()).unwrap());
+            assert!(output["items"].as_array().unwrap().is_empty());
+
+            let bill_id = bill["bill_id"].as_u64().unwrap() as u32;
+            assert_eq!(data(query_bill(bill_id, r.clone()).unwrap()), bill);
+            assert_eq!(
+                query_bill(bill_id + 1, r),
+                Err(Error::BillNotFound(bill_id + 1))
+            );
+        });
+    }
+
+    #[test]
+    fn test_api_checkout_split() {
+        each_backend(1, 0, |r| {
+            for (iid, seat) in [(1, Some(1)), (2, Some(2)), (3, Some(1)), (4, None)] {
+                let req = AddItemRequest {
+                    item_id: iid,
+                    spec: LineSpec {
+                        seat,
+                        ..Default::default()
+                    },
+                };
+                let line_id = line_of(add_item(0, req, r.clone()).unwrap());
+                serve(&r, 0, iid, line_id);
+            }
+
+            let req =
+                parse_json::<CheckoutRequest>(b"{\"split\": {\"by\": \"even\", \"ways\": 0}}");
+            assert!(matches!(
+                checkout(0, req.unwrap(), r.clone()),
+                Err(Error::InvalidSplit(_))
+            ));
+
+            let req = parse_json(b"{\"split\": {\"by\": \"seat\"}}").unwrap();
+            let bill = data(checkout(0, req, r).unwrap());
+            let splits = bill["splits"].as_array().unwrap();
+            assert_eq!(splits.len(), 3);
+            // the item without a seat comes first
+            assert!(splits[0].get("seat").is_none());
+            assert_eq!(splits[1]["seat"], 1);
+            assert_eq!(splits[1]["items"].as_array().unwrap().len(), 2);
+
+            let total = splits.iter().map(|s| s["total"].as_u64().unwrap());
+            assert_eq!(total.sum::<u64>(), bill["total"].as_u64().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_api_checkout_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            let line_id = line_of(add_item(0, add(2), r.clone()).unwrap());
+            serve(&r, 0, 2, line_id);
+            let first = data(checkout(0, CheckoutRequest::default(), r.clone()).unwrap());
+            r.snapshot().unwrap();
+            seat_all(&r);
+            let line_id = line_of(add_item(0, add(3), r.clone()).unwrap());
+            serve(&r, 0, 3, line_id);
+            let second = data(checkout(0, CheckoutRequest::default(), r.clone()).unwrap());
+            drop(r);
+
+            let r = open();
+            for bill in [first, second] {
+                let bill_id = bill["bill_id"].as_u64().unwrap() as u32;
+                assert_eq!(data(query_bill(bill_id, r.clone()).unwrap()), bill);
+            }
+            let output = data(query_history(0, r.clone()).unwrap());
+            assert!(output["items"].as_array().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_api_tables() {
+        each_backend(2, 0, |r| {
+            let mut table = TableConfig::sample(5);
+            table.group = Some("window".to_string());
+            let res = create_table(table.clone(), r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            assert_eq!(data(res)["group"], "window");
+            assert_eq!(create_table(table, r.clone()), Err(Error::TableExists(5)));
+            seat_all(&r);
+            line_of(add_item(5, add(1), r.clone()).unwrap());
+
+            disable_table(1, r.clone()).unwrap();
+            assert_eq!(add_item(1, add(1), r.clone()), Err(Error::TableDisabled(1)));
+            let output = data(query_table(1, r.clone()).unwrap());
+            assert_eq!(output["disabled"], true);
+
+            let renumber = |to| TableUpdate {
+                table_id: Some(to),
+                ..Default::default()
+            };
+            assert_eq!(
+                update_table(5, renumber(6), r.clone()),
+                Err(Error::TableInUse(5))
+            );
+            assert_eq!(
+                update_table(0, renumber(9), r.clone()),
+                Err(Error::TableInUse(0))
+            );
+            unseat_table(0, r.clone()).unwrap();
+            update_table(0, renumber(9), r.clone()).unwrap();
+            assert_eq!(query_all(0, r.clone()), Err(Error::TableNotFound(0)));
+
+            let output = data(list_tables(r).unwrap());
+            let ids = output
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|t| t["table_id"].clone());
+            assert_eq!(ids.collect::<Vec<_>>(), vec![1, 5, 9]);
+        });
+    }
+
+    #[test]
+    fn test_api_tables_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            create_table(TableConfig::sample(7), r.clone()).unwrap();
+            seat_all(&r);
+            let line_id = line_of(add_item(7, add(2), r.clone()).unwrap());
+            unseat_table(1, r.clone()).unwrap();
+            let update = TableUpdate {
+                table_id: Some(3),
+                ..Default::default()
+            };
+            update_table(1, update, r.clone()).unwrap();
+            r.snapshot().unwrap();
+            disable_table(0, r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let output = data(list_tables(r.clone()).unwrap());
+            let ids = output
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|t| t["table_id"].clone());
+            assert_eq!(ids.collect::<Vec<_>>(), vec![0, 3, 7]);
+            assert_eq!(output[0]["disabled"], true);
+            assert!(query_line(7, 2, line_id, r).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_api_floor() {
+        each_backend(3, 0, |r| {
+            unseat_table(2, r.clone()).unwrap();
+            assert_eq!(
+                add_item(2, add(1), r.clone()),
+                Err(Error::TableNotSeated(2))
+            );
+            let line_id = line_of(add_item(1, add(1), r.clone()).unwrap());
+            serve(&r, 1, 1, line_id);
+            add_item(0, add(1), r.clone()).unwrap();
+
+            let floor = data(query_floor(r.clone()).unwrap());
+            assert_eq!(floor[0]["status"], "occupied");
+            assert_eq!(floor[0]["party"]["size"], 2);
+            assert_eq!(floor[1]["status"], "awaiting_checkout");
+            assert_eq!(floor[2]["status"], "free");
+            assert!(floor[2].get("party").is_none());
+
+            let bill = data(checkout(1, CheckoutRequest::default(), r.clone()).unwrap());
+            assert_eq!(bill["party"]["size"], 2);
+            let floor = data(query_floor(r).unwrap());
+            assert_eq!(floor[1]["status"], "free");
+        });
+    }
+
+    #[test]
+    fn test_api_seat_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            unseat_table(1, r.clone()).unwrap();
+            r.snapshot().unwrap();
+            let req = SeatRequest {
+                party_size: 3,
+                server: Some("kim".to_string()),
+            };
+            seat_table(1, req, r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let floor = data(query_floor(r).unwrap());
+            assert_eq!(floor[1]["party"]["size"], 3);
+            assert_eq!(floor[1]["party"]["server"], "kim");
+        });
+    }
+
+    fn booking(table_id: Option<u32>, party_size: u32, starts_at: u64) -> ReservationRequest {
+        ReservationRequest {
+            table_id,
+            name: "kim".to_string(),
+            party_size,
+            starts_at,
+            ends_at: starts_at + 90 * MS_PER_MINUTE,
+        }
+    }
+
+    #[test]
+    fn test_api_reservations() {
+        each_backend(2, 0, |r| {
+            let clock = Arc::new(ManualClock::new(0));
+            let r = r.with_clock(clock.clone());
+            unseat_table(0, r.clone()).unwrap();
+
+            let res = create_reservation(booking(Some(0), 2, 0), r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            let first = data(res)["reservation_id"].as_u64().unwrap() as u32;
+            assert_eq!(
+                create_reservation(booking(Some(0), 2, 60 * MS_PER_MINUTE), r.clone()),
+                Err(Error::ReservationConflict(0))
+            );
+            let res = create_reservation(booking(Some(1), 2, 0), r.clone()).unwrap();
+            let second = data(res)["reservation_id"].as_u64().unwrap() as u32;
+
+            // the table of a booking keeps its number
+            let update = TableUpdate {
+                table_id: Some(5),
+                ..Default::default()
+            };
+            assert_eq!(
+                update_table(0, update, r.clone()),
+                Err(Error::TableInUse(0))
+            );
+
+            clock.advance_minutes(10);
+            let res = seat_reservation(first, ArrivalRequest::default(), r.clone()).unwrap();
+            assert_eq!(data(res)["state"], "seated");
+            assert_eq!(data(query_floor(r.clone()).unwrap())[0]["party"]["size"], 2);
+            add_item(0, add(1), r.clone()).unwrap();
+
+            // table 1 is still taken by the party seated by `each_backend`
+            assert_eq!(
+                seat_reservation(second, ArrivalRequest::default(), r.clone()),
+                Err(Error::TableOccupied(1))
+            );
+            clock.advance_minutes(5);
+            reservation::release_no_shows(&r);
+            let output = data(query_reservation(second, r.clone()).unwrap());
+            assert_eq!(output["state"], "no_show");
+            assert!(matches!(
+                cancel_reservation(second, r.clone()),
+                Err(Error::ReservationClosed { .. })
+            ));
+            assert_eq!(query_reservation(9, r), Err(Error::ReservationNotFound(9)));
+        });
+    }
+
+    #[test]
+    fn test_api_reservations_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            let start = r.now() + 60 * MS_PER_MINUTE;
+            create_reservation(booking(None, 2, start), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            create_reservation(booking(None, 2, start), r.clone()).unwrap();
+            let update = ReservationUpdate {
+                party_size: Some(3),
+                ..Default::default()
+            };
+            update_reservation(0, update, r.clone()).unwrap();
+            cancel_reservation(1, r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let output = data(list_reservations(r.clone()).unwrap());
+            assert_eq!(output[0]["party_size"], 3);
+            assert_eq!(output[1]["state"], "cancelled");
+            let res = create_reservation(booking(None, 2, start), r).unwrap();
+            assert_eq!(data(res)["reservation_id"], 2);
+        });
+    }
+
+    fn walk_in(party_size: u32) -> WaitlistRequest {
+        WaitlistRequest {
+            party_size,
+            contact: "kim".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_api_waitlist() {
+        each_backend(2, 0, |r| {
+            let clock = Arc::new(ManualClock::new(0));
+            let r = r.with_clock(clock.clone());
+            unseat_table(0, r.clone()).unwrap();
+            unseat_table(1, r.clone()).unwrap();
+            let req = SeatRequest {
+                party_size: 2,
+                server: None,
+            };
+            seat_table(1, req, r.clone()).unwrap();
+
+            let res = join_waitlist(walk_in(2), r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            assert_eq!(data(res)["quoted_minutes"], 0);
+            clock.advance_minutes(5);
+            let seating = WaitlistSeatRequest {
+                table_id: 0,
+                server: None,
+            };
+            let output = data(seat_from_waitlist(0, seating, r.clone()).unwrap());
+            assert_eq!(output["state"], "seated");
+            assert_eq!(output["waited_minutes"], 5);
+            assert_eq!(
+                data(query_floor(r.clone()).unwrap())[0]["status"],
+                "occupied"
+            );
+
+            // both tables free up within the usual hour
+            let output = data(join_waitlist(walk_in(4), r.clone()).unwrap());
+            assert_eq!(output["quoted_minutes"], 55);
+            let output = data(join_waitlist(walk_in(2), r.clone()).unwrap());
+            assert_eq!(output["quoted_minutes"], 60);
+            assert_eq!(
+                join_waitlist(walk_in(5), r.clone()),
+                Err(Error::NoTableAvailable(5))
+            );
+
+            assert_eq!(next_on_waitlist(0, r.clone()), Err(Error::TableOccupied(0)));
+            unseat_table(1, r.clone()).unwrap();
+            let output = data(next_on_waitlist(1, r.clone()).unwrap());
+            assert_eq!(output["entry_id"], 1);
+
+            leave_waitlist(2, r.clone()).unwrap();
+            let seating = WaitlistSeatRequest {
+                table_id: 1,
+                server: None,
+            };
+            assert!(matches!(
+                seat_from_waitlist(2, seating, r.clone()),
+                Err(Error::NotWaiting { .. })
+            ));
+            assert_eq!(
+                query_waitlist_entry(9, r),
+                Err(Error::WaitlistEntryNotFound(9))
+            );
+        });
+    }
+
+    #[test]
+    fn test_api_waitlist_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            join_waitlist(walk_in(2), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            join_waitlist(walk_in(3), r.clone()).unwrap();
+            leave_waitlist(0, r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let output = data(list_waitlist(r.clone()).unwrap());
+            assert_eq!(output[0]["state"], "left");
+            assert_eq!(output[1]["party_size"], 3);
+            let res = join_waitlist(walk_in(2), r).unwrap();
+            assert_eq!(data(res)["entry_id"], 2);
+        });
+    }
+
+    fn only(item_id: u32) -> TransferRequest {
+        TransferRequest {
+            item_id: Some(item_id),
+            line_id: None,
+        }
+    }
+
+    #[test]
+    fn test_api_transfer() {
+        each_backend(3, 0, |r| {
+            let clock = Arc::new(ManualClock::new(0));
+            let r = r.with_clock(clock.clone()).with_kitchen(Kitchen::new(1));
+            let first = line_of(add_item(0, add(1), r.clone()).unwrap());
+            add_item(0, add(2), r.clone()).unwrap();
+            add_item(0, add(2), r.clone()).unwrap();
+            kitchen::tick(&r);
+            let before = data(query_history(0, r.clone()).unwrap())["items"][0].clone();
+
+            let output = data(transfer(0, 1, only(1), r.clone()).unwrap());
+            assert_eq!(output["table_id"], 1);
+            assert_eq!(output["line_ids"], serde_json::json!([first]));
+            let mut moved = data(query_history(1, r.clone()).unwrap())["items"][0].clone();
+            assert_eq!(moved["table_id"], 1);
+            moved["table_id"] = before["table_id"].clone();
+            assert_eq!(moved, before);
+
+            // the kitchen hands the dish to the table it moved to
+            clock.advance_minutes(before["prepare_time"].as_u64().unwrap());
+            kitchen::tick(&r);
+            let output = data(query_one(1, 1, r.clone()).unwrap());
+            assert_eq!(output[0]["state"], "ready");
+
+            let output = data(transfer(1, 0, TransferRequest::default(), r.clone()).unwrap());
+            assert_eq!(output["line_ids"].as_array().unwrap().len(), 1);
+            transfer(0, 1, TransferRequest::default(), r.clone()).unwrap();
+            let output = data(query_all(1, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 3);
+            assert_eq!(
+                transfer(0, 1, TransferRequest::default(), r.clone()),
+                Err(Error::NothingToTransfer(0))
+            );
+
+            unseat_table(2, r.clone()).unwrap();
+            assert_eq!(
+                transfer(1, 2, only(2), r.clone()),
+                Err(Error::TableNotSeated(2))
+            );
+            assert!(matches!(
+                transfer(1, 1, only(2), r.clone()),
+                Err(Error::MalformedRequest(_))
+            ));
+            assert_eq!(transfer(1, 0, only(7), r), Err(Error::ItemNotFound(7)));
+        });
+    }
+
+    #[test]
+    fn test_api_transfer_both_ways() {
+        let r = Restaurant::new(Layout::sample(2), Menu::sample(5));
+        seat_all(&r);
+        for iid in 0..3 {
+            add_item(0, add(iid), r.clone()).unwrap();
+        }
+
+        // opposite moves between the same two tables never deadlock
+        let handles = (0..8)
+            .map(|n| {
+                let r = r.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let req = TransferRequest::default();
+                        let _ = transfer(n % 2, 1 - n % 2, req, r.clone());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let count = |tid| {
+            let output = data(query_all(tid, r.clone()).unwrap());
+            output["items"].as_array().unwrap().len()
+        };
+        assert_eq!(count(0) + count(1), 3);
+    }
+
+    #[test]
+    fn test_api_transfer_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            let line_id = line_of(add_item(0, add(1), r.clone()).unwrap());
+            add_item(0, add(2), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            transfer(0, 1, only(1), r.clone()).unwrap();
+            let before = data(query_line(1, 1, line_id, r.clone()).unwrap());
+            drop(r);
+
+            let r = open();
+            let output = data(query_all(0, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 1);
+            // the kitchen estimates the ready time afresh after a restart
+            let output = data(query_line(1, 1, line_id, r).unwrap());
+            assert_eq!(output["ordered_at"], before["ordered_at"]);
+            assert_eq!(output["history"], before["history"]);
+        });
+    }
+
+    fn merge(tables: &[u32]) -> MergeRequest {
+        MergeRequest {
+            tables: tables.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_api_merge() {
+        each_backend(3, 0, |r| {
+            unseat_table(2, r.clone()).unwrap();
+            let first = line_of(add_item(1, add(1), r.clone()).unwrap());
+
+            let output = data(merge_tables(merge(&[0, 1, 2]), r.clone()).unwrap());
+            assert_eq!(output[0]["party"]["size"], 4);
+            assert_eq!(output[0]["members"], serde_json::json!([1, 2]));
+            assert_eq!(output[2]["merged_into"], 0);
+
+            // every table of the group shows the one order
+            let second = line_of(add_item(2, add(2), r.clone()).unwrap());
+            let output = data(query_all(1, r.clone()).unwrap());
+            assert_eq!(output["table_id"], 0);
+            assert_eq!(output["members"], serde_json::json!([1, 2]));
+            assert_eq!(output["items"].as_array().unwrap().len(), 2);
+            let queue = data(query_kitchen_queue(r.clone()).unwrap());
+            assert_eq!(queue[0]["table_id"], 0);
+            assert_eq!(
+                merge_tables(merge(&[1, 0]), r.clone()),
+                Err(Error::TableMerged(1))
+            );
+
+            let req = SplitRequest {
+                lines: BTreeMap::from([(first, 1)]),
+            };
+            let output = data(split_tables(2, req, r.clone()).unwrap());
+            assert_eq!(output[0]["party"]["size"], 2);
+            assert_eq!(output[2]["status"], "free");
+            let output = data(query_all(1, r.clone()).unwrap());
+            assert_eq!(output["items"][0]["line_id"], first);
+            let queue = data(query_kitchen_queue(r.clone()).unwrap());
+            assert_eq!(queue[0]["table_id"], 1);
+            assert_eq!(
+                split_tables(0, SplitRequest::default(), r.clone()),
+                Err(Error::TableNotMerged(0))
+            );
+
+            // one bill for the whole group, and every table is freed
+            merge_tables(merge(&[0, 1]), r.clone()).unwrap();
+            serve(&r, 1, 1, first);
+            serve(&r, 0, 2, second);
+            let bill = data(checkout(1, CheckoutRequest::default(), r.clone()).unwrap());
+            assert_eq!(bill["table_id"], 0);
+            assert_eq!(bill["items"].as_array().unwrap().len(), 2);
+            assert_eq!(bill["party"]["size"], 4);
+            let output = data(query_floor(r).unwrap());
+            assert_eq!(output[0]["status"], "free");
+            assert_eq!(output[1]["status"], "free");
+        });
+    }
+
+    #[test]
+    fn test_api_merge_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            add_item(1, add(1), r.clone()).unwrap();
+            merge_tables(merge(&[0, 1]), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            add_item(1, add(2), r.clone()).unwrap();
+            let before = data(query_floor(r.clone()).unwrap());
+            drop(r);
+
+            let r = open();
+            assert_eq!(data(query_floor(r.clone()).unwrap()), before);
+            let output = data(query_all(1, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 2);
+            split_tables(1, SplitRequest::default(), r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let output = data(query_floor(r).unwrap());
+            assert_eq!(output[0]["party"]["size"], 2);
+            assert!(output[0].get("members").is_none());
+        });
+    }
+
+    fn batch(body: &str) -> BatchAddRequest {
+        parse_json(body.as_bytes()).unwrap()
+    }
+
+    fn batch_remove(body: &str) -> BatchRemoveRequest {
+        parse_json(body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_api_batch_add() {
+        each_backend(2, 0, |r| {
+            let items = r#"[{"item_id": 1, "quantity": 2, "note": "no salt"}, {"item_id": 5000}]"#;
+
+            // one bad entry and nothing goes on the table
+            let req = batch(&format!("{{\"items\": {}}}", items));
+            assert_eq!(
+                add_batch(0, req, r.clone()),
+                Err(Error::MenuItemNotFound(5000))
+            );
+            let output = data(query_all(0, r.clone()).unwrap());
+            assert_eq!(output["items"].as_array().unwrap().len(), 0);
+            assert_eq!(
+                data(query_kitchen_queue(r.clone()).unwrap()),
+                serde_json::json!([])
+            );
+
+            let req = batch(&format!("{{\"items\": {}, \"mode\": \"per_item\"}}", items));
+            let res = add_batch(0, req, r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            let output = data(res);
+            let line_id = output[0]["line_id"].as_u64().unwrap() as u32;
+            assert_eq!(output[1]["error"]["code"], "menu_item_not_found");
+            let output = data(query_line(0, 1, line_id, r.clone()).unwrap());
+            assert_eq!(output["quantity"], 2);
+            assert_eq!(output["note"], "no salt");
+
+            let req = batch(r#"{"items": [{"item_id": 2}, {"item_id": 3, "seat": 1}]}"#);
+            let res = add_batch(0, req, r.clone()).unwrap();
+            assert_eq!(res.status, StatusCode::Created);
+            assert_eq!(data(res).as_array().unwrap().len(), 2);
+            assert_eq!(
+                data(query_kitchen_queue(r.clone()).unwrap())
+                    .as_array()
+                    .unwrap()
+                    .len(),
+                3
+            );
+
+            for body in [
+                r#"{"items": []}"#,
+                r#"{"items": [{"item_id": 1, "quantity": 0}]}"#,
+            ] {
+                assert!(matches!(
+                    add_batch(0, batch(body), r.clone()),
+                    Err(Error::MalformedBody(_))
+                ));
+            }
+            unseat_table(1, r.clone()).unwrap();
+            assert_eq!(
+                add_batch(1, batch(r#"{"items": [{"item_id": 1}]}"#), r.clone()),
+                Err(Error::TableNotSeated(1))
+            );
+        });
+    }
+
+    #[test]
+    fn test_api_batch_remove() {
+        each_backend(1, 0, |r| {
+            let first = line_of(add_item(0, add(1), r.clone()).unwrap());
+            let second = line_of(add_item(0, add(2), r.clone()).unwrap());
+            let third = line_of(add_item(0, add(2), r.clone()).unwrap());
+            serve(&r, 0, 2, second);
+
+            let req = batch_remove(r#"{"items": [{"item_id": 2}, {"item_id": 7}]}"#);
+            assert_eq!(remove_batch(0, req, r.clone()), Err(Error::ItemNotFound(7)));
+            let output = data(query_line(0, 2, third, r.clone()).unwrap());
+            assert_eq!(output["state"], "ordered");
+
+            let req = batch_remove(&format!(
+                "{{\"items\": [{{\"item_id\": 2}}, {{\"item_id\": 2, \"line_id\": {}}}, \
+                 {{\"item_id\": 1, \"line_id\": {}}}, {{\"item_id\": 7}}], \"mode\": \"per_item\"}}",
+                second, first
+            ));
+            let output = data(remove_batch(0, req, r.clone()).unwrap());
+            // the served line stays and the second entry finds nothing left
+            assert_eq!(output[0]["line_ids"], serde_json::json!([third]));
+            assert_eq!(output[1]["error"]["code"], "invalid_transition");
+            assert_eq!(output[2]["line_ids"], serde_json::json!([first]));
+            assert_eq!(output[3]["error"]["code"], "item_not_found");
+            assert_eq!(
+                data(query_kitchen_queue(r.clone()).unwrap()),
+                serde_json::json!([])
+            );
+            let output = data(query_line(0, 2, second, r.clone()).unwrap());
+            assert_eq!(output["state"], "served");
+        });
+    }
+
+    #[test]
+    fn test_api_batch_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            let req = batch(r#"{"items": [{"item_id": 1, "quantity": 3}, {"item_id": 2}]}"#);
+            let output = data(add_batch(0, req, r.clone()).unwrap());
+            let line_id = output[1]["line_id"].as_u64().unwrap() as u32;
+            let req = batch_remove(r#"{"items": [{"item_id": 2}]}"#);
+            remove_batch(0, req, r.clone()).unwrap();
+            drop(r);
+
+            let r = open();
+            let output = data(query_one(0, 1, r.clone()).unwrap());
+            assert_eq!(output[0]["quantity"], 3);
+            let output = data(query_line(0, 2, line_id, r).unwrap());
+            assert_eq!(output["state"], "cancelled");
+        });
+    }
+
+    fn version_of(r: &Restaurant, tid: u32) -> u64 {
+        data(query_all(tid, r.clone()).unwrap())["version"]
+            .as_u64()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_api_versions() {
+        each_backend(2, 0, |r| {
+            let v = version_of(&r, 0);
+            let res = add_item(0, add(1), r.clone().expecting(Some(v))).unwrap();
+            let etag = res.headers.iter().find(|(name, _)| name == "ETag");
+            assert_eq!(etag.unwrap().1, http::etag(v + 1));
+            let line_id = line_of(res);
+            assert_eq!(version_of(&r, 0), v + 1);
+
+            // a tablet still holding the old version has to read again
+            let stale = r.clone().expecting(Some(v));
+            let conflict = Err(Error::VersionMismatch {
+                table_id: 0,
+                expected: v,
+                version: v + 1,
+            });
+            assert_eq!(add_item(0, add(2), stale.clone()), conflict);
+            assert_eq!(remove_line(0, 1, line_id, stale.clone()), conflict);
+            assert_eq!(
+                checkout(0, CheckoutRequest::default(), stale.clone()),
+                conflict
+            );
+            assert_eq!(version_of(&r, 0), v + 1);
+
+            // the kitchen moving a line on counts as a change too
+            let fresh = r.clone().expecting(Some(v + 1));
+            let req = SetStateRequest {
+                state: ItemState::Cooking,
+            };
+            set_state(0, 1, line_id, req, fresh.clone()).unwrap();
+            assert!(add_item(0, add(2), fresh).is_err());
+
+            // tables pushed together share the version of the first one
+            merge_tables(merge(&[0, 1]), r.clone()).unwrap();
+            let v = version_of(&r, 0);
+            assert_eq!(version_of(&r, 1), v);
+            add_item(1, add(2), r.clone().expecting(Some(v))).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_api_versions_recovery() {
+        each_persistent_backend(|open| {
+            let r = open();
+            add_item(0, add(1), r.clone()).unwrap();
+            r.snapshot().unwrap();
+            add_item(0, add(2), r.clone()).unwrap();
+            let line_id = line_of(add_item(0, add(3), r.clone()).unwrap());
+            remove_line(0, 3, line_id, r.clone()).unwrap();
+            let before = version_of(&r, 0);
+            drop(r);
+
+            // a version handed out before the restart still holds
+            let r = open();
+            assert_eq!(version_of(&r, 0), before);
+            add_item(0, add(1), r.expecting(Some(before))).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_api_versions_recovery_cancel_many() {
+        each_persistent_backend(|open| {
+            let r = open();
+            for _ in 0..3 {
+                add_item(0, add(1), r.clone()).unwrap();
+                add_item(0, add(2), r.clone()).unwrap();
+            }
+            remove_item(0, 1, r.clone()).unwrap();
+            let req = batch_remove(r#"{"items": [{"item_id": 2}]}"#);
+            remove_batch(0, req, r.clone()).unwrap();
+            let before = version_of(&r, 0);
+            drop(r);
+
+            // every cancelled serving replays as the one change it was live
+            let r = open();
+            assert_eq!(version_of(&r, 0), before);
+            add_item(0, add(1), r.expecting(Some(before))).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_api_storage_failure() {
+        let storage = Arc::new(FailingStorage::default());
+        let r = Restaurant::new(Layout::sample(2), Menu::sample(5))
+            .with_storage(storage.clone())
+            .with_clock(Arc::new(ManualClock::new(0)));
+        seat_all(&r);
+        let line_id = line_of(add_item(0, add(1), r.clone()).unwrap());
+        let table = data(query_all(0, r.clone()).unwrap());
+        let floor = data(query_floor(r.clone()).unwrap());
+        let queue = data(query_kitchen_queue(r.clone()).unwrap());
+        let mut events = r.subscribe();
+        storage.failing.store(true, Ordering::Relaxed);
+
+        let refused = |res: Result<Response, Error>| {
+            assert!(matches!(res, Err(Error::Storage(_))));
+        };
+        refused(add_item(0, add(2), r.clone()));
+        let req = batch(r#"{"items": [{"item_id": 2}, {"item_id": 3}]}"#);
+        refused(add_batch(0, req, r.clone()));
+        refused(update_line(0, 1, line_id, change(2), r.clone()));
+        let req = SetStateRequest {
+            state: ItemState::Cooking,
+        };
+        refused(set_state(0, 1, line_id, req, r.clone()));
+        refused(remove_item(0, 1, r.clone()));
+        refused(transfer(0, 1, TransferRequest::default(), r.clone()));
+        refused(merge_tables(merge(&[0, 1]), r.clone()));
+        refused(unseat_table(1, r.clone()));
+
+        // none of it stayed in memory, and nobody was told of it
+        assert_eq!(data(query_all(0, r.clone()).unwrap()), table);
+        assert_eq!(data(query_floor(r.clone()).unwrap()), floor);
+        assert_eq!(data(query_kitchen_queue(r.clone()).unwrap()), queue);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_api_kitchen_storage_failure() {
+        let storage = Arc::new(FailingStorage::default());
+        let r = Restaurant::new(Layout::sample(1), Menu::sample(5))
+            .with_storage(storage.clone())
+            .with_clock(Arc::new(ManualClock::new(0)));
+        seat_all(&r);
+        add_item(0, add(1), r.clone()).unwrap();
+
+        // the cook starts, but the table stays as it was until that is kept
+        storage.failing.store(true, Ordering::Relaxed);
+        kitchen::tick(&r);
+        let output = data(query_all(0, r.clone()).unwrap());
+        assert_eq!(output["items"][0]["state"], "ordered");
+
+        storage.failing.store(false, Ordering::Relaxed);
+        kitchen::tick(&r);
+        let output = data(query_all(0, r.clone()).unwrap());
+        assert_eq!(output["items"][0]["state"], "cooking");
+    }
+
+    #[test]
+    fn test_api_no_shows_storage_failure() {
+        let storage = Arc::new(FailingStorage::default());
+        let clock = Arc::new(ManualClock::new(0));
+        let r = Restaurant::new(Layout::sample(1), Menu::sample(5))
+            .with_storage(storage.clone())
+            .with_clock(clock.clone());
+        let res = create_reservation(booking(Some(0), 2, 0), r.clone()).unwrap();
+        let rid = data(res)["reservation_id"].as_u64().unwrap() as u32;
+
+        clock.advance_minutes(15);
+        storage.failing.store(true, Ordering::Relaxed);
+        reservation::release_no_shows(&r);
+        let output = data(query_reservation(rid, r.clone()).unwrap());
+        assert_eq!(output["state"], "booked");
+
+        storage.failing.store(false, Ordering::Relaxed);
+        reservation::release_no_shows(&r);
+        let output = data(query_reservation(rid, r).unwrap());
+        assert_eq!(output["state"], "no_show");
     }
 }
@@ -0,0 +1,498 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::error::Error;
+use super::item::{Item, ItemState};
+use super::table::Party;
+
+/// Basis points, 1 = 0.01%.
+const BPS: u64 = 10_000;
+
+/// Charges added on top of the served items, in basis points. The service
+/// charge is taken on the subtotal and tax on the subtotal plus the service
+/// charge.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Charges {
+    pub service_bps: u64,
+    pub tax_bps: u64,
+}
+
+/// One served line on a bill.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BillItem {
+    line_id: u32,
+    item_id: u32,
+    name: String,
+    #[serde(default = "one")]
+    quantity: u32,
+    /// For all servings of the line.
+    price: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seat: Option<u32>,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Number of recent bills the usual dining time is taken over.
+const DINING_SAMPLE: usize = 50;
+
+/// The most parts a bill can be split into evenly.
+const MAX_WAYS: u32 = 100;
+
+/// How a bill is shared out between the guests.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "by", rename_all = "snake_case")]
+pub enum Split {
+    /// One part per seat, plus one for items not ordered for a seat.
+    Seat,
+    /// `ways` equal parts.
+    Even { ways: u32 },
+    /// One part per group of line ids. Every served line has to be in
+    /// exactly one group.
+    Items { groups: Vec<Vec<u32>> },
+}
+
+/// The items of one part of a split bill and the seat they were for.
+type Part = (Option<u32>, Vec<BillItem>);
+
+/// One guest's share of a bill.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SubBill {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seat: Option<u32>,
+    items: Vec<BillItem>,
+    subtotal: u64,
+    service_charge: u64,
+    tax: u64,
+    total: u64,
+}
+
+/// What a party paid for. Amounts are in cents.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Bill {
+    bill_id: u32,
+    table_id: u32,
+    closed_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    party: Option<Party>,
+    items: Vec<BillItem>,
+    subtotal: u64,
+    #[serde(flatten)]
+    charges: Charges,
+    service_charge: u64,
+    tax: u64,
+    total: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    splits: Vec<SubBill>,
+    // every line of the session, cancelled ones included
+    session: Vec<Item>,
+}
+
+impl Bill {
+    /// Bills the served lines out of `session`.
+    pub fn new(
+        bill_id: u32,
+        table_id: u32,
+        session: Vec<Item>,
+        charges: Charges,
+        closed_at: u64,
+    ) -> Bill {
+        let items = session
+            .iter()
+            .filter(|i| i.state() == ItemState::Served)
+            .map(|i| BillItem {
+                line_id: i.line_id(),
+                item_id: i.id(),
+                name: i.name().to_string(),
+                quantity: i.quantity(),
+                price: i.price() as u64 * i.quantity() as u64,
+                seat: i.seat(),
+            })
+            .collect::<Vec<_>>();
+
+        let subtotal = items.iter().map(|i| i.price).sum();
+        let service_charge = apply_rate(subtotal, charges.service_bps);
+        let tax = apply_rate(subtotal + service_charge, charges.tax_bps);
+
+        Bill {
+            bill_id,
+            table_id,
+            closed_at,
+            party: None,
+            items,
+            subtotal,
+            charges,
+            service_charge,
+            tax,
+            total: subtotal + service_charge + tax,
+            splits: vec![],
+            session,
+        }
+    }
+
+    /// Records who was seated at the table.
+    pub fn with_party(mut self, party: Option<Party>) -> Bill {
+        self.party = party;
+        self
+    }
+
+    /// Shares the bill out as `split` says. Every amount is divided so that
+    /// the parts add up to exactly the bill's own.
+    pub fn split(mut self, split: &Split) -> Result<Bill, Error> {
+        let groups = match split {
+            Split::Seat => {
+                let mut seats = BTreeMap::<Option<u32>, Vec<BillItem>>::new();
+                for item in self.items.iter() {
+                    seats.entry(item.seat).or_default().push(item.clone());
+                }
+                seats.into_iter().collect::<Vec<_>>()
+            }
+            Split::Even { ways } => {
+                if *ways == 0 || *ways > MAX_WAYS {
+                    return Err(Error::InvalidSplit(format!(
+                        "ways has to be between 1 and {}",
+                        MAX_WAYS
+                    )));
+                }
+                vec![(None, vec![]); *ways as usize]
+            }
+            Split::Items { groups } => self.group_items(groups)?,
+        };
+
+        let subtotals = match split {
+            Split::Even { .. } => allocate(self.subtotal, &vec![1; groups.len()]),
+            _ => groups
+                .iter()
+                .map(|(_, items)| items.iter().map(|i| i.price).sum())
+                .collect(),
+        };
+        let service_charges = allocate(self.service_charge, &subtotals);
+        let taxes = allocate(self.tax, &subtotals);
+
+        self.splits = groups
+            .into_iter()
+            .enumerate()
+            .map(|(n, (seat, items))| SubBill {
+                seat,
+                items,
+                subtotal: subtotals[n],
+                service_charge: service_charges[n],
+                tax: taxes[n],
+                total: subtotals[n] + service_charges[n] + taxes[n],
+            })
+            .collect();
+        Ok(self)
+    }
+
+    fn group_items(&self, groups: &[Vec<u32>]) -> Result<Vec<Part>, Error> {
+        let mut assigned = BTreeSet::new();
+        let mut parts = vec![];
+
+        for group in groups {
+            let mut items = vec![];
+            for line_id in group {
+                let item = self
+                    .items
+                    .iter()
+                    .find(|i| i.line_id == *line_id)
+                    .ok_or_else(|| {
+                        Error::InvalidSplit(format!("line {} is not on the bill", line_id))
+                    })?;
+                if !assigned.insert(*line_id) {
+                    return Err(Error::InvalidSplit(format!(
+                        "line {} is assigned twice",
+                        line_id
+                    )));
+                }
+                items.push(item.clone());
+            }
+            parts.push((None, items));
+        }
+
+        if let Some(left) = self.items.iter().find(|i| !assigned.contains(&i.line_id)) {
+            return Err(Error::InvalidSplit(format!(
+                "line {} is not assigned",
+                left.line_id
+            )));
+        }
+        Ok(parts)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.bill_id
+    }
+
+    pub fn table_id(&self) -> u32 {
+        self.table_id
+    }
+
+    /// How long the party sat at the table, in milliseconds.
+    pub fn dining_time(&self) -> Option<u64> {
+        self.party
+            .as_ref()
+            .map(|p| self.closed_at.saturating_sub(p.seated_at))
+    }
+}
+
+/// Every bill closed so far, by id.
+#[derive(Default)]
+pub struct Bills {
+    next_id: u32,
+    bills: BTreeMap<u32, Bill>,
+}
+
+impl Bills {
+    /// The id the next bill gets, taken once it is inserted.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    pub fn insert(&mut self, bill: Bill) {
+        let bill_id = bill.id();
+        self.next_id = self.next_id.max(bill_id + 1);
+        self.bills.insert(bill_id, bill);
+    }
+
+    pub fn get(&self, bill_id: u32) -> Result<&Bill, Error> {
+        self.bills.get(&bill_id).ok_or(Error::BillNotFound(bill_id))
+    }
+
+    /// How long parties usually stay, in milliseconds: the average over the
+    /// last `DINING_SAMPLE` bills that recorded a party.
+    pub fn dining_time(&self) -> Option<u64> {
+        let times = self
+            .bills
+            .values()
+            .rev()
+            .filter_map(|b| b.dining_time())
+            .take(DINING_SAMPLE)
+            .collect::<Vec<_>>();
+        if times.is_empty() {
+            return None;
+        }
+        Some(times.iter().sum::<u64>() / times.len() as u64)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Bill> {
+        self.bills.values()
+    }
+}
+
+/// `amount * bps / 10000`, rounded half up to a whole cent.
+fn apply_rate(amount: u64, bps: u64) -> u64 {
+    (amount * bps + BPS / 2) / BPS
+}
+
+/// Shares `amount` out in proportion to `weights` so that the shares add up
+/// to exactly `amount`. The cents left over after rounding down go to the
+/// largest remainders, the earlier share first on a tie.
+fn allocate(amount: u64, weights: &[u64]) -> Vec<u64> {
+    let total = weights.iter().sum::<u64>();
+    if total == 0 {
+        return match weights.len() {
+            0 => vec![],
+            n => allocate(amount, &vec![1; n]),
+        };
+    }
+
+    let mut shares = weights
+        .iter()
+        .map(|w| amount * w / total)
+        .collect::<Vec<u64>>();
+    let mut order = (0..weights.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&n| std::cmp::Reverse(amount * weights[n] % total));
+
+    let left = amount - shares.iter().sum::<u64>();
+    for n in order.into_iter().take(left as usize) {
+        shares[n] += 1;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::LineSpec;
+    use crate::menu::MenuItem;
+
+    fn served(line_id: u32, item_id: u32) -> Item {
+        let mut i = Item::new(line_id, &MenuItem::sample(item_id), 1, 5, 0);
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            i.transition(state, 1).unwrap();
+        }
+        i
+    }
+
+    #[test]
+    fn test_bill_totals() {
+        let mut cancelled = Item::new(2, &MenuItem::sample(3), 1, 5, 0);
+        cancelled.transition(ItemState::Cancelled, 1).unwrap();
+        let two = served(1, 4).with_spec(
+            &MenuItem::sample(4),
+            LineSpec {
+                quantity: 2,
+                ..Default::default()
+            },
+        );
+        let session = vec![served(0, 1), two, cancelled];
+        let charges = Charges {
+            service_bps: 1000,
+            tax_bps: 825,
+        };
+
+        let bill = Bill::new(7, 1, session, charges, 10);
+        assert_eq!(bill.items.len(), 2);
+        assert_eq!(bill.subtotal, 200 + 2 * 500);
+        assert_eq!(bill.service_charge, 120);
+        // 8.25% of 1320 is 108.9
+        assert_eq!(bill.tax, 109);
+        assert_eq!(bill.total, 1429);
+        assert_eq!(bill.session.len(), 3);
+    }
+
+    #[test]
+    fn test_bills() {
+        let mut bills = Bills::default();
+        assert_eq!(bills.next_id(), 0);
+
+        bills.insert(Bill::new(4, 1, vec![], Charges::default(), 0));
+        assert_eq!(bills.next_id(), 5);
+        assert_eq!(bills.next_id(), 5);
+        assert_eq!(bills.get(4).unwrap().table_id(), 1);
+        assert_eq!(bills.get(0).err(), Some(Error::BillNotFound(0)));
+    }
+
+    #[test]
+    fn test_bills_dining_time() {
+        let party = |seated_at| Party {
+            table_id: 1,
+            size: 2,
+            server: None,
+            seated_at,
+        };
+        let mut bills = Bills::default();
+        // a bill without a party does not count
+        bills.insert(Bill::new(0, 1, vec![], Charges::default(), 90));
+        assert_eq!(bills.dining_time(), None);
+
+        bills.insert(Bill::new(1, 1, vec![], Charges::default(), 100).with_party(Some(party(40))));
+        bills.insert(Bill::new(2, 1, vec![], Charges::default(), 200).with_party(Some(party(120))));
+        assert_eq!(bills.dining_time(), Some(70));
+    }
+
+    fn seated(line_id: u32, item_id: u32, seat: Option<u32>) -> Item {
+        let spec = LineSpec {
+            seat,
+            ..Default::default()
+        };
+        served(line_id, item_id).with_spec(&MenuItem::sample(item_id), spec)
+    }
+
+    fn sums(bill: &Bill) -> (u64, u64, u64, u64) {
+        bill.splits.iter().fold((0, 0, 0, 0), |(a, b, c, d), s| {
+            (a + s.subtotal, b + s.service_charge, c + s.tax, d + s.total)
+        })
+    }
+
+    const CHARGES: Charges = Charges {
+        service_bps: 1000,
+        tax_bps: 825,
+    };
+
+    #[test]
+    fn test_bill_split_by_seat() {
+        let session = vec![
+            seated(0, 1, Some(1)),
+            seated(1, 4, Some(2)),
+            seated(2, 2, Some(1)),
+            seated(3, 0, None),
+        ];
+        let bill = Bill::new(0, 1, session, CHARGES, 0)
+            .split(&Split::Seat)
+            .unwrap();
+
+        assert_eq!(bill.splits.len(), 3);
+        assert_eq!(bill.splits[0].seat, None);
+        assert_eq!(bill.splits[1].seat, Some(1));
+        assert_eq!(bill.splits[1].subtotal, 200 + 300);
+        assert_eq!(bill.splits[2].items[0].line_id, 1);
+        assert_eq!(
+            sums(&bill),
+            (bill.subtotal, bill.service_charge, bill.tax, bill.total)
+        );
+    }
+
+    #[test]
+    fn test_bill_split_even() {
+        let session = vec![served(0, 1), served(1, 2)];
+        let bill = Bill::new(0, 1, session, CHARGES, 0)
+            .split(&Split::Even { ways: 3 })
+            .unwrap();
+
+        // 500 does not divide by three, the first part takes the extra cent
+        assert_eq!(bill.splits[0].subtotal, 167);
+        assert_eq!(bill.splits[1].subtotal, 167);
+        assert_eq!(bill.splits[2].subtotal, 166);
+        assert_eq!(
+            sums(&bill),
+            (bill.subtotal, bill.service_charge, bill.tax, bill.total)
+        );
+
+        let bill = Bill::new(0, 1, vec![], CHARGES, 0);
+        assert!(matches!(
+            bill.clone().split(&Split::Even { ways: 0 }),
+            Err(Error::InvalidSplit(_))
+        ));
+        assert!(matches!(
+            bill.split(&Split::Even { ways: u32::MAX }),
+            Err(Error::InvalidSplit(_))
+        ));
+    }
+
+    #[test]
+    fn test_bill_split_by_items() {
+        let session = vec![served(0, 1), served(1, 2), served(2, 6)];
+        let bill = Bill::new(0, 1, session.clone(), CHARGES, 0);
+
+        let split = bill
+            .clone()
+            .split(&Split::Items {
+                groups: vec![vec![0, 2], vec![1]],
+            })
+            .unwrap();
+        assert_eq!(split.splits[0].subtotal, 200 + 700);
+        assert_eq!(
+            sums(&split),
+            (bill.subtotal, bill.service_charge, bill.tax, bill.total)
+        );
+
+        for groups in [
+            vec![vec![0, 1]],
+            vec![vec![0, 1, 2], vec![2]],
+            vec![vec![0, 1, 2, 9]],
+        ] {
+            assert!(matches!(
+                bill.clone().split(&Split::Items { groups }),
+                Err(Error::InvalidSplit(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_allocate() {
+        assert_eq!(allocate(100, &[1, 1, 1]), vec![34, 33, 33]);
+        assert_eq!(allocate(10, &[1, 2]), vec![3, 7]);
+        assert_eq!(allocate(5, &[0, 0]), vec![3, 2]);
+        assert_eq!(allocate(0, &[]), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_bill_rounding() {
+        assert_eq!(apply_rate(0, 1000), 0);
+        assert_eq!(apply_rate(5, 1000), 1);
+        assert_eq!(apply_rate(4, 1000), 0);
+        assert_eq!(apply_rate(1999, 0), 0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const MS_PER_MINUTE: u64 = 60_000;
+
+/// Source of time for everything that is scheduled.
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the Unix epoch. Never goes backwards.
+    fn now_ms(&self) -> u64;
+}
+
+/// Wall-clock time taken once at startup and advanced by a monotonic
+/// `Instant`, so adjusting the system clock does not move timestamps.
+pub struct MonotonicClock {
+    base_ms: u64,
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> MonotonicClock {
+        let base_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        MonotonicClock {
+            base_ms,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_ms(&self) -> u64 {
+        self.base_ms + self.start.elapsed().as_millis() as u64
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start_ms: u64) -> ManualClock {
+        ManualClock {
+            now: AtomicU64::new(start_ms),
+        }
+    }
+
+    pub fn advance_minutes(&self, minutes: u64) {
+        self.now
+            .fetch_add(minutes * MS_PER_MINUTE, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monotonic_clock() {
+        let clock = MonotonicClock::new();
+
+        let a = clock.now_ms();
+        let b = clock.now_ms();
+        assert!(a > 0);
+        assert!(b >= a);
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(1000);
+
+        assert_eq!(clock.now_ms(), 1000);
+        clock.advance_minutes(2);
+        assert_eq!(clock.now_ms(), 1000 + 2 * MS_PER_MINUTE);
+    }
+}
@@ -0,0 +1,115 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::error::Error;
+use super::storage::StorageKind;
+
+/// Server settings, read from `RESTAURANT_*` environment variables.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    /// `RESTAURANT_COOKS`: cooks working in the kitchen.
+    pub cooks: usize,
+    /// `RESTAURANT_KITCHEN_TICK_MS`: how often the kitchen moves on.
+    pub kitchen_tick_ms: u64,
+    /// `RESTAURANT_SERVICE_BPS`: service charge in basis points, 1000 is 10%.
+    pub service_bps: u64,
+    /// `RESTAURANT_TAX_BPS`: tax in basis points.
+    pub tax_bps: u64,
+    /// `RESTAURANT_STORAGE`: `memory`, `wal` or `sqlite`.
+    pub storage: StorageKind,
+    /// `RESTAURANT_DATA_DIR`: where the `wal` and `sqlite` backends keep
+    /// their files.
+    pub data_dir: PathBuf,
+    /// `RESTAURANT_SNAPSHOT_SECS`: how often a snapshot is taken.
+    pub snapshot_secs: u64,
+    /// `RESTAURANT_LAYOUT`: the file holding the floor plan.
+    pub layout: PathBuf,
+    /// `RESTAURANT_NO_SHOW_MINS`: how long a booked table is held for a
+    /// party that is late.
+    pub no_show_mins: u64,
+    /// `RESTAURANT_IDEMPOTENCY_SECS`: how long the response to a change
+    /// sent with an idempotency key is kept for retries.
+    pub idempotency_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            cooks: 4,
+            kitchen_tick_ms: 1000,
+            service_bps: 0,
+            tax_bps: 1000,
+            storage: StorageKind::Memory,
+            data_dir: PathBuf::from("data"),
+            snapshot_secs: 60,
+            layout: PathBuf::from("layout.json"),
+            no_show_mins: 15,
+            idempotency_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, Error> {
+        Config::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Result<Config, Error> {
+        let default = Config::default();
+
+        Ok(Config {
+            cooks: parse_var(&var, "RESTAURANT_COOKS", default.cooks)?,
+            kitchen_tick_ms: parse_var(
+                &var,
+                "RESTAURANT_KITCHEN_TICK_MS",
+                default.kitchen_tick_ms,
+            )?,
+            service_bps: parse_var(&var, "RESTAURANT_SERVICE_BPS", default.service_bps)?,
+            tax_bps: parse_var(&var, "RESTAURANT_TAX_BPS", default.tax_bps)?,
+            storage: parse_var(&var, "RESTAURANT_STORAGE", default.storage)?,
+            data_dir: parse_var(&var, "RESTAURANT_DATA_DIR", default.data_dir)?,
+            snapshot_secs: parse_var(&var, "RESTAURANT_SNAPSHOT_SECS", default.snapshot_secs)?,
+            layout: parse_var(&var, "RESTAURANT_LAYOUT", default.layout)?,
+            no_show_mins: parse_var(&var, "RESTAURANT_NO_SHOW_MINS", default.no_show_mins)?,
+            idempotency_secs: parse_var(
+                &var,
+                "RESTAURANT_IDEMPOTENCY_SECS",
+                default.idempotency_secs,
+            )?,
+        })
+    }
+}
+
+fn parse_var<T: FromStr, F: Fn(&str) -> Option<String>>(
+    var: &F,
+    name: &str,
+    default: T,
+) -> Result<T, Error> {
+    match var(name) {
+        Some(v) => v
+            .parse::<T>()
+            .map_err(|_| Error::Config(format!("{} has an invalid value '{}'", name, v))),
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_vars() {
+        let config = Config::from_vars(|name| match name {
+            "RESTAURANT_COOKS" => Some("2".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.cooks, 2);
+        assert_eq!(config.kitchen_tick_ms, 1000);
+        assert_eq!(config.storage, StorageKind::Memory);
+
+        let config = Config::from_vars(|_| Some("many".to_string()));
+        assert!(matches!(config, Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,241 @@
+//! Framing of HTTP requests on a byte stream.
+//!
+//! TCP gives no message boundaries: one request can arrive over several
+//! reads, and several pipelined requests can arrive in a single read. The
+//! `RequestBuffer` keeps unread bytes around until a full request is there.
+//! A request to switch to WebSocket ends the HTTP side of the connection
+//! and hands it back as an `Upgrade`.
+
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::error::Error;
+use super::http::{self, Request, Response};
+use super::websocket;
+
+/// Upper bound on a single request, headers and body included.
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+pub struct RequestBuffer {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl RequestBuffer {
+    pub fn new(limit: usize) -> RequestBuffer {
+        RequestBuffer { buf: vec![], limit }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Takes the next complete request off the front of the buffer.
+    pub fn next_request(&mut self) -> Result<Option<Request>, Error> {
+        match http::parse_request(&self.buf, self.limit) {
+            Ok(Some((req, used))) => {
+                // the trailers of a chunked body are only counted here
+                if used > self.limit {
+                    return Err(Error::RequestTooLarge);
+                }
+                self.buf.drain(..used);
+                Ok(Some(req))
+            }
+            Ok(None) if self.buf.len() > self.limit => Err(Error::RequestTooLarge),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The bytes read past the last request taken.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// A connection the client asked to switch over to WebSocket, with the
+/// request that asked.
+pub struct Upgrade<S> {
+    pub stream: S,
+    pub request: Request,
+    /// What the client sent after the request.
+    pub buffered: Vec<u8>,
+}
+
+impl<S: AsyncWrite + Unpin> Upgrade<S> {
+    /// Answers `res` instead of switching, and closes the connection.
+    pub async fn refuse(mut self, res: Response) -> io::Result<()> {
+        let res = res.with_header("Connection", "close");
+        self.stream.write_all(&res.to_bytes()).await
+    }
+}
+
+/// Serves requests from `stream` in the order they arrive until the peer
+/// closes the connection or asks to close it, or to switch it over to
+/// WebSocket. That request is left unanswered and handed back.
+pub async fn handle_connection<S, F>(
+    mut stream: S,
+    mut handler: F,
+) -> io::Result<Option<Upgrade<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(&Request) -> Response,
+{
+    let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+    let mut buf = vec![0; 4096];
+
+    loop {
+        // answer everything that is already buffered before reading more
+        loop {
+            match frames.next_request() {
+                Ok(Some(req)) if websocket::is_upgrade(&req) => {
+                    return Ok(Some(Upgrade {
+                        stream,
+                        request: req,
+                        buffered: frames.into_inner(),
+                    }));
+                }
+                Ok(Some(req)) => {
+                    let keep_alive = req.keep_alive();
+                    let mut response = handler(&req);
+                    if !keep_alive {
+                        response = response.with_header("Connection", "close");
+                    }
+                    stream.write_all(&response.to_bytes()).await?;
+
+                    if !keep_alive {
+                        return Ok(None);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // the stream cannot be resynchronised after a bad frame
+                    let response = e.to_response().with_header("Connection", "close");
+                    stream.write_all(&response.to_bytes()).await?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        frames.push(&buf[0..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn test_request_split_across_reads() {
+        let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+
+        frames.push(b"POST /add/1/2 HTTP/1.1\r\nContent-Le");
+        assert_eq!(frames.next_request(), Ok(None));
+        frames.push(b"ngth: 3\r\n\r\nab");
+        assert_eq!(frames.next_request(), Ok(None));
+        frames.push(b"c");
+
+        let req = frames.next_request().unwrap().unwrap();
+        assert_eq!(req.body, b"abc");
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn test_pipelined_requests_in_one_read() {
+        let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+
+        frames.push(b"GET /query/1 HTTP/1.1\r\n\r\nGET /query/2 HTTP/1.1\r\n\r\nGET /q");
+
+        assert_eq!(frames.next_request().unwrap().unwrap().path, "/query/1");
+        assert_eq!(frames.next_request().unwrap().unwrap().path, "/query/2");
+        assert_eq!(frames.next_request(), Ok(None));
+        assert!(!frames.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        let mut frames = RequestBuffer::new(32);
+
+        frames.push(b"POST /add/1/2 HTTP/1.1\r\nContent-Length: 100\r\n\r\n");
+        assert_eq!(frames.next_request(), Err(Error::RequestTooLarge));
+
+        let mut frames = RequestBuffer::new(32);
+        frames.push(&[b'a'; 64]);
+        assert_eq!(frames.next_request(), Err(Error::RequestTooLarge));
+    }
+
+    #[test]
+    fn test_declared_size_over_limit() {
+        // refused on the head alone, before any of the body arrives
+        let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+        frames.push(b"POST /add/1/2 HTTP/1.1\r\nContent-Length: 100000000\r\n\r\n");
+        assert_eq!(frames.next_request(), Err(Error::RequestTooLarge));
+
+        let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+        frames.push(b"POST /add/1/2 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        frames.push(b"5f5e100\r\n");
+        assert_eq!(frames.next_request(), Err(Error::RequestTooLarge));
+
+        // the chunks add up past the limit, the second is refused on its size
+        let mut frames = RequestBuffer::new(MAX_REQUEST_SIZE);
+        frames.push(b"POST /add/1/2 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        frames.push(b"8000\r\n");
+        frames.push(&[b'a'; 0x8000]);
+        frames.push(b"\r\n8000\r\n");
+        assert_eq!(frames.next_request(), Err(Error::RequestTooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_pipelined() {
+        let (mut client, server) = duplex(1024);
+
+        let task = tokio::spawn(handle_connection(server, |req: &Request| {
+            Response::ok(&req.path)
+        }));
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        task.await.unwrap().unwrap();
+
+        let a = out.find("\"/a\"").unwrap();
+        let b = out.find("\"/b\"").unwrap();
+        assert!(a < b);
+        assert!(out.contains("Connection: close"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_rejects_oversized() {
+        let (mut client, server) = duplex(MAX_REQUEST_SIZE * 2);
+
+        let task = tokio::spawn(handle_connection(server, |_: &Request| Response::ok(&())));
+
+        let head = format!(
+            "POST /add/1/2 HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_REQUEST_SIZE
+        );
+        client.write_all(head.as_bytes()).await.unwrap();
+        // the server may hang up before the whole body is written
+        let _ = client.write_all(&vec![b'a'; MAX_REQUEST_SIZE]).await;
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        task.await.unwrap().unwrap();
+
+        assert!(out.starts_with("HTTP/1.1 413 "));
+    }
+}
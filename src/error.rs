@@ -0,0 +1,334 @@
+use serde::Serialize;
+use std::fmt;
+
+use super::http::{HttpError, Response, StatusCode};
+use super::item::ItemState;
+use super::reservation::ReservationState;
+use super::waitlist::WaitState;
+
+/// Everything that can go wrong while serving a request.
+///
+/// Each variant has a stable `code` so clients can match on it instead of
+/// on the human readable message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    InvalidUtf8,
+    InvalidId(String),
+    TableNotFound(u32),
+    TableExists(u32),
+    TableDisabled(u32),
+    TableInUse(u32),
+    TableOccupied(u32),
+    TableNotSeated(u32),
+    TableMerged(u32),
+    TableNotMerged(u32),
+    TablesApart {
+        table_id: u32,
+        other: u32,
+    },
+    VersionMismatch {
+        table_id: u32,
+        expected: u64,
+        version: u64,
+    },
+    PartyTooLarge {
+        table_id: u32,
+        size: u32,
+    },
+    ItemNotFound(u32),
+    LineNotFound(u32),
+    LineClosed {
+        line_id: u32,
+        state: ItemState,
+    },
+    MenuItemNotFound(u32),
+    MenuItemUnavailable(u32),
+    MenuItemExists(u32),
+    ModifierNotAllowed {
+        item_id: u32,
+        modifier: String,
+    },
+    ItemsPending(u32),
+    NothingToBill(u32),
+    NothingToTransfer(u32),
+    BillNotFound(u32),
+    ReservationNotFound(u32),
+    ReservationClosed {
+        reservation_id: u32,
+        state: ReservationState,
+    },
+    ReservationConflict(u32),
+    NoTableAvailable(u32),
+    WaitlistEntryNotFound(u32),
+    NotWaiting {
+        entry_id: u32,
+        state: WaitState,
+    },
+    InvalidSplit(String),
+    InvalidTransition {
+        line_id: u32,
+        from: ItemState,
+        to: ItemState,
+    },
+    IdempotencyKeyInUse(String),
+    IdempotencyKeyReused(String),
+    MalformedBody(String),
+    MalformedRequest(String),
+    RequestTooLarge,
+    UnknownRoute,
+    MethodNotAllowed,
+    Config(String),
+    Storage(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidUtf8 => "invalid_utf8",
+            Error::InvalidId(_) => "invalid_id",
+            Error::TableNotFound(_) => "table_not_found",
+            Error::TableExists(_) => "table_exists",
+            Error::TableDisabled(_) => "table_disabled",
+            Error::TableInUse(_) => "table_in_use",
+            Error::TableOccupied(_) => "table_occupied",
+            Error::TableNotSeated(_) => "table_not_seated",
+            Error::TableMerged(_) => "table_merged",
+            Error::TableNotMerged(_) => "table_not_merged",
+            Error::TablesApart { .. } => "tables_apart",
+            Error::VersionMismatch { .. } => "version_mismatch",
+            Error::PartyTooLarge { .. } => "party_too_large",
+            Error::ItemNotFound(_) => "item_not_found",
+            Error::LineNotFound(_) => "line_not_found",
+            Error::LineClosed { .. } => "line_closed",
+            Error::MenuItemNotFound(_) => "menu_item_not_found",
+            Error::MenuItemUnavailable(_) => "menu_item_unavailable",
+            Error::MenuItemExists(_) => "menu_item_exists",
+            Error::ModifierNotAllowed { .. } => "modifier_not_allowed",
+            Error::ItemsPending(_) => "items_pending",
+            Error::NothingToBill(_) => "nothing_to_bill",
+            Error::NothingToTransfer(_) => "nothing_to_transfer",
+            Error::BillNotFound(_) => "bill_not_found",
+            Error::ReservationNotFound(_) => "reservation_not_found",
+            Error::ReservationClosed { .. } => "reservation_closed",
+            Error::ReservationConflict(_) => "reservation_conflict",
+            Error::NoTableAvailable(_) => "no_table_available",
+            Error::WaitlistEntryNotFound(_) => "waitlist_entry_not_found",
+            Error::NotWaiting { .. } => "not_waiting",
+            Error::InvalidSplit(_) => "invalid_split",
+            Error::InvalidTransition { .. } => "invalid_transition",
+            Error::IdempotencyKeyInUse(_) => "idempotency_key_in_use",
+            Error::IdempotencyKeyReused(_) => "idempotency_key_reused",
+            Error::MalformedBody(_) => "malformed_body",
+            Error::MalformedRequest(_) => "malformed_request",
+            Error::RequestTooLarge => "request_too_large",
+            Error::UnknownRoute => "unknown_route",
+            Error::MethodNotAllowed => "method_not_allowed",
+            Error::Config(_) => "config_error",
+            Error::Storage(_) => "storage_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::TableNotFound(_)
+            | Error::ItemNotFound(_)
+            | Error::LineNotFound(_)
+            | Error::BillNotFound(_)
+            | Error::ReservationNotFound(_)
+            | Error::WaitlistEntryNotFound(_)
+            | Error::MenuItemNotFound(_)
+            | Error::UnknownRoute => StatusCode::NotFound,
+            Error::MethodNotAllowed => StatusCode::MethodNotAllowed,
+            Error::TableExists(_)
+            | Error::TableDisabled(_)
+            | Error::TableInUse(_)
+            | Error::TableOccupied(_)
+            | Error::TableNotSeated(_)
+            | Error::TableMerged(_)
+            | Error::TableNotMerged(_)
+            | Error::TablesApart { .. }
+            | Error::VersionMismatch { .. }
+            | Error::PartyTooLarge { .. }
+            | Error::LineClosed { .. }
+            | Error::MenuItemUnavailable(_)
+            | Error::MenuItemExists(_)
+            | Error::ItemsPending(_)
+            | Error::NothingToBill(_)
+            | Error::NothingToTransfer(_)
+            | Error::ReservationClosed { .. }
+            | Error::ReservationConflict(_)
+            | Error::NoTableAvailable(_)
+            | Error::NotWaiting { .. }
+            | Error::InvalidTransition { .. }
+            | Error::IdempotencyKeyInUse(_) => StatusCode::Conflict,
+            Error::IdempotencyKeyReused(_) => StatusCode::UnprocessableEntity,
+            Error::RequestTooLarge => StatusCode::PayloadTooLarge,
+            Error::Config(_) | Error::Storage(_) => StatusCode::InternalServerError,
+            _ => StatusCode::BadRequest,
+        }
+    }
+
+    /// The `{"code": ..., "msg": ...}` part of the error envelope.
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code(),
+            msg: self.to_string(),
+        }
+    }
+
+    /// Renders the error envelope `{"error": {"code": ..., "msg": ...}}`.
+    pub fn to_response(&self) -> Response {
+        let body = ErrorEnvelope { error: self.body() };
+
+        Response::new(self.status(), serde_json::to_string(&body).unwrap())
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ErrorBody {
+    code: &'static str,
+    msg: String,
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUtf8 => write!(f, "request is not valid utf-8"),
+            Error::InvalidId(s) => write!(f, "'{}' is not a valid id", s),
+            Error::TableNotFound(tid) => write!(f, "table {} does not exist", tid),
+            Error::TableExists(tid) => write!(f, "table {} already exists", tid),
+            Error::TableDisabled(tid) => write!(f, "table {} is disabled", tid),
+            Error::TableInUse(tid) => write!(f, "table {} has open orders", tid),
+            Error::TableOccupied(tid) => write!(f, "table {} is already taken", tid),
+            Error::TableNotSeated(tid) => write!(f, "nobody is seated at table {}", tid),
+            Error::TableMerged(tid) => {
+                write!(f, "table {} is pushed together with other tables", tid)
+            }
+            Error::TableNotMerged(tid) => {
+                write!(f, "table {} is not pushed together with others", tid)
+            }
+            Error::TablesApart { table_id, other } => write!(
+                f,
+                "table {} cannot be pushed together with table {}",
+                table_id, other
+            ),
+            Error::VersionMismatch {
+                table_id,
+                expected,
+                version,
+            } => write!(
+                f,
+                "table {} is at version {}, not {}",
+                table_id, version, expected
+            ),
+            Error::PartyTooLarge { table_id, size } => {
+                write!(f, "a party of {} does not fit at table {}", size, table_id)
+            }
+            Error::ItemNotFound(iid) => write!(f, "item {} is not on the table", iid),
+            Error::LineNotFound(line_id) => write!(f, "order line {} does not exist", line_id),
+            Error::LineClosed { line_id, state } => write!(
+                f,
+                "order line {} is {} and can no longer be changed",
+                line_id, state
+            ),
+            Error::MenuItemNotFound(iid) => write!(f, "item {} is not on the menu", iid),
+            Error::MenuItemUnavailable(iid) => write!(f, "item {} cannot be ordered", iid),
+            Error::MenuItemExists(iid) => write!(f, "item {} is already on the menu", iid),
+            Error::ModifierNotAllowed { item_id, modifier } => {
+                write!(f, "item {} cannot be ordered '{}'", item_id, modifier)
+            }
+            Error::ItemsPending(tid) => {
+                write!(f, "table {} still has items that are not served", tid)
+            }
+            Error::NothingToBill(tid) => write!(f, "table {} has nothing to bill", tid),
+            Error::NothingToTransfer(tid) => write!(f, "table {} has nothing to move", tid),
+            Error::BillNotFound(bid) => write!(f, "bill {} does not exist", bid),
+            Error::ReservationNotFound(rid) => write!(f, "reservation {} does not exist", rid),
+            Error::ReservationClosed {
+                reservation_id,
+                state,
+            } => write!(f, "reservation {} is {}", reservation_id, state),
+            Error::ReservationConflict(tid) => {
+                write!(f, "table {} is already booked for that time", tid)
+            }
+            Error::NoTableAvailable(size) => {
+                write!(f, "no table can take a party of {}", size)
+            }
+            Error::WaitlistEntryNotFound(eid) => {
+                write!(f, "waitlist entry {} does not exist", eid)
+            }
+            Error::NotWaiting { entry_id, state } => {
+                write!(f, "waitlist entry {} is {}", entry_id, state)
+            }
+            Error::InvalidSplit(s) => write!(f, "cannot split the bill: {}", s),
+            Error::InvalidTransition { line_id, from, to } => {
+                write!(f, "line {} cannot go from {} to {}", line_id, from, to)
+            }
+            Error::IdempotencyKeyInUse(key) => write!(
+                f,
+                "a request with idempotency key '{}' is still being served",
+                key
+            ),
+            Error::IdempotencyKeyReused(key) => write!(
+                f,
+                "idempotency key '{}' was already used for another request",
+                key
+            ),
+            Error::MalformedBody(s) => write!(f, "malformed body: {}", s),
+            Error::MalformedRequest(s) => write!(f, "malformed request: {}", s),
+            Error::RequestTooLarge => write!(f, "request too large"),
+            Error::UnknownRoute => write!(f, "unknown request"),
+            Error::MethodNotAllowed => write!(f, "method not allowed"),
+            Error::Config(s) => write!(f, "configuration error: {}", s),
+            Error::Storage(s) => write!(f, "storage error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Error {
+        match e {
+            HttpError::InvalidUtf8 => Error::InvalidUtf8,
+            HttpError::TooLarge => Error::RequestTooLarge,
+            e => Error::MalformedRequest(e.to_string()),
+        }
+    }
+}
+
+/// Parses a path segment as a numeric id.
+pub fn parse_id(s: &str) -> Result<u32, Error> {
+    s.parse::<u32>()
+        .map_err(|_| Error::InvalidId(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("12"), Ok(12));
+        assert_eq!(parse_id("abc"), Err(Error::InvalidId("abc".to_string())));
+        assert_eq!(parse_id("-1"), Err(Error::InvalidId("-1".to_string())));
+    }
+
+    #[test]
+    fn test_error_response() {
+        let res = Error::TableNotFound(9999).to_response();
+
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(res.body.contains("\"code\":\"table_not_found\""));
+        assert!(res.body.contains("table 9999 does not exist"));
+
+        let res = Error::InvalidId("a\"b".to_string()).to_response();
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(res.body.contains("'a\\\"b' is not a valid id"));
+    }
+}
@@ -0,0 +1,400 @@
+//! Changes to the tables, pushed to clients subscribed over WebSocket.
+//!
+//! Every change logged with `Restaurant::log` is also published as
+//! `Event`s on a broadcast channel, with the table still locked so the
+//! events of each table go out in order. Publishing never waits for a
+//! subscriber: one that falls more than `EVENT_BUFFER` events behind skips
+//! ahead and is told how many it missed, and one that stops reading is
+//! dropped after `WRITE_TIMEOUT`.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+
+use super::connection::Upgrade;
+use super::error::{parse_id, Error};
+use super::item::{Item, ItemState};
+use super::restaurant::Restaurant;
+use super::storage::Op;
+use super::websocket::{self, Frame, Opcode};
+
+/// Events kept for subscribers that have not read them yet.
+pub const EVENT_BUFFER: usize = 256;
+
+/// How long a subscriber may take to accept an event before it is dropped.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One change as subscribers see it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ItemAdded {
+        table_id: u32,
+        item: Item,
+    },
+    /// A line was changed before the kitchen started on it.
+    ItemUpdated {
+        table_id: u32,
+        item: Item,
+    },
+    /// A line was cancelled.
+    ItemRemoved {
+        table_id: u32,
+        item_id: u32,
+        line_id: u32,
+    },
+    StatusChanged {
+        table_id: u32,
+        item_id: u32,
+        line_id: u32,
+        state: ItemState,
+    },
+    /// Lines went from `table_id` to `to`, by a transfer or a split.
+    LinesMoved {
+        table_id: u32,
+        to: u32,
+        line_ids: Vec<u32>,
+    },
+    /// The tables were pushed together, their lines now on `table_id`.
+    TablesMerged {
+        table_id: u32,
+        members: Vec<u32>,
+    },
+    /// The table was checked out and its session closed in `bill_id`.
+    TableClosed {
+        table_id: u32,
+        bill_id: u32,
+    },
+    /// Sent instead of the events a subscriber was too slow to take.
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl Event {
+    /// What subscribers are told about `op`. Changes that do not touch the
+    /// orders, such as seating a party, are not published.
+    pub fn from_op(op: &Op) -> Vec<Event> {
+        match op {
+            Op::Add { item } => vec![Event::ItemAdded {
+                table_id: item.table_id(),
+                item: item.clone(),
+            }],
+            Op::Update { item } => vec![Event::ItemUpdated {
+                table_id: item.table_id(),
+                item: item.clone(),
+            }],
+            Op::SetState {
+                table_id,
+                item_id,
+                line_id,
+                state: ItemState::Cancelled,
+                ..
+            } => vec![Event::ItemRemoved {
+                table_id: *table_id,
+                item_id: *item_id,
+                line_id: *line_id,
+            }],
+            Op::SetState {
+                table_id,
+                item_id,
+                line_id,
+                state,
+                ..
+            } => vec![Event::StatusChanged {
+                table_id: *table_id,
+                item_id: *item_id,
+                line_id: *line_id,
+                state: *state,
+            }],
+            Op::Checkout { bill } => vec![Event::TableClosed {
+                table_id: bill.table_id(),
+                bill_id: bill.id(),
+            }],
+            Op::Transfer { from, to, line_ids } => vec![Event::LinesMoved {
+                table_id: *from,
+                to: *to,
+                line_ids: line_ids.clone(),
+            }],
+            Op::Merge { table_ids } => match table_ids.split_first() {
+                Some((&table_id, members)) => vec![Event::TablesMerged {
+                    table_id,
+                    members: members.to_vec(),
+                }],
+                None => vec![],
+            },
+            Op::Split { table_id, lines } => {
+                let mut by_table = BTreeMap::<u32, Vec<u32>>::new();
+                for (&line_id, &to) in lines.iter() {
+                    by_table.entry(to).or_default().push(line_id);
+                }
+                by_table
+                    .into_iter()
+                    .filter(|(to, _)| to != table_id)
+                    .map(|(to, line_ids)| Event::LinesMoved {
+                        table_id: *table_id,
+                        to,
+                        line_ids,
+                    })
+                    .collect()
+            }
+            Op::Batch { ops } => ops.iter().flat_map(Event::from_op).collect(),
+            Op::Menu { .. }
+            | Op::Table { .. }
+            | Op::Seat { .. }
+            | Op::Unseat { .. }
+            | Op::Reservation { .. }
+            | Op::Waitlist { .. }
+            | Op::Idempotency { .. } => vec![],
+        }
+    }
+
+    /// Every table the event is about.
+    fn table_ids(&self) -> Vec<u32> {
+        match self {
+            Event::ItemAdded { table_id, .. }
+            | Event::ItemUpdated { table_id, .. }
+            | Event::ItemRemoved { table_id, .. }
+            | Event::StatusChanged { table_id, .. }
+            | Event::TableClosed { table_id, .. } => vec![*table_id],
+            Event::LinesMoved { table_id, to, .. } => vec![*table_id, *to],
+            Event::TablesMerged { table_id, members } => [&[*table_id], &members[..]].concat(),
+            Event::Lagged { .. } => vec![],
+        }
+    }
+}
+
+/// What a subscriber listens to.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Topic {
+    /// Every table in the restaurant.
+    Restaurant,
+    Tables(BTreeSet<u32>),
+}
+
+impl Topic {
+    /// Reads the topic from the path after `/subscribe`: nothing for the
+    /// whole restaurant, or a table id or a comma separated list of them.
+    pub fn parse(params: &[&str]) -> Result<Topic, Error> {
+        match params {
+            [] | [""] => Ok(Topic::Restaurant),
+            [ids] => ids
+                .split(',')
+                .map(parse_id)
+                .collect::<Result<BTreeSet<_>, _>>()
+                .map(Topic::Tables),
+            _ => Err(Error::UnknownRoute),
+        }
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        match self {
+            Topic::Restaurant => true,
+            Topic::Tables(ids) => event.table_ids().iter().any(|tid| ids.contains(tid)),
+        }
+    }
+}
+
+/// Answers the WebSocket handshake on `upgrade`, then pushes every event
+/// `topic` asks for until the client closes the connection or stops
+/// reading.
+pub async fn serve<S>(upgrade: Upgrade<S>, topic: Topic, restaurant: Restaurant) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let res = match websocket::handshake(&upgrade.request) {
+        Some(res) => res,
+        None => {
+            let e = Error::MalformedRequest("not a WebSocket handshake".to_string());
+            return upgrade.refuse(e.to_response()).await;
+        }
+    };
+    let Upgrade {
+        mut stream,
+        buffered: mut frames,
+        ..
+    } = upgrade;
+
+    // subscribed before the handshake is answered, so that no change made
+    // after the client sees it is missed
+    let mut events = restaurant.subscribe();
+    stream.write_all(&res.to_bytes()).await?;
+    let mut buf = vec![0; 4096];
+
+    loop {
+        // answer everything the client already sent before waiting again
+        loop {
+            match websocket::parse_frame(&frames) {
+                Ok(Some((frame, used))) => {
+                    frames.drain(..used);
+                    match frame.opcode {
+                        Opcode::Ping => send(&mut stream, Frame::pong(frame.payload)).await?,
+                        Opcode::Close => return send(&mut stream, Frame::close(1000)).await,
+                        // a subscriber has nothing else to say
+                        _ => {}
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    println!("subscriber sent a bad frame: {}", e);
+                    return send(&mut stream, Frame::close(1002)).await;
+                }
+            }
+        }
+
+        tokio::select! {
+            n = stream.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                frames.extend_from_slice(&buf[..n]);
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) if topic.wants(&event) => event,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => Event::Lagged { missed },
+                    // the server is shutting down
+                    Err(RecvError::Closed) => return send(&mut stream, Frame::close(1001)).await,
+                };
+                let text = serde_json::to_string(&event).unwrap();
+                send(&mut stream, Frame::text(&text)).await?;
+            }
+        }
+    }
+}
+
+/// Writes `frame`, giving up on a subscriber that does not take it within
+/// `WRITE_TIMEOUT`.
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, frame: Frame) -> io::Result<()> {
+    match timeout(WRITE_TIMEOUT, stream.write_all(&frame.to_bytes())).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "subscriber stopped reading",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MAX_REQUEST_SIZE;
+    use crate::http;
+    use crate::layout::Layout;
+    use crate::menu::{Menu, MenuItem};
+    use tokio::io::{duplex, DuplexStream};
+
+    #[test]
+    fn test_event_from_op() {
+        let item = Item::new(7, &MenuItem::sample(1), 2, 5, 0);
+        let ops = vec![
+            Op::Add { item: item.clone() },
+            Op::SetState {
+                table_id: 2,
+                item_id: 1,
+                line_id: 7,
+                state: ItemState::Cancelled,
+                at: 1,
+            },
+            Op::Unseat { table_id: 2 },
+            Op::Split {
+                table_id: 2,
+                lines: BTreeMap::from([(7, 3), (8, 2), (9, 3)]),
+            },
+        ];
+        let events = Event::from_op(&Op::Batch { ops });
+
+        assert_eq!(
+            events,
+            vec![
+                Event::ItemAdded { table_id: 2, item },
+                Event::ItemRemoved {
+                    table_id: 2,
+                    item_id: 1,
+                    line_id: 7
+                },
+                Event::LinesMoved {
+                    table_id: 2,
+                    to: 3,
+                    line_ids: vec![7, 9]
+                },
+            ]
+        );
+
+        let topic = Topic::parse(&["3,4"]).unwrap();
+        assert_eq!(
+            events.iter().map(|e| topic.wants(e)).collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+        assert_eq!(Topic::parse(&[]), Ok(Topic::Restaurant));
+        assert_eq!(
+            Topic::parse(&["1,x"]),
+            Err(Error::InvalidId("x".to_string()))
+        );
+    }
+
+    /// Reads one unmasked text frame the server sent.
+    async fn read_text(client: &mut DuplexStream) -> String {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x81);
+        let len = match head[1] {
+            126 => client.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_events_serve() {
+        let restaurant = Restaurant::new(Layout::sample(2), Menu::sample(5));
+        let (mut client, server) = duplex(64 * 1024);
+
+        let raw = "GET /subscribe/1 HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                   Sec-WebSocket-Version: 13\r\n\r\n";
+        let (request, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        let upgrade = Upgrade {
+            stream: server,
+            request,
+            buffered: vec![],
+        };
+        let topic = Topic::parse(&["1"]).unwrap();
+        let task = tokio::spawn(serve(upgrade, topic, restaurant.clone()));
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.ends_with("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"));
+
+        // only what happens at table 1 is pushed
+        let item = |tid| Item::new(tid, &MenuItem::sample(1), tid, 5, 0);
+        restaurant.log(Op::Add { item: item(0) }).unwrap();
+        restaurant.log(Op::Add { item: item(1) }).unwrap();
+        let text = read_text(&mut client).await;
+        assert!(text.starts_with("{\"type\":\"item_added\",\"table_id\":1,\"item\":{"));
+
+        // a masked close from the client is answered and ends the session
+        client
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8])
+            .await
+            .unwrap();
+        let mut close = [0; 4];
+        client.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        task.await.unwrap().unwrap();
+    }
+}
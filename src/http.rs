@@ -0,0 +1,479 @@
+//! A minimal HTTP/1.1 request parser and response writer.
+//!
+//! The parser works on a byte buffer and reports whether a whole request is
+//! available yet, so the caller decides how bytes are read off the socket.
+
+use serde::Serialize;
+use std::fmt;
+use std::str;
+
+use super::error::Error;
+
+#[derive(Debug, PartialEq)]
+pub enum HttpError {
+    BadRequestLine,
+    BadHeader,
+    BadContentLength,
+    BadChunk,
+    TooLarge,
+    UnsupportedVersion,
+    InvalidUtf8,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            HttpError::BadRequestLine => "malformed request line",
+            HttpError::BadHeader => "malformed header",
+            HttpError::BadContentLength => "invalid content-length",
+            HttpError::BadChunk => "malformed chunked body",
+            HttpError::TooLarge => "request too large",
+            HttpError::UnsupportedVersion => "unsupported http version",
+            HttpError::InvalidUtf8 => "request head is not valid utf-8",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Looks up a header by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// HTTP/1.1 connections stay open unless the client asks otherwise,
+    /// HTTP/1.0 ones close unless the client asks to keep them.
+    pub fn keep_alive(&self) -> bool {
+        let conn = self.header("connection").map(|v| v.to_ascii_lowercase());
+        match conn.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+fn find(buf: &[u8], pat: &[u8]) -> Option<usize> {
+    buf.windows(pat.len()).position(|w| w == pat)
+}
+
+/// Parses one request from the front of `buf`.
+///
+/// Returns `Ok(None)` while the request is still incomplete, otherwise the
+/// request together with the number of bytes it used. A request whose head
+/// says it takes more than `limit` bytes is refused as soon as the head is
+/// in, without waiting for the body.
+pub fn parse_request(buf: &[u8], limit: usize) -> Result<Option<(Request, usize)>, HttpError> {
+    let head_end = match find(buf, b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let head = str::from_utf8(&buf[..head_end]).map_err(|_| HttpError::InvalidUtf8)?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().ok_or(HttpError::BadRequestLine)?;
+    let parts = request_line.split(' ').collect::<Vec<&str>>();
+    if parts.len() != 3 || parts[0].is_empty() || !parts[1].starts_with('/') {
+        return Err(HttpError::BadRequestLine);
+    }
+    if parts[2] != "HTTP/1.1" && parts[2] != "HTTP/1.0" {
+        return Err(HttpError::UnsupportedVersion);
+    }
+
+    let (path, query) = match parts[1].split_once('?') {
+        Some((p, q)) => (p.to_string(), Some(q.to_string())),
+        None => (parts[1].to_string(), None),
+    };
+
+    let mut headers = vec![];
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(HttpError::BadHeader)?;
+        if name.is_empty() || name.contains(' ') {
+            return Err(HttpError::BadHeader);
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let mut req = Request {
+        method: parts[0].to_string(),
+        path,
+        query,
+        version: parts[2].to_string(),
+        headers,
+        body: vec![],
+    };
+
+    let body_start = head_end + 4;
+    let chunked = req
+        .header("transfer-encoding")
+        .map(|v| v.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+
+    if chunked {
+        match parse_chunked(&buf[body_start..], limit.saturating_sub(body_start))? {
+            Some((body, used)) => {
+                req.body = body;
+                Ok(Some((req, body_start + used)))
+            }
+            None => Ok(None),
+        }
+    } else {
+        let len = match req.header("content-length") {
+            Some(v) => v
+                .parse::<usize>()
+                .map_err(|_| HttpError::BadContentLength)?,
+            None => 0,
+        };
+        // the length is the client's word, it may be anything
+        let end = body_start
+            .checked_add(len)
+            .ok_or(HttpError::BadContentLength)?;
+        if end > limit {
+            return Err(HttpError::TooLarge);
+        }
+        if buf.len() < end {
+            return Ok(None);
+        }
+        req.body = buf[body_start..end].to_vec();
+        Ok(Some((req, end)))
+    }
+}
+
+/// Decodes a chunked body, returning the body and the bytes consumed. Each
+/// chunk is checked against `limit` as soon as its size line is in.
+fn parse_chunked(buf: &[u8], limit: usize) -> Result<Option<(Vec<u8>, usize)>, HttpError> {
+    let mut body = vec![];
+    let mut pos = 0;
+
+    loop {
+        let line_end = match find(&buf[pos..], b"\r\n") {
+            Some(n) => pos + n,
+            None => return Ok(None),
+        };
+        let size_line = str::from_utf8(&buf[pos..line_end]).map_err(|_| HttpError::BadChunk)?;
+        // chunk extensions after ';' are ignored
+        let size_str = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| HttpError::BadChunk)?;
+        pos = line_end + 2;
+
+        if size == 0 {
+            // skip trailers up to the terminating empty line
+            loop {
+                let end = match find(&buf[pos..], b"\r\n") {
+                    Some(n) => pos + n,
+                    None => return Ok(None),
+                };
+                let empty = end == pos;
+                pos = end + 2;
+                if empty {
+                    return Ok(Some((body, pos)));
+                }
+            }
+        }
+
+        let end = pos
+            .checked_add(size)
+            .and_then(|n| n.checked_add(2))
+            .ok_or(HttpError::BadChunk)?;
+        if end > limit {
+            return Err(HttpError::TooLarge);
+        }
+        if buf.len() < end {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[pos..pos + size]);
+        if &buf[pos + size..end] != b"\r\n" {
+            return Err(HttpError::BadChunk);
+        }
+        pos = end;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    UnprocessableEntity,
+    PayloadTooLarge,
+    InternalServerError,
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::BadRequest => 400,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::Conflict => 409,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::InternalServerError => 500,
+        }
+    }
+
+    /// The status with the number `code`, if it is one this server sends.
+    pub fn from_code(code: u16) -> Option<StatusCode> {
+        [
+            StatusCode::SwitchingProtocols,
+            StatusCode::Ok,
+            StatusCode::Created,
+            StatusCode::BadRequest,
+            StatusCode::NotFound,
+            StatusCode::MethodNotAllowed,
+            StatusCode::Conflict,
+            StatusCode::UnprocessableEntity,
+            StatusCode::PayloadTooLarge,
+            StatusCode::InternalServerError,
+        ]
+        .into_iter()
+        .find(|s| s.code() == code)
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::UnprocessableEntity => "Unprocessable Entity",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+        }
+    }
+}
+
+/// The `ETag` header value for a table at `version`.
+pub fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Reads a version back out of an entity tag as sent in `If-Match`,
+/// quoted or not.
+pub fn parse_etag(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let value = value.strip_prefix("W/").unwrap_or(value);
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value.parse().ok()
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    data: &'a T,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: StatusCode, body: String) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body,
+        }
+    }
+
+    /// Wraps `data` in the success envelope `{"data": ...}`.
+    pub fn json<T: Serialize>(status: StatusCode, data: &T) -> Response {
+        match serde_json::to_string(&Envelope { data }) {
+            Ok(body) => Response::new(status, body),
+            // the data is ours, so this is a server error
+            Err(e) => Error::Storage(e.to_string()).to_response(),
+        }
+    }
+
+    pub fn ok<T: Serialize>(data: &T) -> Response {
+        Response::json(StatusCode::Ok, data)
+    }
+
+    pub fn created<T: Serialize>(data: &T) -> Response {
+        Response::json(StatusCode::Created, data)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.code(),
+            self.status.reason()
+        );
+        // an informational response such as `101` has no body at all
+        if self.status.code() >= 200 {
+            out += &format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                self.body.len()
+            );
+        }
+        for (name, value) in self.headers.iter() {
+            out += &format!("{}: {}\r\n", name, value);
+        }
+        out += "\r\n";
+        out += &self.body;
+
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MAX_REQUEST_SIZE;
+
+    #[test]
+    fn test_parse_simple_request() {
+        let raw = b"GET /query/1?x=2 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (req, used) = parse_request(raw, MAX_REQUEST_SIZE).unwrap().unwrap();
+
+        assert_eq!(used, raw.len());
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/query/1");
+        assert_eq!(req.query.as_deref(), Some("x=2"));
+        assert_eq!(req.header("HOST"), Some("localhost"));
+        assert!(req.keep_alive());
+    }
+
+    #[test]
+    fn test_parse_etag() {
+        assert_eq!(parse_etag(&etag(12)), Some(12));
+        assert_eq!(parse_etag(" W/\"3\""), Some(3));
+        assert_eq!(parse_etag("7"), Some(7));
+        assert_eq!(parse_etag("\"x\""), None);
+    }
+
+    #[test]
+    fn test_parse_incomplete_request() {
+        assert_eq!(
+            parse_request(b"GET /query/1 HTTP/1.1\r\n", MAX_REQUEST_SIZE),
+            Ok(None)
+        );
+
+        let raw = b"POST /add/1 HTTP/1.1\r\nContent-Length: 10\r\n\r\n12345";
+        assert_eq!(parse_request(raw, MAX_REQUEST_SIZE), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_content_length_body() {
+        let raw = b"POST /add/1 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (req, used) = parse_request(raw, MAX_REQUEST_SIZE).unwrap().unwrap();
+
+        assert_eq!(req.body, b"hello");
+        assert_eq!(&raw[used..], b"GET");
+    }
+
+    #[test]
+    fn test_parse_chunked_body() {
+        let raw = b"POST /add/1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n";
+        let (req, used) = parse_request(raw, MAX_REQUEST_SIZE).unwrap().unwrap();
+
+        assert_eq!(req.body, b"hello world");
+        assert_eq!(used, raw.len());
+
+        let partial = b"POST /add/1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel";
+        assert_eq!(parse_request(partial, MAX_REQUEST_SIZE), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_bad_requests() {
+        assert_eq!(
+            parse_request(b"GET /query/1\r\n\r\n", MAX_REQUEST_SIZE),
+            Err(HttpError::BadRequestLine)
+        );
+        assert_eq!(
+            parse_request(b"GET /query/1 HTTP/2\r\n\r\n", MAX_REQUEST_SIZE),
+            Err(HttpError::UnsupportedVersion)
+        );
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nno colon\r\n\r\n", MAX_REQUEST_SIZE),
+            Err(HttpError::BadHeader)
+        );
+        assert_eq!(
+            parse_request(b"GET /\xff HTTP/1.1\r\n\r\n", MAX_REQUEST_SIZE),
+            Err(HttpError::InvalidUtf8)
+        );
+        assert_eq!(
+            parse_request(
+                b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+                MAX_REQUEST_SIZE
+            ),
+            Err(HttpError::BadContentLength)
+        );
+    }
+
+    #[test]
+    fn test_parse_huge_lengths() {
+        let raw = format!(
+            "POST /add/1 HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello",
+            usize::MAX
+        );
+        assert_eq!(
+            parse_request(raw.as_bytes(), MAX_REQUEST_SIZE),
+            Err(HttpError::BadContentLength)
+        );
+
+        let raw = format!(
+            "POST /add/1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\nhello\r\n",
+            usize::MAX
+        );
+        assert_eq!(
+            parse_request(raw.as_bytes(), MAX_REQUEST_SIZE),
+            Err(HttpError::BadChunk)
+        );
+    }
+
+    #[test]
+    fn test_response_to_bytes() {
+        let res = Response::created(&vec![1, 2]);
+        let s = String::from_utf8(res.to_bytes()).unwrap();
+
+        assert!(s.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(s.contains("Content-Length: 14\r\n"));
+        assert!(s.ends_with("\r\n\r\n{\"data\":[1,2]}"));
+    }
+
+    #[test]
+    fn test_response_json_error() {
+        // JSON object keys must be strings
+        let data = std::collections::BTreeMap::from([((1, 2), 3)]);
+        let res = Response::ok(&data);
+
+        assert_eq!(res.status, StatusCode::InternalServerError);
+        let body = serde_json::from_str::<serde_json::Value>(&res.body).unwrap();
+        assert_eq!(body["error"]["code"], "storage_error");
+    }
+}
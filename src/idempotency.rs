@@ -0,0 +1,221 @@
+//! Responses kept by the `Idempotency-Key` a change was sent with, so a
+//! client that retries after a timeout gets the first answer back instead
+//! of making the change twice.
+//!
+//! `IdempotencyKeys` is never locked together with a table. It is taken
+//! briefly before a change is served and again after it is done.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::error::Error;
+use super::http::{Request, Response, StatusCode};
+
+/// Longest key a client may send.
+pub const MAX_KEY_LEN: usize = 255;
+
+/// The header added to a response that was kept from an earlier request.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// A key as sent in the `Idempotency-Key` header, checked to be printable
+/// ASCII of a sensible length.
+pub fn check_key(key: &str) -> Result<&str, Error> {
+    let key = key.trim();
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(Error::MalformedRequest(format!(
+            "an idempotency key is 1 to {} printable characters",
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(key)
+}
+
+/// Tells requests apart by their method, path, query and body. This is
+/// FNV-1a, which unlike the std hashers stays the same across restarts.
+pub fn fingerprint(req: &Request) -> u64 {
+    let parts = [
+        req.method.as_bytes(),
+        req.path.as_bytes(),
+        req.query.as_deref().unwrap_or("").as_bytes(),
+        &req.body,
+    ];
+
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for part in parts {
+        for &b in part.iter().chain(&[0xff]) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// The response a change sent with `key` got the first time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeptResponse {
+    pub key: String,
+    /// The request that was served, see `fingerprint`.
+    pub fingerprint: u64,
+    pub at: u64,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl KeptResponse {
+    pub fn new(key: &str, fingerprint: u64, at: u64, res: &Response) -> KeptResponse {
+        KeptResponse {
+            key: key.to_string(),
+            fingerprint,
+            at,
+            status: res.status.code(),
+            headers: res.headers.clone(),
+            body: res.body.clone(),
+        }
+    }
+
+    /// The response again, marked as a replay.
+    fn to_response(&self) -> Response {
+        Response {
+            status: StatusCode::from_code(self.status).unwrap_or(StatusCode::Ok),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+        }
+        .with_header(REPLAYED_HEADER, "true")
+    }
+}
+
+enum Slot {
+    /// The first request with the key is still being served.
+    InFlight,
+    Done(KeptResponse),
+}
+
+/// Every key seen within the window, with what it was answered.
+///
+/// Only changes that went through are kept. One that failed made no
+/// change, so sending it again is safe and may well work this time.
+pub struct IdempotencyKeys {
+    window_ms: u64,
+    keys: HashMap<String, Slot>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(window_secs: u64) -> IdempotencyKeys {
+        IdempotencyKeys {
+            window_ms: window_secs * 1000,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Claims `key` for the request `fingerprint`. Returns the kept response
+    /// if the request was already served, otherwise the caller serves it
+    /// and then calls `finish` or `release`.
+    pub fn begin(
+        &mut self,
+        key: &str,
+        fingerprint: u64,
+        now: u64,
+    ) -> Result<Option<Response>, Error> {
+        self.expire(now);
+
+        match self.keys.get(key) {
+            None => {
+                self.keys.insert(key.to_string(), Slot::InFlight);
+                Ok(None)
+            }
+            Some(Slot::InFlight) => Err(Error::IdempotencyKeyInUse(key.to_string())),
+            Some(Slot::Done(kept)) if kept.fingerprint != fingerprint => {
+                Err(Error::IdempotencyKeyReused(key.to_string()))
+            }
+            Some(Slot::Done(kept)) => Ok(Some(kept.to_response())),
+        }
+    }
+
+    /// Keeps the response to a request claimed with `begin`, or one kept
+    /// before a restart.
+    pub fn finish(&mut self, kept: KeptResponse) {
+        self.keys.insert(kept.key.clone(), Slot::Done(kept));
+    }
+
+    /// Gives up a key claimed with `begin`, as the request made no change.
+    pub fn release(&mut self, key: &str) {
+        if let Some(Slot::InFlight) = self.keys.get(key) {
+            self.keys.remove(key);
+        }
+    }
+
+    /// Forgets the responses kept for longer than the window.
+    pub fn expire(&mut self, now: u64) {
+        let window_ms = self.window_ms;
+        self.keys.retain(|_, slot| match slot {
+            Slot::InFlight => true,
+            Slot::Done(kept) => kept.at + window_ms > now,
+        });
+    }
+
+    /// Every response kept, oldest first.
+    pub fn list(&self) -> Vec<&KeptResponse> {
+        let mut kept = self
+            .keys
+            .values()
+            .filter_map(|slot| match slot {
+                Slot::Done(kept) => Some(kept),
+                Slot::InFlight => None,
+            })
+            .collect::<Vec<_>>();
+        kept.sort_by(|a, b| (a.at, &a.key).cmp(&(b.at, &b.key)));
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_keys() {
+        let mut keys = IdempotencyKeys::new(60);
+        let res = Response::created(&1).with_header("ETag", "\"2\"");
+
+        assert_eq!(keys.begin("a", 1, 0), Ok(None));
+        assert_eq!(
+            keys.begin("a", 1, 0),
+            Err(Error::IdempotencyKeyInUse("a".to_string()))
+        );
+        keys.finish(KeptResponse::new("a", 1, 0, &res));
+
+        let replay = keys.begin("a", 1, 1000).unwrap().unwrap();
+        assert_eq!(replay.status, StatusCode::Created);
+        assert_eq!(replay.body, res.body);
+        assert_eq!(
+            replay.headers,
+            vec![
+                ("ETag".to_string(), "\"2\"".to_string()),
+                (REPLAYED_HEADER.to_string(), "true".to_string())
+            ]
+        );
+        assert_eq!(
+            keys.begin("a", 2, 1000),
+            Err(Error::IdempotencyKeyReused("a".to_string()))
+        );
+
+        // a failed request leaves the key free
+        assert_eq!(keys.begin("b", 1, 0), Ok(None));
+        keys.release("b");
+        assert_eq!(keys.begin("b", 1, 0), Ok(None));
+
+        // and a kept one is forgotten after the window
+        assert_eq!(keys.begin("a", 2, 60_000), Ok(None));
+        assert_eq!(keys.list().len(), 0);
+    }
+
+    #[test]
+    fn test_idempotency_check_key() {
+        assert_eq!(check_key(" 5f1c-42 "), Ok("5f1c-42"));
+        assert!(check_key("").is_err());
+        assert!(check_key("two words").is_err());
+        assert!(check_key(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+}
@@ -1,50 +1,685 @@
-#[derive(Debug, PartialEq)]
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::clock::MS_PER_MINUTE;
+use super::error::Error;
+use super::menu::{MenuItem, Modifier};
+
+/// Order-line ids are unique across the whole restaurant, so the same
+/// serving keeps its id wherever it ends up.
+static NEXT_LINE_ID: AtomicU32 = AtomicU32::new(0);
+
+pub fn next_line_id() -> u32 {
+    NEXT_LINE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Makes sure new lines get ids above `line_id`, e.g. after recovery.
+pub fn reserve_line_ids(line_id: u32) {
+    NEXT_LINE_ID.fetch_max(line_id + 1, Ordering::Relaxed);
+}
+
+/// The most servings one line can be ordered with.
+pub const MAX_QUANTITY: u32 = 99;
+
+/// The longest note the kitchen is expected to read.
+const MAX_NOTE_LEN: usize = 200;
+
+/// How a dish is wanted, besides which dish it is.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LineSpec {
+    #[serde(default = "one")]
+    pub quantity: u32,
+    /// The seat or guest the item is for.
+    #[serde(default)]
+    pub seat: Option<u32>,
+    /// Changes to the dish, out of the ones the menu offers for it.
+    #[serde(default)]
+    pub modifiers: Vec<String>,
+    /// Free text for the kitchen, e.g. "birthday, bring a candle".
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+fn one() -> u32 {
+    1
+}
+
+impl Default for LineSpec {
+    fn default() -> LineSpec {
+        LineSpec {
+            quantity: 1,
+            seat: None,
+            modifiers: vec![],
+            note: None,
+        }
+    }
+}
+
+impl LineSpec {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.quantity == 0 || self.quantity > MAX_QUANTITY {
+            return Err(Error::MalformedBody(format!(
+                "quantity must be between 1 and {}",
+                MAX_QUANTITY
+            )));
+        }
+        if self.note.as_ref().is_some_and(|n| n.len() > MAX_NOTE_LEN) {
+            return Err(Error::MalformedBody(format!(
+                "a note takes at most {} bytes",
+                MAX_NOTE_LEN
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Body of `PUT /update/:table_id/:item_id/:line_id`: the parts of a line
+/// to change, everything left out stays as it is.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<u32>,
+    /// A `null` seat takes the line off its seat.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub seat: Option<Option<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modifiers: Option<Vec<String>>,
+    /// A blank note takes the note away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl LineUpdate {
+    fn is_empty(&self) -> bool {
+        *self == LineUpdate::default()
+    }
+}
+
+/// Reads a field that was sent, `null` included, as `Some`. One left out
+/// stays `None` through `#[serde(default)]`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Lifecycle of an order line:
+/// `ordered -> cooking -> ready -> served`, and `cancelled` from any state
+/// before `served`.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemState {
+    Ordered,
+    Cooking,
+    Ready,
+    Served,
+    Cancelled,
+}
+
+impl ItemState {
+    pub fn can_transition(&self, to: ItemState) -> bool {
+        matches!(
+            (self, to),
+            (ItemState::Ordered, ItemState::Cooking)
+                | (ItemState::Cooking, ItemState::Ready)
+                | (ItemState::Ready, ItemState::Served)
+                | (ItemState::Ordered, ItemState::Cancelled)
+                | (ItemState::Cooking, ItemState::Cancelled)
+                | (ItemState::Ready, ItemState::Cancelled)
+        )
+    }
+}
+
+impl fmt::Display for ItemState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ItemState::Ordered => "ordered",
+            ItemState::Cooking => "cooking",
+            ItemState::Ready => "ready",
+            ItemState::Served => "served",
+            ItemState::Cancelled => "cancelled",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StateChange {
+    state: ItemState,
+    at: u64,
+    /// What was changed on the line, for entries that record an update
+    /// rather than a new state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    update: Option<LineUpdate>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Item {
+    line_id: u32,
     item_id: u32,
+    name: String,
+    price: u32,
     table_id: u32,
+    #[serde(default = "one")]
+    quantity: u32,
+    // the guest it was ordered for, if anyone said so
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seat: Option<u32>,
+    // with what each added when it was ordered, which later menu edits
+    // do not change either
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modifiers: Vec<Modifier>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    // minutes
     prepare_time: u32,
+    // milliseconds since the Unix epoch, see `Clock`
+    ordered_at: u64,
+    ready_at: u64,
+    state: ItemState,
+    history: Vec<StateChange>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrepStatus {
+    Pending,
+    Ready,
+}
+
+/// An item as reported to clients at a given moment.
+#[derive(Serialize)]
+pub struct ItemView<'a> {
+    #[serde(flatten)]
+    item: &'a Item,
+    status: PrepStatus,
+    remaining_minutes: u64,
 }
 
 impl Item {
-    pub fn new(p_item_id: u32, p_table_id: u32, p_time: u32) -> Item {
+    /// Name and price are copied from the menu so later menu edits do not
+    /// change what was ordered.
+    pub fn new(
+        p_line_id: u32,
+        p_menu_item: &MenuItem,
+        p_table_id: u32,
+        p_time: u32,
+        p_ordered_at: u64,
+    ) -> Item {
         Item {
-            item_id: p_item_id,
+            line_id: p_line_id,
+            item_id: p_menu_item.item_id,
+            name: p_menu_item.name.clone(),
+            price: p_menu_item.price,
             table_id: p_table_id,
+            quantity: 1,
+            seat: None,
+            modifiers: vec![],
+            note: None,
             prepare_time: p_time,
+            ordered_at: p_ordered_at,
+            ready_at: p_ordered_at + p_time as u64 * MS_PER_MINUTE,
+            state: ItemState::Ordered,
+            history: vec![StateChange {
+                state: ItemState::Ordered,
+                at: p_ordered_at,
+                update: None,
+            }],
+        }
+    }
+
+    /// Orders the line the way `spec` says. Each modifier adds what
+    /// `menu_item` charges and takes for it; the caller checks they are
+    /// allowed.
+    pub fn with_spec(self, menu_item: &MenuItem, spec: LineSpec) -> Item {
+        let modifiers = spec
+            .modifiers
+            .iter()
+            .filter_map(|m| menu_item.modifier(m))
+            .cloned()
+            .collect();
+        self.with(modifiers, spec)
+    }
+
+    /// Orders the line the way `spec` says, with `modifiers` for the ones
+    /// it names.
+    fn with(mut self, modifiers: Vec<Modifier>, spec: LineSpec) -> Item {
+        for modifier in modifiers.iter() {
+            self.price += modifier.price;
+            self.prepare_time += modifier.prep_time;
+            self.ready_at += modifier.prep_time as u64 * MS_PER_MINUTE;
         }
+        self.modifiers = modifiers;
+        self.quantity = spec.quantity;
+        self.seat = spec.seat;
+        self.note = spec.note.filter(|n| !n.trim().is_empty());
+        self
+    }
+
+    pub fn line_id(&self) -> u32 {
+        self.line_id
     }
 
-    #[cfg(test)]
     pub fn id(&self) -> u32 {
         self.item_id
     }
 
-    pub fn print(&self) -> String {
-        let s = format!(
-            "{{\"item_id\": {}, \"table_id\": {}, \"prepare_time\": {}}}",
-            self.item_id, self.table_id, self.prepare_time
-        );
+    pub fn table_id(&self) -> u32 {
+        self.table_id
+    }
+
+    /// Hands the line over to another table, as it is.
+    pub fn move_to(&mut self, table_id: u32) {
+        self.table_id = table_id;
+    }
+
+    pub fn quantity(&self) -> u32 {
+        self.quantity
+    }
+
+    pub fn seat(&self) -> Option<u32> {
+        self.seat
+    }
+
+    pub fn modifiers(&self) -> &[Modifier] {
+        &self.modifiers
+    }
+
+    /// The modifiers `names` asks for. One the line already has stays as it
+    /// was ordered, only new ones have to be on the menu now.
+    fn pick_modifiers(
+        &self,
+        menu_item: &MenuItem,
+        names: &[String],
+    ) -> Result<Vec<Modifier>, Error> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(n, name)| {
+                let modifier = self
+                    .modifiers
+                    .iter()
+                    .find(|m| m.name == *name)
+                    .or_else(|| menu_item.modifier(name));
+                match modifier {
+                    Some(modifier) if !names[..n].contains(name) => Ok(modifier.clone()),
+                    _ => Err(Error::ModifierNotAllowed {
+                        item_id: self.item_id,
+                        modifier: name.clone(),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn price(&self) -> u32 {
+        self.price
+    }
+
+    pub fn prepare_time(&self) -> u32 {
+        self.prepare_time
+    }
 
-        s
+    /// Updates the expected ready time, e.g. from the kitchen queue.
+    pub fn set_ready_at(&mut self, ready_at: u64) {
+        self.ready_at = ready_at;
+    }
+
+    pub fn state(&self) -> ItemState {
+        self.state
+    }
+
+    /// Moves the line to `to`, keeping the change in its history.
+    pub fn transition(&mut self, to: ItemState, now: u64) -> Result<(), Error> {
+        if !self.state.can_transition(to) {
+            return Err(Error::InvalidTransition {
+                line_id: self.line_id,
+                from: self.state,
+                to,
+            });
+        }
+
+        self.state = to;
+        self.history.push(StateChange {
+            state: to,
+            at: now,
+            update: None,
+        });
+        Ok(())
+    }
+
+    /// A copy of the line changed as `update` says, with the change in its
+    /// history. Only lines the kitchen has not started on can be changed.
+    pub fn updated(
+        &self,
+        menu_item: &MenuItem,
+        update: LineUpdate,
+        now: u64,
+    ) -> Result<Item, Error> {
+        if self.state != ItemState::Ordered {
+            return Err(Error::LineClosed {
+                line_id: self.line_id,
+                state: self.state,
+            });
+        }
+        if update.is_empty() {
+            return Err(Error::MalformedBody("nothing to change".to_string()));
+        }
+
+        let modifiers = match &update.modifiers {
+            Some(names) => self.pick_modifiers(menu_item, names)?,
+            None => self.modifiers.clone(),
+        };
+        let spec = LineSpec {
+            quantity: update.quantity.unwrap_or(self.quantity),
+            seat: update.seat.unwrap_or(self.seat),
+            modifiers: modifiers.iter().map(|m| m.name.clone()).collect(),
+            note: update.note.clone().or_else(|| self.note.clone()),
+        };
+        spec.validate()?;
+
+        // take off what the old modifiers added before the new ones go on
+        let mut item = self.clone();
+        for modifier in self.modifiers.iter() {
+            item.price = item.price.saturating_sub(modifier.price);
+            item.prepare_time = item.prepare_time.saturating_sub(modifier.prep_time);
+            item.ready_at = item
+                .ready_at
+                .saturating_sub(modifier.prep_time as u64 * MS_PER_MINUTE);
+        }
+        let mut item = item.with(modifiers, spec);
+        item.history.push(StateChange {
+            state: self.state,
+            at: now,
+            update: Some(update),
+        });
+
+        Ok(item)
+    }
+
+    pub fn status(&self, now: u64) -> PrepStatus {
+        if matches!(self.state, ItemState::Ready | ItemState::Served) || now >= self.ready_at {
+            PrepStatus::Ready
+        } else {
+            PrepStatus::Pending
+        }
+    }
+
+    /// Whole minutes until the item is ready, rounded up.
+    pub fn remaining_minutes(&self, now: u64) -> u64 {
+        let remaining = self.ready_at.saturating_sub(now);
+        remaining.div_ceil(MS_PER_MINUTE)
+    }
+
+    pub fn view(&self, now: u64) -> ItemView<'_> {
+        ItemView {
+            item: self,
+            status: self.status(now),
+            remaining_minutes: self.remaining_minutes(now),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::menu::Modifier;
 
     #[test]
     fn test_item() -> Result<(), String> {
-        let i = Item::new(1, 2, 3);
+        let i = Item::new(0, &MenuItem::sample(1), 2, 3, 1000);
 
         assert_eq!(
             i,
             Item {
+                line_id: 0,
                 item_id: 1,
+                name: "dish 1".to_string(),
+                price: 200,
                 table_id: 2,
+                quantity: 1,
+                seat: None,
+                modifiers: vec![],
+                note: None,
                 prepare_time: 3,
+                ordered_at: 1000,
+                ready_at: 1000 + 3 * MS_PER_MINUTE,
+                state: ItemState::Ordered,
+                history: vec![StateChange {
+                    state: ItemState::Ordered,
+                    at: 1000,
+                    update: None,
+                }],
             }
         );
         Ok(())
     }
+
+    #[test]
+    fn test_item_serialize() {
+        let i = Item::new(4, &MenuItem::sample(1), 2, 3, 0);
+
+        assert_eq!(
+            serde_json::to_string(&i.view(0)).unwrap(),
+            "{\"line_id\":4,\"item_id\":1,\"name\":\"dish 1\",\"price\":200,\"table_id\":2,\
+             \"quantity\":1,\"prepare_time\":3,\"ordered_at\":0,\"ready_at\":180000,\
+             \"state\":\"ordered\",\"history\":[{\"state\":\"ordered\",\"at\":0}],\
+             \"status\":\"pending\",\"remaining_minutes\":3}"
+        );
+    }
+
+    #[test]
+    fn test_item_spec() {
+        let mut menu_item = MenuItem::sample(1);
+        menu_item.modifiers = vec![Modifier {
+            name: "extra spicy".to_string(),
+            price: 50,
+            prep_time: 2,
+        }];
+        let spec = LineSpec {
+            quantity: 2,
+            modifiers: vec!["extra spicy".to_string()],
+            note: Some(" ".to_string()),
+            ..Default::default()
+        };
+        let i = Item::new(0, &menu_item, 2, 3, 0).with_spec(&menu_item, spec);
+
+        assert_eq!(i.quantity(), 2);
+        assert_eq!(i.price(), 250);
+        assert_eq!(i.prepare_time(), 5);
+        assert_eq!(i.ready_at, 5 * MS_PER_MINUTE);
+        assert_eq!(i.modifiers(), &menu_item.modifiers[..]);
+        // a blank note is no note
+        assert_eq!(i.note(), None);
+    }
+
+    #[test]
+    fn test_item_update() {
+        let mut menu_item = MenuItem::sample(1);
+        menu_item.modifiers = vec![
+            Modifier {
+                name: "extra spicy".to_string(),
+                price: 50,
+                prep_time: 2,
+            },
+            Modifier {
+                name: "large".to_string(),
+                price: 100,
+                prep_time: 1,
+            },
+        ];
+        let spec = LineSpec {
+            modifiers: vec!["extra spicy".to_string()],
+            note: Some("no rush".to_string()),
+            ..Default::default()
+        };
+        let i = Item::new(0, &menu_item, 2, 3, 0).with_spec(&menu_item, spec);
+
+        let update = LineUpdate {
+            quantity: Some(3),
+            modifiers: Some(vec!["large".to_string()]),
+            note: Some("".to_string()),
+            ..Default::default()
+        };
+        let u = i.updated(&menu_item, update.clone(), 7).unwrap();
+        assert_eq!(u.quantity(), 3);
+        assert_eq!(u.price(), 300);
+        assert_eq!(u.prepare_time(), 4);
+        assert_eq!(u.modifiers(), &menu_item.modifiers[1..]);
+        assert_eq!(u.note(), None);
+        assert_eq!(u.state(), ItemState::Ordered);
+        assert_eq!(
+            u.history[1],
+            StateChange {
+                state: ItemState::Ordered,
+                at: 7,
+                update: Some(update),
+            }
+        );
+
+        let bad = LineUpdate {
+            quantity: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            i.updated(&menu_item, bad, 7),
+            Err(Error::MalformedBody(_))
+        ));
+        assert!(matches!(
+            i.updated(&menu_item, LineUpdate::default(), 7),
+            Err(Error::MalformedBody(_))
+        ));
+
+        let mut cooking = i.clone();
+        cooking.transition(ItemState::Cooking, 8).unwrap();
+        let seat = LineUpdate {
+            seat: Some(Some(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            cooking.updated(&menu_item, seat, 9).err(),
+            Some(Error::LineClosed {
+                line_id: 0,
+                state: ItemState::Cooking
+            })
+        );
+    }
+
+    #[test]
+    fn test_item_update_after_menu_change() {
+        let spicy = Modifier {
+            name: "extra spicy".to_string(),
+            price: 50,
+            prep_time: 2,
+        };
+        let mut menu_item = MenuItem::sample(1);
+        menu_item.modifiers = vec![spicy.clone()];
+        let spec = LineSpec {
+            seat: Some(2),
+            modifiers: vec![spicy.name.clone()],
+            ..Default::default()
+        };
+        let i = Item::new(0, &menu_item, 2, 3, 0).with_spec(&menu_item, spec);
+
+        // what the modifier added comes off, whatever it costs now
+        menu_item.modifiers[0].price = 80;
+        let drop = LineUpdate {
+            modifiers: Some(vec![]),
+            ..Default::default()
+        };
+        let u = i.updated(&menu_item, drop.clone(), 1).unwrap();
+        assert_eq!((u.price(), u.prepare_time()), (200, 3));
+
+        // and the line can still be changed once it is off the menu
+        menu_item.modifiers.clear();
+        let keep = LineUpdate {
+            quantity: Some(2),
+            modifiers: Some(vec![spicy.name.clone()]),
+            ..Default::default()
+        };
+        let u = i.updated(&menu_item, keep, 1).unwrap();
+        assert_eq!((u.price(), u.modifiers()), (250, &[spicy][..]));
+        assert_eq!(i.updated(&menu_item, drop, 1).unwrap().price(), 200);
+
+        // a null seat takes the line off its seat, a missing one leaves it
+        let update: LineUpdate = serde_json::from_str(r#"{"seat": null}"#).unwrap();
+        assert_eq!(update.seat, Some(None));
+        assert_eq!(
+            i.updated(&menu_item, update.clone(), 1).unwrap().seat(),
+            None
+        );
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(serde_json::from_str::<LineUpdate>(&json).unwrap(), update);
+        let update: LineUpdate = serde_json::from_str(r#"{"quantity": 2}"#).unwrap();
+        assert_eq!(i.updated(&menu_item, update, 1).unwrap().seat(), Some(2));
+    }
+
+    #[test]
+    fn test_item_ready_time() {
+        let clock = ManualClock::new(5_000);
+        let i = Item::new(0, &MenuItem::sample(1), 2, 10, clock.now_ms());
+
+        assert_eq!(i.status(clock.now_ms()), PrepStatus::Pending);
+        assert_eq!(i.remaining_minutes(clock.now_ms()), 10);
+
+        clock.advance_minutes(4);
+        assert_eq!(i.remaining_minutes(clock.now_ms()), 6);
+
+        clock.advance_minutes(6);
+        assert_eq!(i.status(clock.now_ms()), PrepStatus::Ready);
+        assert_eq!(i.remaining_minutes(clock.now_ms()), 0);
+    }
+
+    #[test]
+    fn test_item_transitions() {
+        let mut i = Item::new(0, &MenuItem::sample(1), 2, 10, 0);
+
+        i.transition(ItemState::Cooking, 1).unwrap();
+        assert_eq!(
+            i.transition(ItemState::Served, 2),
+            Err(Error::InvalidTransition {
+                line_id: 0,
+                from: ItemState::Cooking,
+                to: ItemState::Served
+            })
+        );
+        i.transition(ItemState::Ready, 3).unwrap();
+        assert_eq!(i.status(3), PrepStatus::Ready);
+        i.transition(ItemState::Served, 4).unwrap();
+
+        assert!(i.transition(ItemState::Cancelled, 5).is_err());
+        assert_eq!(i.state(), ItemState::Served);
+        assert_eq!(i.history.len(), 4);
+        assert_eq!(
+            i.history[3],
+            StateChange {
+                state: ItemState::Served,
+                at: 4,
+                update: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_next_line_id_unique() {
+        let a = next_line_id();
+        let b = next_line_id();
+        assert_ne!(a, b);
+    }
 }
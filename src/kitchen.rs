@@ -0,0 +1,491 @@
+//! A kitchen with a fixed number of cooks working through one shared queue
+//! of tickets from every table.
+//!
+//! `Kitchen` itself only does bookkeeping. `run` drives it from a tokio
+//! task and applies what happened to the tables. To stay deadlock free a
+//! table lock may be held while taking the kitchen lock, never the other
+//! way round.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::clock::MS_PER_MINUTE;
+use super::error::Error;
+use super::item::{Item, ItemState};
+use super::restaurant::{undo_on_error, Restaurant};
+use super::storage::Op;
+use super::table::Table;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ticket {
+    pub table_id: u32,
+    pub item_id: u32,
+    pub line_id: u32,
+    // minutes
+    pub prepare_time: u32,
+    pub quantity: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl Ticket {
+    /// What the cooks need to know to make `item`.
+    pub fn new(item: &Item) -> Ticket {
+        Ticket {
+            table_id: item.table_id(),
+            item_id: item.id(),
+            line_id: item.line_id(),
+            prepare_time: item.prepare_time(),
+            quantity: item.quantity(),
+            modifiers: item.modifiers().iter().map(|m| m.name.clone()).collect(),
+            note: item.note().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Cooking {
+    ticket: Ticket,
+    started_at: u64,
+    done_at: u64,
+}
+
+/// Something the tables need to hear about after a kitchen step.
+#[derive(Debug, PartialEq)]
+pub enum KitchenEvent {
+    Started(Ticket),
+    Finished(Ticket),
+    Estimate(Ticket, u64),
+}
+
+impl KitchenEvent {
+    pub fn ticket(&self) -> &Ticket {
+        match self {
+            KitchenEvent::Started(t) | KitchenEvent::Finished(t) | KitchenEvent::Estimate(t, _) => {
+                t
+            }
+        }
+    }
+
+    fn ticket_mut(&mut self) -> &mut Ticket {
+        match self {
+            KitchenEvent::Started(t) | KitchenEvent::Finished(t) | KitchenEvent::Estimate(t, _) => {
+                t
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct QueuedTicket {
+    position: usize,
+    #[serde(flatten)]
+    ticket: Ticket,
+    estimated_start: u64,
+    estimated_ready: u64,
+}
+
+#[derive(Serialize)]
+pub struct KitchenStatus {
+    cooks: usize,
+    busy_cooks: usize,
+    utilisation: f64,
+    queue_length: usize,
+    estimated_wait_minutes: u64,
+    cooking: Vec<Cooking>,
+}
+
+pub struct Kitchen {
+    cooks: Vec<Option<Cooking>>,
+    queue: VecDeque<Ticket>,
+    // events the tables could not take yet, sent again by the next step
+    unsaved: Vec<KitchenEvent>,
+}
+
+impl Kitchen {
+    pub fn new(cooks: usize) -> Kitchen {
+        Kitchen {
+            cooks: vec![None; cooks.max(1)],
+            queue: VecDeque::new(),
+            unsaved: vec![],
+        }
+    }
+
+    /// Queues a ticket and returns when it is expected to be ready.
+    pub fn enqueue(&mut self, ticket: Ticket, now: u64) -> u64 {
+        let line_id = ticket.line_id;
+        self.queue.push_back(ticket);
+
+        self.schedule(now)
+            .into_iter()
+            .find(|(t, _, _)| t.line_id == line_id)
+            .map(|(_, _, ready)| ready)
+            .unwrap_or(now)
+    }
+
+    /// Drops a ticket, whether it is still queued or already being cooked.
+    pub fn remove(&mut self, line_id: u32) -> bool {
+        self.unsaved.retain(|e| e.ticket().line_id != line_id);
+        if let Some(pos) = self.queue.iter().position(|t| t.line_id == line_id) {
+            self.queue.remove(pos);
+            return true;
+        }
+        for cook in self.cooks.iter_mut() {
+            if cook.as_ref().map(|c| c.ticket.line_id) == Some(line_id) {
+                *cook = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Swaps in a changed ticket for a line still waiting for a cook,
+    /// keeping its place, and returns when it is expected to be ready.
+    /// `None` if a cook has picked it up already.
+    pub fn update(&mut self, ticket: Ticket, now: u64) -> Option<u64> {
+        let line_id = ticket.line_id;
+        let queued = self.queue.iter_mut().find(|t| t.line_id == line_id)?;
+        *queued = ticket;
+
+        self.schedule(now)
+            .into_iter()
+            .find(|(t, _, _)| t.line_id == line_id)
+            .map(|(_, _, ready)| ready)
+    }
+
+    /// Points the ticket for `line_id` at the table the line moved to.
+    pub fn retarget(&mut self, line_id: u32, table_id: u32) {
+        let cooking = self.cooks.iter_mut().flatten().map(|c| &mut c.ticket);
+        let unsaved = self.unsaved.iter_mut().map(KitchenEvent::ticket_mut);
+        for ticket in self.queue.iter_mut().chain(cooking).chain(unsaved) {
+            if ticket.line_id == line_id {
+                ticket.table_id = table_id;
+            }
+        }
+    }
+
+    /// Finishes whatever is done by `now`, hands queued tickets to free
+    /// cooks and re-estimates the rest of the queue. Events handed back by
+    /// `retry` come first.
+    pub fn step(&mut self, now: u64) -> Vec<KitchenEvent> {
+        let mut events = std::mem::take(&mut self.unsaved);
+
+        for cook in self.cooks.iter_mut() {
+            if cook.as_ref().map(|c| c.done_at <= now).unwrap_or(false) {
+                let done = cook.take().unwrap();
+                events.push(KitchenEvent::Finished(done.ticket));
+            }
+            if cook.is_none() {
+                if let Some(ticket) = self.queue.pop_front() {
+                    let done_at = now + ticket.prepare_time as u64 * MS_PER_MINUTE;
+                    events.push(KitchenEvent::Started(ticket.clone()));
+                    events.push(KitchenEvent::Estimate(ticket.clone(), done_at));
+                    *cook = Some(Cooking {
+                        ticket,
+                        started_at: now,
+                        done_at,
+                    });
+                }
+            }
+        }
+
+        for (ticket, _, ready) in self.schedule(now) {
+            events.push(KitchenEvent::Estimate(ticket, ready));
+        }
+
+        events
+    }
+
+    /// Hands back events the tables could not take, in the order they came.
+    pub fn retry(&mut self, events: Vec<KitchenEvent>) {
+        self.unsaved.extend(events);
+    }
+
+    /// Plays the queue forward: each ticket goes to the cook that frees up
+    /// first. Returns `(ticket, start, ready)` in queue order.
+    fn schedule(&self, now: u64) -> Vec<(Ticket, u64, u64)> {
+        let mut free_at = self.cook_free_times(now);
+
+        self.queue
+            .iter()
+            .map(|ticket| {
+                let (idx, start) = earliest(&free_at);
+                let ready = start + ticket.prepare_time as u64 * MS_PER_MINUTE;
+                free_at[idx] = ready;
+                (ticket.clone(), start, ready)
+            })
+            .collect()
+    }
+
+    fn cook_free_times(&self, now: u64) -> Vec<u64> {
+        self.cooks
+            .iter()
+            .map(|c| c.as_ref().map(|c| c.done_at.max(now)).unwrap_or(now))
+            .collect()
+    }
+
+    pub fn queue(&self, now: u64) -> Vec<QueuedTicket> {
+        self.schedule(now)
+            .into_iter()
+            .enumerate()
+            .map(|(position, (ticket, start, ready))| QueuedTicket {
+                position,
+                ticket,
+                estimated_start: start,
+                estimated_ready: ready,
+            })
+            .collect()
+    }
+
+    /// Minutes until a cook could start on an order placed now.
+    pub fn estimated_wait(&self, now: u64) -> u64 {
+        let mut free_at = self.cook_free_times(now);
+        for (_, _, ready) in self.schedule(now) {
+            let (idx, _) = earliest(&free_at);
+            free_at[idx] = ready;
+        }
+        let (_, start) = earliest(&free_at);
+
+        (start - now).div_ceil(MS_PER_MINUTE)
+    }
+
+    pub fn status(&self, now: u64) -> KitchenStatus {
+        let cooking = self
+            .cooks
+            .iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<Cooking>>();
+
+        KitchenStatus {
+            cooks: self.cooks.len(),
+            busy_cooks: cooking.len(),
+            utilisation: cooking.len() as f64 / self.cooks.len() as f64,
+            queue_length: self.queue.len(),
+            estimated_wait_minutes: self.estimated_wait(now),
+            cooking,
+        }
+    }
+}
+
+fn earliest(free_at: &[u64]) -> (usize, u64) {
+    free_at
+        .iter()
+        .copied()
+        .enumerate()
+        .min_by_key(|(_, t)| *t)
+        .unwrap()
+}
+
+/// Applies one kitchen step to the tables. A change that cannot be logged
+/// is undone and handed back to the kitchen, so the next tick tries again.
+pub fn tick(restaurant: &Restaurant) {
+    let now = restaurant.now();
+    // the kitchen lock is released before any table is locked
+    let events = restaurant.kitchen().lock().unwrap().step(now);
+    let mut moved = vec![];
+    let mut retry: Vec<KitchenEvent> = vec![];
+
+    for event in events {
+        let ticket = event.ticket();
+        // a line still waiting on an earlier change keeps its order
+        if retry.iter().any(|e| e.ticket().line_id == ticket.line_id) {
+            retry.push(event);
+            continue;
+        }
+        let table = match restaurant.get_table(ticket.table_id) {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let mut table = table.lock().unwrap();
+
+        let state = match &event {
+            KitchenEvent::Started(_) => ItemState::Cooking,
+            KitchenEvent::Finished(_) => ItemState::Ready,
+            KitchenEvent::Estimate(t, ready_at) => {
+                let _ = table.set_ready_at(t.line_id, *ready_at);
+                continue;
+            }
+        };
+        // a ticket may have been cancelled since the step, then the
+        // transition is refused and there is nothing to do
+        match apply(restaurant, &mut table, event.ticket(), state, now) {
+            Ok(()) => {}
+            // or its line moved to another table
+            Err(Error::ItemNotFound(_)) => moved.push((event, state)),
+            Err(e @ Error::Storage(_)) => {
+                println!("kitchen: {}", e);
+                retry.push(event);
+            }
+            Err(_) => {}
+        }
+    }
+
+    // one table at a time, so this cannot deadlock with a transfer
+    for (event, state) in moved {
+        for table in restaurant.tables() {
+            let mut table = table.lock().unwrap();
+            match apply(restaurant, &mut table, event.ticket(), state, now) {
+                Err(Error::ItemNotFound(_)) => continue,
+                Err(e @ Error::Storage(_)) => {
+                    println!("kitchen: {}", e);
+                    retry.push(event);
+                }
+                _ => {}
+            }
+            break;
+        }
+    }
+
+    if !retry.is_empty() {
+        restaurant.kitchen().lock().unwrap().retry(retry);
+    }
+}
+
+/// Moves the ticket's line on `table` to `state` and logs it, leaving the
+/// table as it was if either fails.
+fn apply(
+    restaurant: &Restaurant,
+    table: &mut Table,
+    t: &Ticket,
+    state: ItemState,
+    now: u64,
+) -> Result<(), Error> {
+    undo_on_error(table, |table| {
+        let item = table.set_state(t.item_id, t.line_id, state, now)?;
+        restaurant.log(Op::set_state(item, now))
+    })
+}
+
+/// Drives the kitchen forever, one step per `interval`.
+pub async fn run(restaurant: Restaurant, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        tick(&restaurant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket(line_id: u32, prepare_time: u32) -> Ticket {
+        Ticket {
+            table_id: 0,
+            item_id: 1,
+            line_id,
+            prepare_time,
+            quantity: 1,
+            modifiers: vec![],
+            note: None,
+        }
+    }
+
+    const M: u64 = MS_PER_MINUTE;
+
+    #[test]
+    fn test_kitchen_estimates_from_queue_position() {
+        let mut k = Kitchen::new(2);
+
+        assert_eq!(k.enqueue(ticket(0, 10), 0), 10 * M);
+        assert_eq!(k.enqueue(ticket(1, 5), 0), 5 * M);
+        // both cooks are taken, the third waits for the 5 minute dish
+        assert_eq!(k.enqueue(ticket(2, 4), 0), 9 * M);
+        assert_eq!(k.estimated_wait(0), 9);
+    }
+
+    #[test]
+    fn test_kitchen_retarget() {
+        let mut k = Kitchen::new(1);
+        k.enqueue(ticket(0, 3), 0);
+        k.enqueue(ticket(1, 2), 0);
+        k.step(0);
+
+        k.retarget(0, 5);
+        k.retarget(1, 6);
+        let events = k.step(3 * M);
+        let moved = |line_id, table_id| Ticket {
+            table_id,
+            ..ticket(line_id, 3 - line_id)
+        };
+        assert_eq!(events[0], KitchenEvent::Finished(moved(0, 5)));
+        assert_eq!(events[1], KitchenEvent::Started(moved(1, 6)));
+    }
+
+    #[test]
+    fn test_kitchen_update() {
+        let mut k = Kitchen::new(1);
+        k.enqueue(ticket(0, 3), 0);
+        k.enqueue(ticket(1, 2), 0);
+        k.enqueue(ticket(2, 2), 0);
+        k.step(0);
+
+        // a longer dish pushes back what comes after it
+        assert_eq!(k.update(ticket(1, 4), 0), Some(7 * M));
+        assert_eq!(k.queue(0)[1].estimated_ready, 9 * M);
+        assert_eq!(k.update(ticket(0, 1), 0), None);
+    }
+
+    #[test]
+    fn test_kitchen_step() {
+        let mut k = Kitchen::new(1);
+        k.enqueue(ticket(0, 3), 0);
+        k.enqueue(ticket(1, 2), 0);
+
+        let events = k.step(0);
+        assert!(events.contains(&KitchenEvent::Started(ticket(0, 3))));
+        assert!(events.contains(&KitchenEvent::Estimate(ticket(1, 2), 5 * M)));
+        assert_eq!(k.status(0).busy_cooks, 1);
+        assert_eq!(k.status(0).queue_length, 1);
+
+        let events = k.step(3 * M);
+        assert_eq!(events[0], KitchenEvent::Finished(ticket(0, 3)));
+        assert_eq!(events[1], KitchenEvent::Started(ticket(1, 2)));
+
+        k.step(5 * M);
+        let status = k.status(5 * M);
+        assert_eq!(status.busy_cooks, 0);
+        assert_eq!(status.utilisation, 0.0);
+        assert_eq!(status.estimated_wait_minutes, 0);
+    }
+
+    #[test]
+    fn test_kitchen_retry() {
+        let mut k = Kitchen::new(1);
+        k.enqueue(ticket(0, 3), 0);
+        k.step(0);
+        let events = k.step(3 * M);
+        assert_eq!(events, vec![KitchenEvent::Finished(ticket(0, 3))]);
+
+        k.retry(events);
+        k.retarget(0, 4);
+        let moved = Ticket {
+            table_id: 4,
+            ..ticket(0, 3)
+        };
+        assert_eq!(k.step(4 * M), vec![KitchenEvent::Finished(moved)]);
+        assert!(k.step(5 * M).is_empty());
+
+        k.retry(vec![KitchenEvent::Finished(ticket(0, 3))]);
+        k.remove(0);
+        assert!(k.step(6 * M).is_empty());
+    }
+
+    #[test]
+    fn test_kitchen_remove() {
+        let mut k = Kitchen::new(1);
+        k.enqueue(ticket(0, 3), 0);
+        k.enqueue(ticket(1, 2), 0);
+        k.step(0);
+
+        assert!(k.remove(0));
+        assert!(k.remove(1));
+        assert!(!k.remove(2));
+        assert_eq!(k.status(0).busy_cooks, 0);
+        assert!(k.queue(0).is_empty());
+    }
+}
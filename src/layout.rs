@@ -0,0 +1,187 @@
+//! The floor plan: which tables there are, how many guests each seats and
+//! where they stand.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use super::error::Error;
+
+/// One table on the floor plan.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableConfig {
+    pub table_id: u32,
+    pub name: String,
+    /// Number of guests the table seats.
+    pub capacity: u32,
+    #[serde(default)]
+    pub section: String,
+    /// Tables in the same group can be pushed together for a larger party.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// A disabled table keeps its orders but takes no new ones.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl TableConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error::MalformedBody("table needs a name".to_string()));
+        }
+        if self.capacity == 0 {
+            return Err(Error::MalformedBody(
+                "capacity must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn sample(table_id: u32) -> TableConfig {
+        TableConfig {
+            table_id,
+            name: format!("table {}", table_id),
+            capacity: 4,
+            section: "main".to_string(),
+            group: Some("main".to_string()),
+            disabled: false,
+        }
+    }
+}
+
+/// Fields of a table that can be changed at runtime.
+#[derive(Debug, Default, Deserialize)]
+pub struct TableUpdate {
+    /// Renumbers the table, which is only allowed while it has no orders.
+    pub table_id: Option<u32>,
+    pub name: Option<String>,
+    pub capacity: Option<u32>,
+    pub section: Option<String>,
+    /// An empty group takes the table out of its group.
+    pub group: Option<String>,
+    pub disabled: Option<bool>,
+}
+
+impl TableUpdate {
+    /// `config` with the update applied.
+    pub fn apply(self, config: &TableConfig) -> Result<TableConfig, Error> {
+        let mut config = config.clone();
+
+        if let Some(table_id) = self.table_id {
+            config.table_id = table_id;
+        }
+        if let Some(name) = self.name {
+            config.name = name;
+        }
+        if let Some(capacity) = self.capacity {
+            config.capacity = capacity;
+        }
+        if let Some(section) = self.section {
+            config.section = section;
+        }
+        if let Some(group) = self.group {
+            config.group = Some(group).filter(|g| !g.is_empty());
+        }
+        if let Some(disabled) = self.disabled {
+            config.disabled = disabled;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+/// Every table of the restaurant, in id order.
+#[derive(Debug, Default)]
+pub struct Layout {
+    tables: Vec<TableConfig>,
+}
+
+impl Layout {
+    pub fn new(mut tables: Vec<TableConfig>) -> Result<Layout, Error> {
+        let mut ids = BTreeSet::new();
+        for table in tables.iter() {
+            table.validate()?;
+            if !ids.insert(table.table_id) {
+                return Err(Error::TableExists(table.table_id));
+            }
+        }
+        tables.sort_by_key(|t| t.table_id);
+
+        Ok(Layout { tables })
+    }
+
+    /// Reads a layout from a JSON file holding a list of tables.
+    pub fn load(path: &Path) -> Result<Layout, Error> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        let tables: Vec<TableConfig> = serde_json::from_str(&data)
+            .map_err(|e| Error::Config(format!("bad layout {}: {}", path.display(), e)))?;
+
+        Layout::new(tables)
+    }
+
+    pub fn tables(&self) -> &[TableConfig] {
+        &self.tables
+    }
+
+    /// Tables numbered from 0 to `n - 1`.
+    #[cfg(test)]
+    pub fn sample(n: u32) -> Layout {
+        Layout::new((0..n).map(TableConfig::sample).collect()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_new() {
+        let layout = Layout::new(vec![TableConfig::sample(3), TableConfig::sample(1)]).unwrap();
+        let ids = layout
+            .tables()
+            .iter()
+            .map(|t| t.table_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3]);
+
+        assert_eq!(
+            Layout::new(vec![TableConfig::sample(1), TableConfig::sample(1)]).err(),
+            Some(Error::TableExists(1))
+        );
+
+        let mut table = TableConfig::sample(1);
+        table.capacity = 0;
+        assert!(matches!(
+            Layout::new(vec![table]),
+            Err(Error::MalformedBody(_))
+        ));
+    }
+
+    #[test]
+    fn test_table_update() {
+        let mut table = TableConfig::sample(1);
+        table.group = Some("window".to_string());
+
+        let update = TableUpdate {
+            table_id: Some(7),
+            capacity: Some(6),
+            group: Some(String::new()),
+            ..Default::default()
+        };
+        let updated = update.apply(&table).unwrap();
+        assert_eq!(updated.table_id, 7);
+        assert_eq!(updated.capacity, 6);
+        assert_eq!(updated.group, None);
+        assert_eq!(updated.name, table.name);
+
+        let update = TableUpdate {
+            name: Some(" ".to_string()),
+            ..Default::default()
+        };
+        assert!(update.apply(&table).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 //! A Simple Restaurant API Server
 //!
 //! This server will create a TCP listener, accept connections in a loop, and
-//! write back everything that's read off of each TCP connection.
+//! answer the HTTP/1.1 requests sent over each TCP connection.
 //!
 //! Because the Tokio runtime uses a thread pool, each TCP connection is
 //! processed concurrently with all other TCP connections across multiple
@@ -13,29 +13,63 @@
 //!
 //! and in another terminal you can run:
 //!
-//!    telnet 127.0.0.1 8080
+//!    curl -X POST -d '{"party_size": 2}' http://127.0.0.1:8080/seat/1
+//!    curl -X POST http://127.0.0.1:8080/add/1/3
+//!    curl http://127.0.0.1:8080/query/1
 //!
-//! Each line you type in to the `connect` terminal should be echo'd back to
-//! you! If you open up multiple terminals running the `connect` example you
-//! should be able to see them all make progress simultaneously.
+//! The number of cooks is read from `RESTAURANT_COOKS` (4 by default).
+//! Orders are only kept across restarts with `RESTAURANT_STORAGE` set to `wal`
+//! or `sqlite`.
+//!
+//! If you run several clients at once you should be able to see them all make
+//! progress simultaneously.
 
 #![warn(rust_2018_idioms)]
 
 use std::env;
-use std::error::Error;
-use std::str;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 mod api;
+mod bill;
+mod clock;
+mod config;
+mod connection;
+mod error;
+mod events;
+mod http;
+mod idempotency;
 mod item;
+mod kitchen;
+mod layout;
+mod menu;
+mod reservation;
 mod restaurant;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
 mod table;
-
+mod waitlist;
+mod wal;
+mod websocket;
+
+use bill::Charges;
+use config::Config;
+use connection::Upgrade;
+use error::{parse_id, Error};
+use events::Topic;
+use http::{Request, Response};
+use idempotency::{IdempotencyKeys, KeptResponse};
+use item::LineSpec;
+use kitchen::Kitchen;
+use layout::Layout;
+use menu::Menu;
+use reservation::Reservations;
 use restaurant::Restaurant;
+use storage::StorageKind;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Allow passing an address to listen on as the first argument of this
     // program, but otherwise we'll just set up our TCP listener on
     // 127.0.0.1:8080 for connections.
@@ -43,18 +77,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .nth(1)
         .unwrap_or_else(|| "127.0.0.1:8080".to_string());
 
+    // The menu is read from the second argument, or `menu.json` by default.
+    let menu_path = env::args()
+        .nth(2)
+        .unwrap_or_else(|| "menu.json".to_string());
+    let menu = Menu::load(&menu_path)?;
+    let config = Config::from_env()?;
+
     // Next up we create a TCP listener which will listen for incoming
     // connections. This TCP listener is bound to the address we determined
     // above and must be associated with an event loop.
     let listener = TcpListener::bind(&addr).await?;
     println!("Listening on: {}", addr);
 
-    // create 200 tables for the restaurant
-    let restaurant = Restaurant::new(200);
+    // the tables come from the layout file, unless the storage below
+    // brings back the layout of the last run
+    let layout = Layout::load(&config.layout)?;
+    let mut restaurant = Restaurant::new(layout, menu)
+        .with_kitchen(Kitchen::new(config.cooks))
+        .with_reservations(Reservations::new(config.no_show_mins))
+        .with_idempotency(IdempotencyKeys::new(config.idempotency_secs))
+        .with_charges(Charges {
+            service_bps: config.service_bps,
+            tax_bps: config.tax_bps,
+        });
+
+    // bring back the orders from the last run, then compact the log
+    if config.storage != StorageKind::Memory {
+        let (storage, recovery) = storage::open(config.storage, &config.data_dir)?;
+        println!(
+            "Recovered {} lines and {} log records from {} storage in {}",
+            recovery.state.items.len(),
+            recovery.ops.len(),
+            config.storage,
+            config.data_dir.display()
+        );
+        restaurant = restaurant.with_storage(storage);
+        storage::restore(&restaurant, recovery)?;
+        restaurant.snapshot()?;
+
+        tokio::spawn(storage::run(
+            restaurant.clone(),
+            Duration::from_secs(config.snapshot_secs),
+        ));
+    }
+
+    // tables booked by parties that do not turn up are given back
+    tokio::spawn(reservation::run(
+        restaurant.clone(),
+        Duration::from_secs(30),
+    ));
+
+    // the kitchen works through its queue in the background
+    tokio::spawn(kitchen::run(
+        restaurant.clone(),
+        Duration::from_millis(config.kitchen_tick_ms),
+    ));
 
     loop {
         // Asynchronously wait for an inbound socket.
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, _) = listener.accept().await?;
 
         // And this is where much of the magic of this server happens. We
         // crucially want all clients to make progress concurrently, rather than
@@ -67,25 +149,19 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let restaurant = restaurant.clone();
 
         tokio::spawn(async move {
-            let mut buf = vec![0; 1024];
-
-            // In a loop, read data from the socket and write the data back.
-            loop {
-                let n = socket
-                    .read(&mut buf)
-                    .await
-                    .expect("failed to read data from socket");
-
-                if n == 0 {
-                    return;
-                }
-
-                let response = request_parser(&mut buf[0..n], restaurant.clone());
-
-                socket
-                    .write_all(response.as_bytes())
-                    .await
-                    .expect("failed to write data to socket");
+            let result = connection::handle_connection(socket, |req| {
+                request_parser(req, restaurant.clone())
+            })
+            .await;
+            // a subscriber keeps the connection from here on
+            let result = match result {
+                Ok(Some(upgrade)) => serve_upgrade(upgrade, restaurant).await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                println!("connection error: {}", e);
             }
         });
     }
@@ -105,6 +181,23 @@ enum RequestApi {
     Add,
     Remove,
     Query,
+    Menu,
+    Update,
+    Status,
+    History,
+    Kitchen,
+    Batch,
+    Transfer,
+    Merge,
+    Split,
+    Checkout,
+    Bill,
+    Tables,
+    Seat,
+    Floor,
+    Reservations,
+    Waitlist,
+    Subscribe,
     Unknown,
 }
 
@@ -137,85 +230,434 @@ fn parse_api(s: &str) -> (RequestApi, Vec<&str>) {
         "add" => (RequestApi::Add, api_param),
         "remove" => (RequestApi::Remove, api_param),
         "query" => (RequestApi::Query, api_param),
+        "menu" => (RequestApi::Menu, api_param),
+        "update" => (RequestApi::Update, api_param),
+        "status" => (RequestApi::Status, api_param),
+        "history" => (RequestApi::History, api_param),
+        "kitchen" => (RequestApi::Kitchen, api_param),
+        "batch" => (RequestApi::Batch, api_param),
+        "transfer" => (RequestApi::Transfer, api_param),
+        "merge" => (RequestApi::Merge, api_param),
+        "split" => (RequestApi::Split, api_param),
+        "checkout" => (RequestApi::Checkout, api_param),
+        "bill" => (RequestApi::Bill, api_param),
+        "tables" => (RequestApi::Tables, api_param),
+        "seat" => (RequestApi::Seat, api_param),
+        "floor" => (RequestApi::Floor, api_param),
+        "reservations" => (RequestApi::Reservations, api_param),
+        "waitlist" => (RequestApi::Waitlist, api_param),
+        "subscribe" => (RequestApi::Subscribe, api_param),
         _ => (RequestApi::Unknown, vec![]),
     }
 }
 
-fn request_parser(req: &mut [u8], restaurant: Restaurant) -> String {
-    let req_str = str::from_utf8(req).unwrap();
-    println!("Request: {}", req_str);
+/// Serves a connection switched over to WebSocket, see `events::serve`.
+async fn serve_upgrade<S>(upgrade: Upgrade<S>, restaurant: Restaurant) -> std::io::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    println!(
+        "Upgrade: {} {}",
+        upgrade.request.method, upgrade.request.path
+    );
+
+    match subscription(&upgrade.request, &restaurant) {
+        Ok(topic) => events::serve(upgrade, topic, restaurant).await,
+        Err(e) => upgrade.refuse(e.to_response()).await,
+    }
+}
 
-    let split = req_str.split(' ');
+/// What a client asks to be told about, from `/subscribe`,
+/// `/subscribe/:table_id` or `/subscribe/:table_id,:table_id,...`.
+fn subscription(req: &Request, restaurant: &Restaurant) -> Result<Topic, Error> {
+    let topic = match parse_api(&req.path) {
+        (RequestApi::Subscribe, api_param) => Topic::parse(&api_param)?,
+        _ => return Err(Error::UnknownRoute),
+    };
+    if let Topic::Tables(ids) = &topic {
+        for &tid in ids {
+            restaurant.get_table(tid)?;
+        }
+    }
+    Ok(topic)
+}
 
-    let req_vec = split.collect::<Vec<&str>>();
+fn request_parser(req: &Request, restaurant: Restaurant) -> Response {
+    println!("Request: {} {}", req.method, req.path);
 
-    if req_vec.len() < 2 {
-        return "some error".to_string();
+    let result = match idempotency_key(req) {
+        Ok(Some(key)) => route_once(key, req, restaurant),
+        Ok(None) => route(req, restaurant),
+        Err(e) => Err(e),
     };
+    match result {
+        Ok(response) => response,
+        Err(e) => e.to_response(),
+    }
+}
+
+/// The key a change was sent with so it is safe to retry, from
+/// `Idempotency-Key`. Reads are safe to retry anyway and ignore it.
+fn idempotency_key(req: &Request) -> Result<Option<&str>, Error> {
+    if parse_method(&req.method) == RequestMethod::Get {
+        return Ok(None);
+    }
+    req.header("idempotency-key")
+        .map(idempotency::check_key)
+        .transpose()
+}
+
+/// Serves a change at most once per idempotency key, answering a retry
+/// with the response the change got the first time.
+fn route_once(key: &str, req: &Request, restaurant: Restaurant) -> Result<Response, Error> {
+    let fingerprint = idempotency::fingerprint(req);
+    let now = restaurant.now();
+    let kept = restaurant
+        .idempotency()
+        .lock()
+        .unwrap()
+        .begin(key, fingerprint, now)?;
+    if let Some(response) = kept {
+        return Ok(response);
+    }
+
+    let result = route(req, restaurant.clone());
+    match &result {
+        Ok(response) => {
+            // the change is made, but the client must not count on the key
+            // surviving a restart
+            let kept = KeptResponse::new(key, fingerprint, now, response);
+            restaurant.keep_response(kept)?;
+        }
+        Err(_) => restaurant.idempotency().lock().unwrap().release(key),
+    }
+    result
+}
+
+/// The table version a change was made against, from `If-Match`. `*`
+/// matches any version, as does leaving the header out.
+fn expected_version(req: &Request) -> Result<Option<u64>, Error> {
+    match req.header("if-match").map(str::trim) {
+        None | Some("*") => Ok(None),
+        Some(value) => http::parse_etag(value)
+            .map(Some)
+            .ok_or_else(|| Error::MalformedRequest(format!("'{}' is not a table version", value))),
+    }
+}
 
-    let method = parse_method(req_vec[0]);
-    let (api, api_param) = parse_api(req_vec[1]);
-
-    match method {
-        RequestMethod::Get => match api {
-            RequestApi::Query => match api_param.len() {
-                1 => {
-                    // TODO: error handling for not a number
-                    let tid: u32 = api_param[0].parse::<u32>().unwrap();
-
-                    // `/query/:table_id`
-                    return api::query_all(tid, restaurant);
-                }
-                2 => {
-                    let tid: u32 = api_param[0].parse::<u32>().unwrap();
-                    let iid: u32 = api_param[1].parse::<u32>().unwrap();
-
-                    // `/query/:table_id/:item_id`
-                    return api::query_one(tid, iid, restaurant);
-                }
-                _ => return "wrong api".to_string(),
-            },
-            _ => {}
+fn route(req: &Request, restaurant: Restaurant) -> Result<Response, Error> {
+    let method = parse_method(&req.method);
+    let (api, api_param) = parse_api(&req.path);
+
+    if method == RequestMethod::Unknown {
+        return Err(Error::MethodNotAllowed);
+    }
+    let restaurant = restaurant.expecting(expected_version(req)?);
+
+    match (api, method) {
+        (RequestApi::Query, RequestMethod::Get) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+
+                // `/query/:table_id`
+                api::query_all(tid, restaurant)
+            }
+            2 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+
+                // `/query/:table_id/:item_id`
+                api::query_one(tid, iid, restaurant)
+            }
+            3 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+                let line_id = parse_id(api_param[2])?;
+
+                // `/query/:table_id/:item_id/:line_id`
+                api::query_line(tid, iid, line_id, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
         },
-        RequestMethod::Post => match api {
-            RequestApi::Add => match api_param.len() {
-                2 => {
-                    let tid: u32 = api_param[0].parse::<u32>().unwrap();
-                    let item_data: &str = api_param[1];
-
-                    // `/add/:table_id/<item>`
-                    return api::add_item(tid, item_data, restaurant);
-                }
-                _ => return "wrong api".to_string(),
-            },
-            _ => {}
+        (RequestApi::Add, RequestMethod::Post) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+                let add_req = api::parse_json(&req.body)?;
+
+                // `/add/:table_id` with a JSON body
+                api::add_item(tid, add_req, restaurant)
+            }
+            2 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+
+                // `/add/:table_id/:item_id`
+                let add_req = api::AddItemRequest {
+                    item_id: iid,
+                    spec: LineSpec::default(),
+                };
+                api::add_item(tid, add_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
         },
-        RequestMethod::Delete => match api {
-            RequestApi::Remove => match api_param.len() {
-                2 => {
-                    let tid: u32 = api_param[0].parse::<u32>().unwrap();
-                    let iid: u32 = api_param[1].parse::<u32>().unwrap();
-
-                    // `/romove/:table_id/:item_id`
-                    return api::remove_item(tid, iid, restaurant);
-                }
-                _ => return "wrong api".to_string(),
-            },
-            _ => {}
+        (RequestApi::Remove, RequestMethod::Delete) => match api_param.len() {
+            2 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+
+                // `/remove/:table_id/:item_id`
+                api::remove_item(tid, iid, restaurant)
+            }
+            3 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+                let line_id = parse_id(api_param[2])?;
+
+                // `/remove/:table_id/:item_id/:line_id`
+                api::remove_line(tid, iid, line_id, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
         },
-        RequestMethod::Put => {}
-        _ => {
-            return "unknown method".to_string();
-        }
-    }
+        (RequestApi::Update, RequestMethod::Put) => match api_param.len() {
+            3 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+                let line_id = parse_id(api_param[2])?;
+                let update = api::parse_json(&req.body)?;
+
+                // `/update/:table_id/:item_id/:line_id`
+                api::update_line(tid, iid, line_id, update, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Status, RequestMethod::Put) => match api_param.len() {
+            3 => {
+                let tid = parse_id(api_param[0])?;
+                let iid = parse_id(api_param[1])?;
+                let line_id = parse_id(api_param[2])?;
+                let state_req = api::parse_json(&req.body)?;
+
+                // `/status/:table_id/:item_id/:line_id`
+                api::set_state(tid, iid, line_id, state_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::History, RequestMethod::Get) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
 
-    "unknown request".to_string()
+                // `/history/:table_id`
+                api::query_history(tid, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Kitchen, RequestMethod::Get) => match api_param[..] {
+            // `/kitchen`
+            [] => api::query_kitchen(restaurant),
+            // `/kitchen/queue`
+            ["queue"] => api::query_kitchen_queue(restaurant),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Batch, RequestMethod::Post) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+                let batch_req = api::parse_json(&req.body)?;
+
+                // `/batch/:table_id` with a JSON body
+                api::add_batch(tid, batch_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Batch, RequestMethod::Delete) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+                let batch_req = api::parse_json(&req.body)?;
+
+                // `/batch/:table_id` with a JSON body
+                api::remove_batch(tid, batch_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Transfer, RequestMethod::Post) => match api_param.len() {
+            2 => {
+                let from = parse_id(api_param[0])?;
+                let to = parse_id(api_param[1])?;
+                let transfer_req = if req.body.is_empty() {
+                    api::TransferRequest::default()
+                } else {
+                    api::parse_json(&req.body)?
+                };
+
+                // `/transfer/:from_table_id/:to_table_id` with an optional
+                // JSON body
+                api::transfer(from, to, transfer_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Merge, RequestMethod::Post) => match api_param.len() {
+            // `/merge` with a JSON body
+            0 => api::merge_tables(api::parse_json(&req.body)?, restaurant),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Split, RequestMethod::Post) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+                let split_req = if req.body.is_empty() {
+                    api::SplitRequest::default()
+                } else {
+                    api::parse_json(&req.body)?
+                };
+
+                // `/split/:table_id` with an optional JSON body
+                api::split_tables(tid, split_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Checkout, RequestMethod::Post) => match api_param.len() {
+            1 => {
+                let tid = parse_id(api_param[0])?;
+                let checkout_req = if req.body.is_empty() {
+                    api::CheckoutRequest::default()
+                } else {
+                    api::parse_json(&req.body)?
+                };
+
+                // `/checkout/:table_id` with an optional JSON body
+                api::checkout(tid, checkout_req, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Bill, RequestMethod::Get) => match api_param.len() {
+            1 => {
+                let bid = parse_id(api_param[0])?;
+
+                // `/bill/:bill_id`
+                api::query_bill(bid, restaurant)
+            }
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Menu, method) => match (method, api_param.len()) {
+            // `/menu`
+            (RequestMethod::Get, 0) => api::list_menu(restaurant),
+            (RequestMethod::Post, 0) => {
+                let item = api::parse_json(&req.body)?;
+                api::create_menu_item(item, restaurant)
+            }
+            // `/menu/:item_id`
+            (RequestMethod::Get, 1) => api::query_menu_item(parse_id(api_param[0])?, restaurant),
+            (RequestMethod::Put, 1) => {
+                let iid = parse_id(api_param[0])?;
+                let update = api::parse_json(&req.body)?;
+                api::update_menu_item(iid, update, restaurant)
+            }
+            (RequestMethod::Delete, 1) => {
+                api::retire_menu_item(parse_id(api_param[0])?, restaurant)
+            }
+            (_, 0) | (_, 1) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Tables, method) => match (method, api_param.len()) {
+            // `/tables`
+            (RequestMethod::Get, 0) => api::list_tables(restaurant),
+            (RequestMethod::Post, 0) => {
+                let table = api::parse_json(&req.body)?;
+                api::create_table(table, restaurant)
+            }
+            // `/tables/:table_id`
+            (RequestMethod::Get, 1) => api::query_table(parse_id(api_param[0])?, restaurant),
+            (RequestMethod::Put, 1) => {
+                let tid = parse_id(api_param[0])?;
+                let update = api::parse_json(&req.body)?;
+                api::update_table(tid, update, restaurant)
+            }
+            (RequestMethod::Delete, 1) => api::disable_table(parse_id(api_param[0])?, restaurant),
+            (_, 0) | (_, 1) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Seat, method) => match (method, api_param.len()) {
+            // `/seat/:table_id`
+            (RequestMethod::Post, 1) => {
+                let tid = parse_id(api_param[0])?;
+                let seat_req = api::parse_json(&req.body)?;
+                api::seat_table(tid, seat_req, restaurant)
+            }
+            (RequestMethod::Delete, 1) => api::unseat_table(parse_id(api_param[0])?, restaurant),
+            (_, 1) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Floor, RequestMethod::Get) => match api_param.len() {
+            // `/floor`
+            0 => api::query_floor(restaurant),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Reservations, method) => match (method, &api_param[..]) {
+            // `/reservations`
+            (RequestMethod::Get, []) => api::list_reservations(restaurant),
+            (RequestMethod::Post, []) => {
+                let reservation = api::parse_json(&req.body)?;
+                api::create_reservation(reservation, restaurant)
+            }
+            // `/reservations/:reservation_id`
+            (RequestMethod::Get, [rid]) => api::query_reservation(parse_id(rid)?, restaurant),
+            (RequestMethod::Put, [rid]) => {
+                let rid = parse_id(rid)?;
+                let update = api::parse_json(&req.body)?;
+                api::update_reservation(rid, update, restaurant)
+            }
+            (RequestMethod::Delete, [rid]) => api::cancel_reservation(parse_id(rid)?, restaurant),
+            // `/reservations/:reservation_id/seat` with an optional JSON body
+            (RequestMethod::Post, [rid, "seat"]) => {
+                let rid = parse_id(rid)?;
+                let arrival = if req.body.is_empty() {
+                    api::ArrivalRequest::default()
+                } else {
+                    api::parse_json(&req.body)?
+                };
+                api::seat_reservation(rid, arrival, restaurant)
+            }
+            (_, []) | (_, [_]) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::UnknownRoute),
+        },
+        (RequestApi::Waitlist, method) => match (method, &api_param[..]) {
+            // `/waitlist`
+            (RequestMethod::Get, []) => api::list_waitlist(restaurant),
+            (RequestMethod::Post, []) => {
+                let entry = api::parse_json(&req.body)?;
+                api::join_waitlist(entry, restaurant)
+            }
+            // `/waitlist/next/:table_id`
+            (RequestMethod::Get, ["next", tid]) => {
+                api::next_on_waitlist(parse_id(tid)?, restaurant)
+            }
+            // `/waitlist/:entry_id`
+            (RequestMethod::Get, [eid]) => api::query_waitlist_entry(parse_id(eid)?, restaurant),
+            (RequestMethod::Delete, [eid]) => api::leave_waitlist(parse_id(eid)?, restaurant),
+            // `/waitlist/:entry_id/seat`
+            (RequestMethod::Post, [eid, "seat"]) => {
+                let eid = parse_id(eid)?;
+                let seating = api::parse_json(&req.body)?;
+                api::seat_from_waitlist(eid, seating, restaurant)
+            }
+            (_, []) | (_, [_]) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::UnknownRoute),
+        },
+        // `/subscribe` only answers a WebSocket upgrade, see `serve_upgrade`
+        (RequestApi::Subscribe, RequestMethod::Get) => Err(Error::MalformedRequest(
+            "subscribing takes a WebSocket upgrade".to_string(),
+        )),
+        (RequestApi::Unknown, _) => Err(Error::UnknownRoute),
+        _ => Err(Error::MethodNotAllowed),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use connection::MAX_REQUEST_SIZE;
+    use http::StatusCode;
+    use std::sync::Arc;
     use std::thread;
+    use storage::{Op, State, Storage};
 
     #[test]
     fn test_parse_method() -> Result<(), String> {
@@ -232,6 +674,7 @@ mod tests {
         assert_eq!(parse_api("/add/xxx"), (RequestApi::Add, vec!["xxx"]));
         assert_eq!(parse_api("/query/xxx"), (RequestApi::Query, vec!["xxx"]));
         assert_eq!(parse_api("/remove/xxx"), (RequestApi::Remove, vec!["xxx"]));
+        assert_eq!(parse_api("/menu"), (RequestApi::Menu, vec![]));
         assert_eq!(
             parse_api("/add/xxx/yyy"),
             (RequestApi::Add, vec!["xxx", "yyy"])
@@ -247,19 +690,329 @@ mod tests {
         Ok(())
     }
 
+    fn build_request(line: &str) -> Request {
+        let raw = format!("{} HTTP/1.1\r\n\r\n", line);
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        req
+    }
+
+    fn build_json_request(line: &str, body: &str) -> Request {
+        let raw = format!(
+            "{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            line,
+            body.len(),
+            body
+        );
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        req
+    }
+
+    /// Seats a party of two at table `tid`.
+    fn seat(restaurant: &Restaurant, tid: u32) -> Response {
+        let req = build_json_request(&format!("POST /seat/{}", tid), "{\"party_size\":2}");
+        request_parser(&req, restaurant.clone())
+    }
+
+    #[test]
+    fn test_request_parser_json_body() {
+        let restaurant = Restaurant::new(Layout::sample(1), Menu::sample(200));
+        seat(&restaurant, 0);
+
+        let raw = "POST /add/0 HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"item_id\":5}";
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        let res = request_parser(&req, restaurant.clone());
+        assert_eq!(res.status, StatusCode::Created);
+        assert!(res.body.starts_with("{\"data\":{\"line_id\":"));
+
+        let raw = "POST /add/0 HTTP/1.1\r\nContent-Length: 3\r\n\r\n5,x";
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        let res = request_parser(&req, restaurant);
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(res.body.contains("\"malformed_body\""));
+    }
+
+    #[test]
+    fn test_request_parser_if_match() {
+        let restaurant = Restaurant::new(Layout::sample(1), Menu::sample(200));
+        let res = seat(&restaurant, 0);
+        let etag = res.headers.iter().find(|(name, _)| name == "ETag").unwrap();
+        assert_eq!(etag.1, "\"1\"");
+
+        let add = |if_match: &str| {
+            let raw = format!("POST /add/0/1 HTTP/1.1\r\nIf-Match: {}\r\n\r\n", if_match);
+            let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+                .unwrap()
+                .unwrap();
+            request_parser(&req, restaurant.clone())
+        };
+        assert_eq!(add("\"1\"").status, StatusCode::Created);
+        // someone else got there first
+        let res = add("\"1\"");
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"version_mismatch\""));
+        assert_eq!(add("*").status, StatusCode::Created);
+        assert_eq!(add("soon").status, StatusCode::BadRequest);
+    }
+
+    /// Adds item 1 to table 0, sent with `Idempotency-Key: key`.
+    fn add_once(restaurant: &Restaurant, key: &str, body: &str) -> Response {
+        let raw = format!(
+            "POST /add/0 HTTP/1.1\r\nIdempotency-Key: {}\r\nContent-Length: {}\r\n\r\n{}",
+            key,
+            body.len(),
+            body
+        );
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        request_parser(&req, restaurant.clone())
+    }
+
+    fn items_on(restaurant: &Restaurant, tid: u32) -> usize {
+        restaurant
+            .get_table(tid)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .items_size()
+    }
+
+    #[test]
+    fn test_request_parser_idempotency_key() {
+        let restaurant = Restaurant::new(Layout::sample(1), Menu::sample(200));
+        let body = "{\"item_id\":1}";
+
+        // nobody is seated yet, and a failed change can be sent again
+        let res = add_once(&restaurant, "k1", body);
+        assert_eq!(res.status, StatusCode::Conflict);
+        seat(&restaurant, 0);
+
+        let first = add_once(&restaurant, "k1", body);
+        assert_eq!(first.status, StatusCode::Created);
+        let retry = add_once(&restaurant, "k1", body);
+        assert_eq!(retry.status, StatusCode::Created);
+        assert_eq!(retry.body, first.body);
+        assert!(retry
+            .headers
+            .contains(&("Idempotent-Replayed".to_string(), "true".to_string())));
+        assert_eq!(items_on(&restaurant, 0), 1);
+
+        let res = add_once(&restaurant, "k1", "{\"item_id\":2}");
+        assert_eq!(res.status, StatusCode::UnprocessableEntity);
+        assert!(res.body.contains("\"idempotency_key_reused\""));
+        assert_eq!(
+            add_once(&restaurant, "k2", body).status,
+            StatusCode::Created
+        );
+        assert_eq!(items_on(&restaurant, 0), 2);
+        assert_eq!(
+            add_once(&restaurant, "", body).status,
+            StatusCode::BadRequest
+        );
+    }
+
+    /// Keeps every change but refuses to keep idempotency keys.
+    struct KeyFailingStorage;
+
+    impl Storage for KeyFailingStorage {
+        fn append(&self, op: Op) -> Result<(), Error> {
+            match op {
+                Op::Idempotency { .. } => Err(Error::Storage("disk full".to_string())),
+                _ => Ok(()),
+            }
+        }
+
+        fn snapshot(&self, _state: State) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_request_parser_idempotency_key_not_kept() {
+        let restaurant = Restaurant::new(Layout::sample(1), Menu::sample(5))
+            .with_storage(Arc::new(KeyFailingStorage));
+        seat(&restaurant, 0);
+
+        let res = add_once(&restaurant, "k1", "{\"item_id\":1}");
+        assert_eq!(res.status, StatusCode::InternalServerError);
+        // a retry is answered from memory rather than adding again
+        let retry = add_once(&restaurant, "k1", "{\"item_id\":1}");
+        assert_eq!(retry.status, StatusCode::Created);
+        assert_eq!(items_on(&restaurant, 0), 1);
+    }
+
+    #[test]
+    fn test_request_parser_idempotency_key_recovery() {
+        for kind in StorageKind::all() {
+            if kind == StorageKind::Memory {
+                continue;
+            }
+            let dir = storage::TestDir::new("idempotency");
+            let open = || {
+                let (storage, recovery) = storage::open(kind, &dir.0).unwrap();
+                let r = Restaurant::new(Layout::sample(1), Menu::sample(5)).with_storage(storage);
+                storage::restore(&r, recovery).unwrap();
+                r
+            };
+
+            let restaurant = open();
+            seat(&restaurant, 0);
+            let first = add_once(&restaurant, "before", "{\"item_id\":1}");
+            restaurant.snapshot().unwrap();
+            add_once(&restaurant, "after", "{\"item_id\":2}");
+            drop(restaurant);
+
+            // keys from the snapshot and from the log are both kept
+            let restaurant = open();
+            let retry = add_once(&restaurant, "before", "{\"item_id\":1}");
+            assert_eq!(retry.body, first.body);
+            add_once(&restaurant, "after", "{\"item_id\":2}");
+            assert_eq!(items_on(&restaurant, 0), 2);
+        }
+    }
+
+    #[test]
+    fn test_subscription() {
+        let restaurant = Restaurant::new(Layout::sample(3), Menu::sample(5));
+        let topic =
+            |path: &str| subscription(&build_request(&format!("GET {}", path)), &restaurant);
+
+        assert_eq!(topic("/subscribe"), Ok(Topic::Restaurant));
+        assert_eq!(
+            topic("/subscribe/2,0"),
+            Ok(Topic::Tables([0, 2].into_iter().collect()))
+        );
+        assert_eq!(topic("/subscribe/7"), Err(Error::TableNotFound(7)));
+        assert_eq!(topic("/query/1"), Err(Error::UnknownRoute));
+
+        // without the upgrade there is nothing to subscribe to
+        let res = request_parser(&build_request("GET /subscribe"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_request_parser_status() {
+        let restaurant = Restaurant::new(Layout::sample(1), Menu::sample(200));
+
+        let res = request_parser(&build_request("POST /add/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"table_not_seated\""));
+
+        assert_eq!(seat(&restaurant, 0).status, StatusCode::Created);
+        assert_eq!(seat(&restaurant, 0).status, StatusCode::Conflict);
+
+        let res = request_parser(&build_request("POST /add/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Created);
+
+        let res = request_parser(&build_request("GET /floor"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(res.body.contains("\"status\":\"occupied\""));
+
+        let res = request_parser(&build_request("DELETE /seat/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"table_in_use\""));
+
+        let res = request_parser(&build_request("GET /query/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let res = request_parser(&build_request("GET /query/0/2"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        let res = request_parser(&build_request("GET /unknown/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        let res = request_parser(&build_request("GET /add/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+
+        let res = request_parser(&build_request("PATCH /add/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+
+        let res = request_parser(&build_request("GET /query/abc"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(res.body.contains("\"invalid_id\""));
+
+        let res = request_parser(&build_request("GET /query/9999"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(res.body.contains("\"table_not_found\""));
+
+        let res = request_parser(&build_request("POST /add/0/9999"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(res.body.contains("\"menu_item_not_found\""));
+
+        let res = request_parser(&build_request("DELETE /menu/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let res = request_parser(&build_request("POST /add/0/1"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+
+        let res = request_parser(&build_request("GET /kitchen/queue"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let res = request_parser(&build_request("POST /checkout/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"items_pending\""));
+
+        let res = request_parser(&build_request("GET /bill/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        let res = request_parser(&build_request("GET /tables"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let res = request_parser(
+            &build_request("POST /reservations/0/seat"),
+            restaurant.clone(),
+        );
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(res.body.contains("\"reservation_not_found\""));
+
+        let res = request_parser(&build_request("POST /split/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"table_not_merged\""));
+
+        let res = request_parser(&build_request("PUT /update/0/1/2"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(res.body.contains("\"malformed_body\""));
+
+        let res = request_parser(&build_request("DELETE /batch/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(res.body.contains("\"malformed_body\""));
+
+        let res = request_parser(&build_request("POST /transfer/0/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::BadRequest);
+
+        let res = request_parser(&build_request("GET /waitlist/next/0"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::Conflict);
+        assert!(res.body.contains("\"table_occupied\""));
+
+        let res = request_parser(&build_request("DELETE /tables/9999"), restaurant.clone());
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(res.body.contains("\"table_not_found\""));
+
+        let res = request_parser(&build_request("PATCH /menu"), restaurant);
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+    }
+
     fn get_restaruant_ready(desire_table_id: u32, add_amount: usize) -> Restaurant {
-        let restaurant = Restaurant::new(200);
+        let restaurant = Restaurant::new(Layout::sample(200), Menu::sample(200));
+        seat(&restaurant, desire_table_id);
 
         let mut handles = vec![];
 
         for test_id in 0..add_amount {
             let restaurant = restaurant.clone();
 
-            let req = format!("POST /add/{}/{}", desire_table_id, test_id);
-            let mut bytes: Vec<u8> = req.as_bytes().to_vec();
+            let req = build_request(&format!("POST /add/{}/{}", desire_table_id, test_id));
 
             let handle = thread::spawn(move || {
-                let _res = request_parser(&mut bytes, restaurant.clone());
+                let _res = request_parser(&req, restaurant.clone());
             });
 
             handles.push(handle);
@@ -277,7 +1030,7 @@ mod tests {
         let add_amount = 100;
         let restaurant = get_restaruant_ready(desire_table_id, add_amount);
 
-        let t = restaurant.get_table(desire_table_id);
+        let t = restaurant.get_table(desire_table_id).unwrap();
         let len = t.lock().unwrap().items_size();
         assert_eq!(len, add_amount);
 
@@ -296,12 +1049,11 @@ mod tests {
         for test_id in 0..remove_amount {
             let restaurant = restaurant.clone();
 
-            let req = format!("DELETE /remove/{}/{}", desire_table_id, test_id);
-            let mut bytes: Vec<u8> = req.as_bytes().to_vec();
+            let req = build_request(&format!("DELETE /remove/{}/{}", desire_table_id, test_id));
 
             let handle = thread::spawn(move || {
-                let _res = request_parser(&mut bytes, restaurant.clone());
-                println!("{}", _res);
+                let _res = request_parser(&req, restaurant.clone());
+                println!("{}", _res.body);
             });
 
             handles.push(handle);
@@ -310,7 +1062,7 @@ mod tests {
             handle.join().unwrap();
         }
 
-        let t = restaurant.get_table(desire_table_id);
+        let t = restaurant.get_table(desire_table_id).unwrap();
         let len = t.lock().unwrap().items_size();
         assert_eq!(len, add_amount - remove_amount);
 
@@ -328,13 +1080,12 @@ mod tests {
         for test_id in 0..add_amount {
             let restaurant = restaurant.clone();
 
-            let req = format!("GET /query/{}/{}", desire_table_id, test_id);
-            let mut bytes: Vec<u8> = req.as_bytes().to_vec();
+            let req = build_request(&format!("GET /query/{}/{}", desire_table_id, test_id));
 
             let handle = thread::spawn(move || {
-                let res = request_parser(&mut bytes, restaurant.clone());
-                let s = format!("\"item_id\": {}", test_id);
-                assert_eq!(res.contains(&s), true);
+                let res = request_parser(&req, restaurant.clone()).body;
+                let s = format!("\"item_id\":{},", test_id);
+                assert!(res.contains(&s));
             });
 
             handles.push(handle);
@@ -358,12 +1109,11 @@ mod tests {
         for test_id in 0..remove_amount {
             let restaurant = restaurant.clone();
 
-            let req = format!("DELETE /remove/{}/{}", desire_table_id, test_id);
-            let mut bytes: Vec<u8> = req.as_bytes().to_vec();
+            let req = build_request(&format!("DELETE /remove/{}/{}", desire_table_id, test_id));
 
             let handle = thread::spawn(move || {
-                let _res = request_parser(&mut bytes, restaurant.clone());
-                println!("{}", _res);
+                let _res = request_parser(&req, restaurant.clone());
+                println!("{}", _res.body);
             });
 
             handles.push(handle);
@@ -376,22 +1126,23 @@ mod tests {
         {
             let restaurant = restaurant.clone();
 
-            let req = format!("GET /query/{}", desire_table_id);
-            let mut bytes: Vec<u8> = req.as_bytes().to_vec();
-
-            let _ = thread::spawn(move || {
-                let res = request_parser(&mut bytes, restaurant.clone());
-                let s0 = "\"item_id\": 16";
-                let s1 = "\"item_id\": 17";
-                let s2 = "\"item_id\": 18";
-                let s3 = "\"item_id\": 19";
-                let s4 = "\"item_id\": 20";
-                assert_eq!(res.contains(&s0), false);
-                assert_eq!(res.contains(&s1), true);
-                assert_eq!(res.contains(&s2), true);
-                assert_eq!(res.contains(&s3), true);
-                assert_eq!(res.contains(&s4), false);
-            });
+            let req = build_request(&format!("GET /query/{}", desire_table_id));
+
+            thread::spawn(move || {
+                let res = request_parser(&req, restaurant.clone()).body;
+                let s0 = "\"item_id\":16,";
+                let s1 = "\"item_id\":17,";
+                let s2 = "\"item_id\":18,";
+                let s3 = "\"item_id\":19,";
+                let s4 = "\"item_id\":20,";
+                assert!(!res.contains(s0));
+                assert!(res.contains(s1));
+                assert!(res.contains(s2));
+                assert!(res.contains(s3));
+                assert!(!res.contains(s4));
+            })
+            .join()
+            .unwrap();
         }
 
         Ok(())
@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+use super::error::Error;
+
+/// One dish the kitchen can make. Prices are in cents and preparation
+/// times in minutes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MenuItem {
+    pub item_id: u32,
+    pub name: String,
+    pub category: String,
+    pub price: u32,
+    pub prep_time_min: u32,
+    pub prep_time_max: u32,
+    #[serde(default = "default_available")]
+    pub available: bool,
+    #[serde(default)]
+    pub retired: bool,
+    /// The changes guests may ask for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<Modifier>,
+}
+
+/// A change to a dish a guest may ask for, e.g. "no onion" or "extra
+/// spicy", and what it adds to the price and the cooking.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Modifier {
+    pub name: String,
+    #[serde(default)]
+    pub price: u32,
+    #[serde(default)]
+    pub prep_time: u32,
+}
+
+fn default_available() -> bool {
+    true
+}
+
+impl MenuItem {
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error::MalformedBody("menu item needs a name".to_string()));
+        }
+        if self.prep_time_min == 0 || self.prep_time_min > self.prep_time_max {
+            return Err(Error::MalformedBody(
+                "prep_time_min must be positive and not above prep_time_max".to_string(),
+            ));
+        }
+        for (n, modifier) in self.modifiers.iter().enumerate() {
+            if modifier.name.trim().is_empty() {
+                return Err(Error::MalformedBody("modifier needs a name".to_string()));
+            }
+            if self.modifiers[..n].iter().any(|m| m.name == modifier.name) {
+                return Err(Error::MalformedBody(format!(
+                    "modifier '{}' is listed twice",
+                    modifier.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn modifier(&self, name: &str) -> Option<&Modifier> {
+        self.modifiers.iter().find(|m| m.name == name)
+    }
+
+    /// Checks every one of `names` may be asked for, each at most once.
+    pub fn check_modifiers(&self, names: &[String]) -> Result<(), Error> {
+        for (n, name) in names.iter().enumerate() {
+            if self.modifier(name).is_none() || names[..n].contains(name) {
+                return Err(Error::ModifierNotAllowed {
+                    item_id: self.item_id,
+                    modifier: name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn sample(item_id: u32) -> MenuItem {
+        MenuItem {
+            item_id,
+            name: format!("dish {}", item_id),
+            category: "main".to_string(),
+            price: 100 * (item_id + 1),
+            prep_time_min: 5,
+            prep_time_max: 14,
+            available: true,
+            retired: false,
+            modifiers: vec![],
+        }
+    }
+}
+
+/// Fields of a menu entry that can be changed at runtime.
+#[derive(Debug, Default, Deserialize)]
+pub struct MenuItemUpdate {
+    pub name: Option<String>,
+    pub category: Option<String>,
+    pub price: Option<u32>,
+    pub prep_time_min: Option<u32>,
+    pub prep_time_max: Option<u32>,
+    pub available: Option<bool>,
+    pub modifiers: Option<Vec<Modifier>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Menu {
+    items: BTreeMap<u32, MenuItem>,
+}
+
+impl Menu {
+    pub fn new(items: Vec<MenuItem>) -> Result<Menu, Error> {
+        let mut menu = Menu::default();
+        for item in items {
+            menu.create(item)?;
+        }
+        Ok(menu)
+    }
+
+    /// The menu as it was stored, taken as it is.
+    pub fn restored(items: Vec<MenuItem>) -> Menu {
+        let mut menu = Menu::default();
+        for item in items {
+            menu.restore(item);
+        }
+        menu
+    }
+
+    /// Puts back an entry recovered from storage, replacing the one there.
+    pub fn restore(&mut self, item: MenuItem) {
+        self.items.insert(item.item_id, item);
+    }
+
+    /// Reads a menu from a JSON file holding a list of menu items.
+    pub fn load(path: &str) -> Result<Menu, Error> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("cannot read {}: {}", path, e)))?;
+        let items: Vec<MenuItem> = serde_json::from_str(&data)
+            .map_err(|e| Error::Config(format!("bad menu {}: {}", path, e)))?;
+
+        Menu::new(items)
+    }
+
+    pub fn list(&self) -> Vec<&MenuItem> {
+        self.items.values().collect()
+    }
+
+    pub fn get(&self, item_id: u32) -> Result<&MenuItem, Error> {
+        self.items
+            .get(&item_id)
+            .ok_or(Error::MenuItemNotFound(item_id))
+    }
+
+    /// The menu entry for `item_id` if it can be ordered right now.
+    pub fn orderable(&self, item_id: u32) -> Result<&MenuItem, Error> {
+        let item = self.get(item_id)?;
+        if item.retired || !item.available {
+            return Err(Error::MenuItemUnavailable(item_id));
+        }
+        Ok(item)
+    }
+
+    pub fn create(&mut self, item: MenuItem) -> Result<&MenuItem, Error> {
+        item.validate()?;
+        if self.items.contains_key(&item.item_id) {
+            return Err(Error::MenuItemExists(item.item_id));
+        }
+
+        let item_id = item.item_id;
+        self.items.insert(item_id, item);
+        self.get(item_id)
+    }
+
+    pub fn update(&mut self, item_id: u32, update: MenuItemUpdate) -> Result<&MenuItem, Error> {
+        let mut item = self.get(item_id)?.clone();
+        if item.retired {
+            return Err(Error::MenuItemUnavailable(item_id));
+        }
+
+        if let Some(name) = update.name {
+            item.name = name;
+        }
+        if let Some(category) = update.category {
+            item.category = category;
+        }
+        if let Some(price) = update.price {
+            item.price = price;
+        }
+        if let Some(min) = update.prep_time_min {
+            item.prep_time_min = min;
+        }
+        if let Some(max) = update.prep_time_max {
+            item.prep_time_max = max;
+        }
+        if let Some(available) = update.available {
+            item.available = available;
+        }
+        if let Some(modifiers) = update.modifiers {
+            item.modifiers = modifiers;
+        }
+        item.validate()?;
+
+        self.items.insert(item_id, item);
+        self.get(item_id)
+    }
+
+    /// Retired entries stay on record for past orders but can never be
+    /// ordered again.
+    pub fn retire(&mut self, item_id: u32) -> Result<&MenuItem, Error> {
+        let item = self
+            .items
+            .get_mut(&item_id)
+            .ok_or(Error::MenuItemNotFound(item_id))?;
+        item.retired = true;
+        item.available = false;
+
+        Ok(item)
+    }
+
+    #[cfg(test)]
+    pub fn sample(n: u32) -> Menu {
+        Menu::new((0..n).map(MenuItem::sample).collect()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_menu_orderable() {
+        let mut menu = Menu::sample(3);
+
+        assert_eq!(menu.orderable(1).unwrap().name, "dish 1");
+        assert_eq!(menu.orderable(3).err(), Some(Error::MenuItemNotFound(3)));
+
+        menu.update(
+            1,
+            MenuItemUpdate {
+                available: Some(false),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(menu.orderable(1).err(), Some(Error::MenuItemUnavailable(1)));
+
+        menu.retire(2).unwrap();
+        assert_eq!(menu.orderable(2).err(), Some(Error::MenuItemUnavailable(2)));
+        assert!(menu.get(2).unwrap().retired);
+    }
+
+    #[test]
+    fn test_menu_create_and_update() {
+        let mut menu = Menu::sample(1);
+
+        assert_eq!(
+            menu.create(MenuItem::sample(0)).err(),
+            Some(Error::MenuItemExists(0))
+        );
+
+        let mut bad = MenuItem::sample(5);
+        bad.prep_time_max = 1;
+        assert!(matches!(menu.create(bad), Err(Error::MalformedBody(_))));
+
+        let item = menu
+            .update(
+                0,
+                MenuItemUpdate {
+                    price: Some(999),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(item.price, 999);
+    }
+
+    #[test]
+    fn test_menu_from_json() {
+        let data = r#"[{"item_id": 1, "name": "Ramen", "category": "noodles",
+                        "price": 1200, "prep_time_min": 8, "prep_time_max": 12}]"#;
+        let items: Vec<MenuItem> = serde_json::from_str(data).unwrap();
+        let menu = Menu::new(items).unwrap();
+
+        let ramen = menu.orderable(1).unwrap();
+        assert_eq!(ramen.name, "Ramen");
+        assert!(ramen.available);
+        assert!(!ramen.retired);
+        assert!(ramen.modifiers.is_empty());
+    }
+
+    #[test]
+    fn test_menu_modifiers() {
+        let data = r#"{"item_id": 1, "name": "Ramen", "category": "noodles",
+                       "price": 1200, "prep_time_min": 8, "prep_time_max": 12,
+                       "modifiers": [{"name": "no onion"},
+                                     {"name": "extra pork", "price": 300, "prep_time": 2}]}"#;
+        let ramen: MenuItem = serde_json::from_str(data).unwrap();
+        assert_eq!(ramen.modifier("extra pork").unwrap().price, 300);
+        assert_eq!(ramen.modifier("no onion").unwrap().prep_time, 0);
+
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            ramen.check_modifiers(&names(&["no onion", "extra pork"])),
+            Ok(())
+        );
+        let not_allowed = |modifier: &str| {
+            Err(Error::ModifierNotAllowed {
+                item_id: 1,
+                modifier: modifier.to_string(),
+            })
+        };
+        assert_eq!(
+            ramen.check_modifiers(&names(&["no egg"])),
+            not_allowed("no egg")
+        );
+        assert_eq!(
+            ramen.check_modifiers(&names(&["no onion", "no onion"])),
+            not_allowed("no onion")
+        );
+
+        let mut twice = ramen.clone();
+        twice.modifiers.push(ramen.modifiers[0].clone());
+        assert!(matches!(
+            Menu::new(vec![twice]),
+            Err(Error::MalformedBody(_))
+        ));
+    }
+}
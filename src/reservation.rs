@@ -0,0 +1,460 @@
+//! Bookings of a table for a party and a time window.
+//!
+//! `Reservations` only does the bookkeeping and never locks a table itself;
+//! callers pass in the floor plan to check against. Lock a table first if
+//! both are needed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use super::clock::MS_PER_MINUTE;
+use super::error::Error;
+use super::layout::TableConfig;
+use super::restaurant::{undo_on_error, Restaurant};
+use super::storage::Op;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservationState {
+    Booked,
+    Seated,
+    Cancelled,
+    /// The party did not turn up within the grace period.
+    NoShow,
+}
+
+impl ReservationState {
+    /// Whether the reservation still holds its table.
+    fn holds_table(self) -> bool {
+        matches!(self, ReservationState::Booked | ReservationState::Seated)
+    }
+}
+
+impl fmt::Display for ReservationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ReservationState::Booked => "booked",
+            ReservationState::Seated => "seated",
+            ReservationState::Cancelled => "cancelled",
+            ReservationState::NoShow => "no_show",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// One booking. Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub reservation_id: u32,
+    pub table_id: u32,
+    pub name: String,
+    pub party_size: u32,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub state: ReservationState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seated_at: Option<u64>,
+}
+
+impl Reservation {
+    fn overlaps(&self, other: &Reservation) -> bool {
+        self.starts_at < other.ends_at && other.starts_at < self.ends_at
+    }
+}
+
+/// Body of `POST /reservations`.
+#[derive(Debug, Deserialize)]
+pub struct ReservationRequest {
+    /// The table to book. Left out, the smallest free table that fits the
+    /// party is taken.
+    #[serde(default)]
+    pub table_id: Option<u32>,
+    pub name: String,
+    pub party_size: u32,
+    pub starts_at: u64,
+    pub ends_at: u64,
+}
+
+/// Fields of a booking that can be changed.
+#[derive(Debug, Default, Deserialize)]
+pub struct ReservationUpdate {
+    pub table_id: Option<u32>,
+    pub name: Option<String>,
+    pub party_size: Option<u32>,
+    pub starts_at: Option<u64>,
+    pub ends_at: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Reservations {
+    // how long a table is held after the booked time
+    grace_ms: u64,
+    next_id: u32,
+    reservations: BTreeMap<u32, Reservation>,
+}
+
+impl Reservations {
+    pub fn new(no_show_minutes: u64) -> Reservations {
+        Reservations {
+            grace_ms: no_show_minutes * MS_PER_MINUTE,
+            next_id: 0,
+            reservations: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, reservation_id: u32) -> Result<&Reservation, Error> {
+        self.reservations
+            .get(&reservation_id)
+            .ok_or(Error::ReservationNotFound(reservation_id))
+    }
+
+    /// A booking that can still be changed or sat down.
+    pub fn booked(&self, reservation_id: u32) -> Result<&Reservation, Error> {
+        let reservation = self.get(reservation_id)?;
+        if reservation.state != ReservationState::Booked {
+            return Err(Error::ReservationClosed {
+                reservation_id,
+                state: reservation.state,
+            });
+        }
+        Ok(reservation)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Reservation> {
+        self.reservations.values()
+    }
+
+    /// Whether a party is still expected at `table_id`.
+    pub fn holds_table(&self, table_id: u32) -> bool {
+        self.list()
+            .any(|r| r.table_id == table_id && r.state == ReservationState::Booked)
+    }
+
+    pub fn insert(&mut self, reservation: Reservation) {
+        let reservation_id = reservation.reservation_id;
+        self.next_id = self.next_id.max(reservation_id + 1);
+        self.reservations.insert(reservation_id, reservation);
+    }
+
+    /// Books a table out of `tables` for `req`.
+    pub fn book(
+        &mut self,
+        req: ReservationRequest,
+        tables: &[TableConfig],
+        now: u64,
+    ) -> Result<Reservation, Error> {
+        let mut reservation = Reservation {
+            reservation_id: self.next_id,
+            table_id: 0,
+            name: req.name,
+            party_size: req.party_size,
+            starts_at: req.starts_at,
+            ends_at: req.ends_at,
+            state: ReservationState::Booked,
+            seated_at: None,
+        };
+        reservation.table_id = self.place(&reservation, req.table_id, tables, now)?;
+
+        self.insert(reservation.clone());
+        Ok(reservation)
+    }
+
+    /// Changes a booking, keeping its table unless another is asked for.
+    pub fn modify(
+        &mut self,
+        reservation_id: u32,
+        update: ReservationUpdate,
+        tables: &[TableConfig],
+        now: u64,
+    ) -> Result<Reservation, Error> {
+        let mut reservation = self.booked(reservation_id)?.clone();
+
+        if let Some(name) = update.name {
+            reservation.name = name;
+        }
+        if let Some(party_size) = update.party_size {
+            reservation.party_size = party_size;
+        }
+        if let Some(starts_at) = update.starts_at {
+            reservation.starts_at = starts_at;
+        }
+        if let Some(ends_at) = update.ends_at {
+            reservation.ends_at = ends_at;
+        }
+        let table_id = update.table_id.unwrap_or(reservation.table_id);
+        reservation.table_id = self.place(&reservation, Some(table_id), tables, now)?;
+
+        self.insert(reservation.clone());
+        Ok(reservation)
+    }
+
+    pub fn cancel(&mut self, reservation_id: u32) -> Result<Reservation, Error> {
+        self.booked(reservation_id)?;
+        self.set_state(reservation_id, ReservationState::Cancelled, None)
+    }
+
+    /// Marks the party as arrived. Seating them at the table is up to the
+    /// caller.
+    pub fn seat(&mut self, reservation_id: u32, now: u64) -> Result<Reservation, Error> {
+        self.booked(reservation_id)?;
+        self.set_state(reservation_id, ReservationState::Seated, Some(now))
+    }
+
+    /// Gives up on every party that is more than the grace period late and
+    /// returns their bookings.
+    pub fn release_no_shows(&mut self, now: u64) -> Vec<Reservation> {
+        let late = self
+            .list()
+            .filter(|r| r.state == ReservationState::Booked && r.starts_at + self.grace_ms <= now)
+            .map(|r| r.reservation_id)
+            .collect::<Vec<_>>();
+
+        late.into_iter()
+            .filter_map(|id| self.set_state(id, ReservationState::NoShow, None).ok())
+            .collect()
+    }
+
+    fn set_state(
+        &mut self,
+        reservation_id: u32,
+        state: ReservationState,
+        seated_at: Option<u64>,
+    ) -> Result<Reservation, Error> {
+        let reservation = self
+            .reservations
+            .get_mut(&reservation_id)
+            .ok_or(Error::ReservationNotFound(reservation_id))?;
+        reservation.state = state;
+        reservation.seated_at = seated_at;
+
+        Ok(reservation.clone())
+    }
+
+    /// Picks the table for `reservation`: `table_id` if given and free, or
+    /// else the smallest free table the party fits at.
+    fn place(
+        &self,
+        reservation: &Reservation,
+        table_id: Option<u32>,
+        tables: &[TableConfig],
+        now: u64,
+    ) -> Result<u32, Error> {
+        if reservation.name.trim().is_empty() {
+            return Err(Error::MalformedBody("reservation needs a name".to_string()));
+        }
+        if reservation.party_size == 0 {
+            return Err(Error::MalformedBody(
+                "party size must be positive".to_string(),
+            ));
+        }
+        if reservation.ends_at <= reservation.starts_at {
+            return Err(Error::MalformedBody(
+                "a reservation has to end after it starts".to_string(),
+            ));
+        }
+        if reservation.ends_at <= now {
+            return Err(Error::MalformedBody(
+                "reservation is in the past".to_string(),
+            ));
+        }
+
+        let is_free = |table_id: u32| {
+            !self.list().any(|r| {
+                r.reservation_id != reservation.reservation_id
+                    && r.table_id == table_id
+                    && r.state.holds_table()
+                    && r.overlaps(reservation)
+            })
+        };
+
+        if let Some(table_id) = table_id {
+            let table = tables
+                .iter()
+                .find(|t| t.table_id == table_id)
+                .ok_or(Error::TableNotFound(table_id))?;
+            if table.disabled {
+                return Err(Error::TableDisabled(table_id));
+            }
+            if table.capacity < reservation.party_size {
+                return Err(Error::PartyTooLarge {
+                    table_id,
+                    size: reservation.party_size,
+                });
+            }
+            if !is_free(table_id) {
+                return Err(Error::ReservationConflict(table_id));
+            }
+            return Ok(table_id);
+        }
+
+        let mut fitting = tables
+            .iter()
+            .filter(|t| !t.disabled && t.capacity >= reservation.party_size)
+            .collect::<Vec<_>>();
+        fitting.sort_by_key(|t| (t.capacity, t.table_id));
+
+        fitting
+            .into_iter()
+            .map(|t| t.table_id)
+            .find(|&table_id| is_free(table_id))
+            .ok_or(Error::NoTableAvailable(reservation.party_size))
+    }
+}
+
+/// Releases the tables of parties that did not turn up. If that cannot be
+/// logged nothing is released, and the next round tries again.
+pub fn release_no_shows(restaurant: &Restaurant) {
+    let now = restaurant.now();
+    let mut reservations = restaurant.reservations().lock().unwrap();
+
+    let released = undo_on_error(&mut *reservations, |reservations| {
+        let ops = reservations
+            .release_no_shows(now)
+            .into_iter()
+            .map(|reservation| Op::Reservation { reservation })
+            .collect::<Vec<_>>();
+        if !ops.is_empty() {
+            restaurant.log(Op::Batch { ops })?;
+        }
+        Ok(())
+    });
+    if let Err(e) = released {
+        println!("reservations: {}", e);
+    }
+}
+
+/// Looks for no-shows every `interval`.
+pub async fn run(restaurant: Restaurant, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        release_no_shows(&restaurant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: u64 = MS_PER_MINUTE;
+
+    fn tables() -> Vec<TableConfig> {
+        let mut tables = (0..3).map(TableConfig::sample).collect::<Vec<_>>();
+        tables[0].capacity = 6;
+        tables[2].capacity = 2;
+        tables
+    }
+
+    fn request(table_id: Option<u32>, party_size: u32, starts_at: u64) -> ReservationRequest {
+        ReservationRequest {
+            table_id,
+            name: "kim".to_string(),
+            party_size,
+            starts_at,
+            ends_at: starts_at + 90 * M,
+        }
+    }
+
+    #[test]
+    fn test_reservations_book() {
+        let mut reservations = Reservations::new(15);
+        let tables = tables();
+
+        // the smallest table that fits comes first
+        let booked = reservations.book(request(None, 2, 60 * M), &tables, 0);
+        assert_eq!(booked.unwrap().table_id, 2);
+        let booked = reservations.book(request(None, 2, 60 * M), &tables, 0);
+        assert_eq!(booked.unwrap().table_id, 1);
+
+        assert_eq!(
+            reservations
+                .book(request(Some(1), 2, 120 * M), &tables, 0)
+                .err(),
+            Some(Error::ReservationConflict(1))
+        );
+        assert_eq!(
+            reservations.book(request(Some(2), 3, 0), &tables, 0).err(),
+            Some(Error::PartyTooLarge {
+                table_id: 2,
+                size: 3
+            })
+        );
+        assert_eq!(
+            reservations.book(request(None, 8, 0), &tables, 0).err(),
+            Some(Error::NoTableAvailable(8))
+        );
+
+        // back to back bookings do not overlap
+        let booked = reservations.book(request(Some(1), 2, 150 * M), &tables, 0);
+        assert_eq!(booked.unwrap().reservation_id, 2);
+    }
+
+    #[test]
+    fn test_reservations_modify_and_cancel() {
+        let mut reservations = Reservations::new(15);
+        let tables = tables();
+        reservations
+            .book(request(Some(0), 4, 0), &tables, 0)
+            .unwrap();
+        reservations
+            .book(request(Some(1), 4, 0), &tables, 0)
+            .unwrap();
+
+        let update = ReservationUpdate {
+            party_size: Some(5),
+            ..Default::default()
+        };
+        assert!(matches!(
+            reservations.modify(1, update, &tables, 0),
+            Err(Error::PartyTooLarge { .. })
+        ));
+        let update = ReservationUpdate {
+            table_id: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            reservations.modify(1, update, &tables, 0).err(),
+            Some(Error::ReservationConflict(0))
+        );
+
+        reservations.cancel(0).unwrap();
+        assert!(!reservations.holds_table(0));
+        let update = ReservationUpdate {
+            table_id: Some(0),
+            party_size: Some(5),
+            ..Default::default()
+        };
+        let modified = reservations.modify(1, update, &tables, 0).unwrap();
+        assert_eq!((modified.table_id, modified.party_size), (0, 5));
+
+        assert_eq!(
+            reservations.cancel(0).err(),
+            Some(Error::ReservationClosed {
+                reservation_id: 0,
+                state: ReservationState::Cancelled
+            })
+        );
+    }
+
+    #[test]
+    fn test_reservations_release_no_shows() {
+        let mut reservations = Reservations::new(15);
+        let tables = tables();
+        reservations
+            .book(request(None, 2, 10 * M), &tables, 0)
+            .unwrap();
+        reservations
+            .book(request(None, 2, 10 * M), &tables, 0)
+            .unwrap();
+        reservations.seat(1, 12 * M).unwrap();
+
+        assert!(reservations.release_no_shows(24 * M).is_empty());
+        let released = reservations.release_no_shows(25 * M);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].state, ReservationState::NoShow);
+        assert_eq!(reservations.get(1).unwrap().state, ReservationState::Seated);
+        assert!(reservations.seat(0, 26 * M).is_err());
+    }
+}
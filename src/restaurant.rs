@@ -1,45 +1,438 @@
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
 
-use super::table::Table;
+use super::bill::{Bills, Charges};
+use super::clock::{Clock, MonotonicClock};
+use super::error::Error;
+use super::events::{Event, EVENT_BUFFER};
+use super::idempotency::{IdempotencyKeys, KeptResponse};
+use super::kitchen::Kitchen;
+use super::layout::{Layout, TableConfig, TableUpdate};
+use super::menu::Menu;
+use super::reservation::Reservations;
+use super::storage::{MemoryStorage, Op, State, Storage};
+use super::table::{Table, TableVersion};
+use super::waitlist::Waitlist;
 
 type TablePtr = Arc<Mutex<Table>>;
 
 #[derive(Clone)]
 pub struct Restaurant {
-    tables: Vec<TablePtr>,
+    // a table is looked up with this lock held only briefly, and never
+    // while holding a table lock
+    tables: Arc<RwLock<BTreeMap<u32, TablePtr>>>,
+    menu: Arc<RwLock<Menu>>,
+    clock: Arc<dyn Clock>,
+    kitchen: Arc<Mutex<Kitchen>>,
+    storage: Arc<dyn Storage>,
+    bills: Arc<Mutex<Bills>>,
+    reservations: Arc<Mutex<Reservations>>,
+    waitlist: Arc<Mutex<Waitlist>>,
+    idempotency: Arc<Mutex<IdempotencyKeys>>,
+    events: broadcast::Sender<Event>,
+    charges: Charges,
+    // the table version the request being served was made against
+    expected_version: Option<u64>,
 }
 
 impl Restaurant {
-    pub fn new(table_size: usize) -> Restaurant {
-        let mut tables = Vec::new();
+    pub fn new(layout: Layout, menu: Menu) -> Restaurant {
+        let restaurant = Restaurant {
+            tables: Arc::new(RwLock::new(BTreeMap::new())),
+            menu: Arc::new(RwLock::new(menu)),
+            clock: Arc::new(MonotonicClock::new()),
+            kitchen: Arc::new(Mutex::new(Kitchen::new(4))),
+            storage: Arc::new(MemoryStorage),
+            bills: Arc::new(Mutex::new(Bills::default())),
+            reservations: Arc::new(Mutex::new(Reservations::new(15))),
+            waitlist: Arc::new(Mutex::new(Waitlist::default())),
+            idempotency: Arc::new(Mutex::new(IdempotencyKeys::new(24 * 60 * 60))),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            charges: Charges::default(),
+            expected_version: None,
+        };
+        restaurant.set_layout(layout);
+        restaurant
+    }
+
+    /// Replaces every table, dropping whatever was ordered on them.
+    pub fn set_layout(&self, layout: Layout) {
+        *self.tables.write().unwrap() = layout
+            .tables()
+            .iter()
+            .map(|config| {
+                let table = Table::new(config.clone());
+                (config.table_id, Arc::new(Mutex::new(table)))
+            })
+            .collect();
+    }
+
+    pub fn with_kitchen(mut self, kitchen: Kitchen) -> Restaurant {
+        self.kitchen = Arc::new(Mutex::new(kitchen));
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Restaurant {
+        self.storage = storage;
+        self
+    }
+
+    pub fn with_reservations(mut self, reservations: Reservations) -> Restaurant {
+        self.reservations = Arc::new(Mutex::new(reservations));
+        self
+    }
+
+    pub fn with_idempotency(mut self, keys: IdempotencyKeys) -> Restaurant {
+        self.idempotency = Arc::new(Mutex::new(keys));
+        self
+    }
 
-        tables.reserve(table_size);
+    pub fn with_charges(mut self, charges: Charges) -> Restaurant {
+        self.charges = charges;
+        self
+    }
+
+    /// A handle for one request that only changes a table still at
+    /// `version`, see `check_version`.
+    pub fn expecting(mut self, version: Option<u64>) -> Restaurant {
+        self.expected_version = version;
+        self
+    }
+
+    /// Refuses to change `t` if the request was made against another
+    /// version of it. Call with the table locked.
+    pub fn check_version(&self, t: &Table) -> Result<(), Error> {
+        t.check_version(self.expected_version)
+    }
+
+    /// Replaces the clock, e.g. with a manual one in tests.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Restaurant {
+        self.clock = clock;
+        self
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    /// Read it before locking a table, never while holding one.
+    pub fn menu(&self) -> &RwLock<Menu> {
+        &self.menu
+    }
 
-        for tid in 0..table_size as u32 {
-            tables.push(Arc::new(Mutex::new(Table::new(tid))));
+    /// Lock a table first if both are needed, see `kitchen`.
+    pub fn kitchen(&self) -> &Mutex<Kitchen> {
+        &self.kitchen
+    }
+
+    /// Lock a table first if both are needed.
+    pub fn bills(&self) -> &Mutex<Bills> {
+        &self.bills
+    }
+
+    /// Lock a table first if both are needed.
+    pub fn reservations(&self) -> &Mutex<Reservations> {
+        &self.reservations
+    }
+
+    /// Lock a table first if both are needed.
+    pub fn waitlist(&self) -> &Mutex<Waitlist> {
+        &self.waitlist
+    }
+
+    /// Never lock it while holding a table, see `IdempotencyKeys`.
+    pub fn idempotency(&self) -> &Mutex<IdempotencyKeys> {
+        &self.idempotency
+    }
+
+    /// Keeps the response to a change sent with an idempotency key, so it
+    /// is answered again after a restart too. If that cannot be logged the
+    /// response is still kept until the server stops, so a retry does not
+    /// make the change twice.
+    pub fn keep_response(&self, kept: KeptResponse) -> Result<(), Error> {
+        let mut keys = self.idempotency.lock().unwrap();
+        keys.finish(kept.clone());
+        self.log(Op::Idempotency { response: kept })
+    }
+
+    pub fn charges(&self) -> Charges {
+        self.charges
+    }
+
+    /// Every table, in id order.
+    pub fn tables(&self) -> Vec<TablePtr> {
+        self.tables.read().unwrap().values().cloned().collect()
+    }
+
+    /// The floor plan as it is now.
+    pub fn layout(&self) -> Vec<TableConfig> {
+        self.tables()
+            .iter()
+            .map(|t| t.lock().unwrap().config().clone())
+            .collect()
+    }
+
+    pub fn add_table(&self, config: TableConfig) -> Result<TableConfig, Error> {
+        let config = Layout::new(vec![config])?.tables()[0].clone();
+        let mut tables = self.tables.write().unwrap();
+        if tables.contains_key(&config.table_id) {
+            return Err(Error::TableExists(config.table_id));
         }
 
-        Restaurant { tables: tables }
+        self.log(Op::Table {
+            table_id: config.table_id,
+            table: config.clone(),
+        })?;
+        let table = Table::new(config.clone());
+        tables.insert(config.table_id, Arc::new(Mutex::new(table)));
+        Ok(config)
     }
 
-    pub fn get_table(&self, table_id: u32) -> TablePtr {
-        Arc::clone(&self.tables[table_id as usize])
+    /// Changes a table, moving it to a new id if the update says so.
+    pub fn update_table(&self, table_id: u32, update: TableUpdate) -> Result<TableConfig, Error> {
+        let mut tables = self.tables.write().unwrap();
+        let table = tables
+            .get(&table_id)
+            .cloned()
+            .ok_or(Error::TableNotFound(table_id))?;
+        let mut t = table.lock().unwrap();
+
+        let config = update.apply(t.config())?;
+        if config.table_id != table_id {
+            if tables.contains_key(&config.table_id) {
+                return Err(Error::TableExists(config.table_id));
+            }
+            // bookings refer to the table by id as well
+            if self.reservations.lock().unwrap().holds_table(table_id) {
+                return Err(Error::TableInUse(table_id));
+            }
+        }
+        undo_on_error(&mut *t, |t| {
+            t.set_config(config.clone())?;
+            self.log(Op::Table {
+                table_id,
+                table: config.clone(),
+            })
+        })?;
+        tables.remove(&table_id);
+        tables.insert(config.table_id, Arc::clone(&table));
+        Ok(config)
     }
+
+    /// Replays a table change from the log.
+    pub fn restore_table(&self, table_id: u32, config: TableConfig) -> Result<(), Error> {
+        let mut tables = self.tables.write().unwrap();
+        let table = match tables.remove(&table_id) {
+            Some(table) => {
+                table.lock().unwrap().set_config(config.clone())?;
+                table
+            }
+            None => Arc::new(Mutex::new(Table::new(config.clone()))),
+        };
+        tables.insert(config.table_id, table);
+        Ok(())
+    }
+
+    /// Makes a table change durable and then tells subscribers about it.
+    /// Call it while still holding the table lock, so the log and the
+    /// subscribers get the changes of each table in order, and as the last
+    /// step of the change, see `undo_on_error`.
+    pub fn log(&self, op: Op) -> Result<(), Error> {
+        let events = Event::from_op(&op);
+        self.storage.append(op)?;
+        for event in events {
+            // there may be nobody listening, which is fine
+            let _ = self.events.send(event);
+        }
+        Ok(())
+    }
+
+    /// Starts listening to the changes logged from now on, see `events`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Writes the menu, the layout, all tables, bills and queues to a snapshot. Every table is
+    /// locked, in id order, for the duration.
+    pub fn snapshot(&self) -> Result<(), Error> {
+        // taken before the tables, as every request does
+        let menu = self.menu.read().unwrap();
+        let layout = self.tables.read().unwrap();
+        let tables = layout
+            .values()
+            .map(|t| t.lock().unwrap())
+            .collect::<Vec<_>>();
+        let items = tables
+            .iter()
+            .flat_map(|t| t.lines().cloned())
+            .collect::<Vec<_>>();
+        let bills = self.bills.lock().unwrap().list().cloned().collect();
+        let reservations = self.reservations.lock().unwrap().list().cloned().collect();
+        let waitlist = self.waitlist.lock().unwrap().list().cloned().collect();
+        // held until the snapshot is written, so no key is logged meanwhile
+        let mut keys = self.idempotency.lock().unwrap();
+        keys.expire(self.now());
+
+        self.storage.snapshot(State {
+            tables: tables.iter().map(|t| t.config().clone()).collect(),
+            menu: menu.list().into_iter().cloned().collect(),
+            items,
+            parties: tables.iter().filter_map(|t| t.party().cloned()).collect(),
+            groups: tables.iter().filter_map(|t| t.group()).collect(),
+            bills,
+            reservations,
+            waitlist,
+            versions: tables
+                .iter()
+                .filter(|t| t.version() > 0)
+                .map(|t| TableVersion {
+                    table_id: t.id(),
+                    version: t.version(),
+                })
+                .collect(),
+            idempotency: keys.list().into_iter().cloned().collect(),
+        })
+    }
+
+    /// Runs `f` with the tables `table_ids` all locked, handing them over
+    /// in the order asked for. They are locked in id order, so calls on the
+    /// same tables cannot deadlock whichever way round they name them.
+    pub fn with_tables<T>(
+        &self,
+        table_ids: &[u32],
+        f: impl FnOnce(&mut [&mut Table]) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut by_id = table_ids
+            .iter()
+            .map(|&tid| Ok((tid, self.get_table(tid)?)))
+            .collect::<Result<BTreeMap<_, _>, Error>>()?;
+        if by_id.len() != table_ids.len() {
+            return Err(Error::MalformedRequest(
+                "a table is named twice".to_string(),
+            ));
+        }
+
+        let mut guards = by_id
+            .iter_mut()
+            .map(|(&tid, t)| (tid, t.lock().unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        let mut tables = guards.values_mut().map(|t| &mut **t).collect::<Vec<_>>();
+        // back from id order to the order asked for
+        let mut ordered = Vec::with_capacity(tables.len());
+        for tid in table_ids {
+            let pos = tables.iter().position(|t| t.id() == *tid).unwrap();
+            ordered.push(tables.swap_remove(pos));
+        }
+
+        f(&mut ordered)
+    }
+
+    /// Runs `f` with the tables `from` and `to` both locked, see
+    /// `with_tables`.
+    pub fn with_table_pair<T>(
+        &self,
+        from: u32,
+        to: u32,
+        f: impl FnOnce(&mut Table, &mut Table) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if from == to {
+            return Err(Error::MalformedRequest(format!(
+                "table {} cannot take items from itself",
+                from
+            )));
+        }
+        self.with_tables(&[from, to], |tables| match tables {
+            [a, b] => f(a, b),
+            _ => unreachable!(),
+        })
+    }
+
+    /// The table holding the session `table_id` is part of: the table
+    /// itself, or the one it is pushed together with.
+    pub fn session_of(&self, table_id: u32) -> Result<TablePtr, Error> {
+        let session_id = self.get_table(table_id)?.lock().unwrap().session_id();
+        self.get_table(session_id)
+    }
+
+    /// Runs `f` with every table of the session `table_id` is part of
+    /// locked, the one holding the session first.
+    pub fn with_session<T>(
+        &self,
+        table_id: u32,
+        f: impl FnOnce(&mut [&mut Table]) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let group = {
+            let t = self.session_of(table_id)?;
+            let t = t.lock().unwrap();
+            [&[t.id()], t.members()].concat()
+        };
+
+        self.with_tables(&group, |tables| {
+            // the group may have changed before everything was locked
+            let lead = &tables[0];
+            if lead.session_id() != lead.id() || lead.members() != &group[1..] {
+                return Err(Error::TableMerged(table_id));
+            }
+            f(tables)
+        })
+    }
+
+    pub fn get_table(&self, table_id: u32) -> Result<TablePtr, Error> {
+        self.tables
+            .read()
+            .unwrap()
+            .get(&table_id)
+            .map(Arc::clone)
+            .ok_or(Error::TableNotFound(table_id))
+    }
+}
+
+/// Runs `change` on `state`, putting `state` back as it was if it fails. A
+/// change ends by logging itself, so one that could not be made durable
+/// leaves nothing behind in memory either.
+pub fn undo_on_error<S: Clone, T>(
+    state: &mut S,
+    change: impl FnOnce(&mut S) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let before = state.clone();
+    let result = change(state);
+    if result.is_err() {
+        *state = before;
+    }
+    result
+}
+
+/// `undo_on_error` for several locked tables at once.
+pub fn undo_on_error_tables<T>(
+    tables: &mut [&mut Table],
+    change: impl FnOnce(&mut [&mut Table]) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let before = tables.iter().map(|t| (**t).clone()).collect::<Vec<_>>();
+    let result = change(tables);
+    if result.is_err() {
+        for (t, before) in tables.iter_mut().zip(before) {
+            **t = before;
+        }
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::menu::MenuItem;
     use std::thread;
 
     #[test]
     fn test_restaurant_get_table() {
-        let r = Restaurant::new(10);
+        let r = Restaurant::new(Layout::sample(10), Menu::default());
 
         for test_id in 0..4 {
             let r2 = r.clone();
             thread::spawn(move || {
-                let t = r2.get_table(test_id);
+                let t = r2.get_table(test_id).unwrap();
                 let id = t.lock().unwrap().id();
 
                 assert_eq!(id, test_id)
@@ -49,7 +442,7 @@ mod tests {
 
     #[test]
     fn test_restaurant_get_table_then_do_something() {
-        let r = Restaurant::new(10);
+        let r = Restaurant::new(Layout::sample(10), Menu::default());
 
         let desire_table_id = 0;
 
@@ -60,9 +453,11 @@ mod tests {
         for test_val in 0..add_amount as u32 {
             let r2 = r.clone();
             let handle = thread::spawn(move || {
-                let t = r2.get_table(desire_table_id); // same table
+                let t = r2.get_table(desire_table_id).unwrap(); // same table
 
-                t.lock().unwrap().add_item(test_val);
+                t.lock()
+                    .unwrap()
+                    .add_item(&MenuItem::sample(test_val), None, 0);
             });
 
             handles.push(handle);
@@ -72,8 +467,42 @@ mod tests {
             handle.join().unwrap();
         }
 
-        let t = r.get_table(desire_table_id);
+        let t = r.get_table(desire_table_id).unwrap();
         let len = t.lock().unwrap().items_size();
         assert_eq!(len, add_amount);
     }
+
+    #[test]
+    fn test_restaurant_update_table() {
+        let r = Restaurant::new(Layout::sample(3), Menu::default());
+
+        assert_eq!(
+            r.add_table(TableConfig::sample(2)).err(),
+            Some(Error::TableExists(2))
+        );
+        r.add_table(TableConfig::sample(10)).unwrap();
+
+        let renumber = |to| TableUpdate {
+            table_id: Some(to),
+            ..Default::default()
+        };
+        assert_eq!(
+            r.update_table(0, renumber(1)).err(),
+            Some(Error::TableExists(1))
+        );
+        assert_eq!(r.update_table(0, renumber(20)).unwrap().table_id, 20);
+        assert_eq!(r.get_table(0).err(), Some(Error::TableNotFound(0)));
+        assert_eq!(r.get_table(20).unwrap().lock().unwrap().id(), 20);
+
+        let ids = r.layout().iter().map(|t| t.table_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 10, 20]);
+    }
+
+    #[test]
+    fn test_restaurant_get_unknown_table() {
+        let r = Restaurant::new(Layout::sample(10), Menu::default());
+
+        assert!(r.get_table(9).is_ok());
+        assert_eq!(r.get_table(10).err(), Some(Error::TableNotFound(10)));
+    }
 }
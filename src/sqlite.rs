@@ -0,0 +1,222 @@
+//! Storage backend keeping the tables in an embedded SQLite database.
+//!
+//! Changes go to the `log` table, one JSON row each. A snapshot replaces
+//! every state table and empties `log` in one transaction. SQLite takes
+//! care of torn writes.
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::error::Error;
+use super::storage::{Op, Recovery, State, Storage};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = FULL;
+    CREATE TABLE IF NOT EXISTS layout (
+        table_id INTEGER PRIMARY KEY,
+        config TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS menu (
+        item_id INTEGER PRIMARY KEY,
+        item TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS lines (
+        line_id INTEGER PRIMARY KEY,
+        table_id INTEGER NOT NULL,
+        item TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS parties (
+        table_id INTEGER PRIMARY KEY,
+        party TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS merges (
+        table_id INTEGER PRIMARY KEY,
+        merge TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS bills (
+        bill_id INTEGER PRIMARY KEY,
+        table_id INTEGER NOT NULL,
+        bill TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS reservations (
+        reservation_id INTEGER PRIMARY KEY,
+        reservation TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS waitlist (
+        entry_id INTEGER PRIMARY KEY,
+        entry TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS versions (
+        table_id INTEGER PRIMARY KEY,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS idempotency (
+        key TEXT PRIMARY KEY,
+        response TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS log (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        op TEXT NOT NULL
+    );
+";
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed, and reads back
+    /// everything stored so far.
+    pub fn open(path: &Path) -> Result<(SqliteStorage, Recovery), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::Storage(format!("{}: {}", dir.display(), e)))?;
+        }
+        let conn = Connection::open(path).map_err(db_error)?;
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+
+        let recovery = Recovery {
+            state: State {
+                tables: read_json(&conn, "SELECT config FROM layout ORDER BY table_id")?,
+                menu: read_json(&conn, "SELECT item FROM menu ORDER BY item_id")?,
+                items: read_json(&conn, "SELECT item FROM lines ORDER BY line_id")?,
+                parties: read_json(&conn, "SELECT party FROM parties ORDER BY table_id")?,
+                groups: read_json(&conn, "SELECT merge FROM merges ORDER BY table_id")?,
+                bills: read_json(&conn, "SELECT bill FROM bills ORDER BY bill_id")?,
+                reservations: read_json(
+                    &conn,
+                    "SELECT reservation FROM reservations ORDER BY reservation_id",
+                )?,
+                waitlist: read_json(&conn, "SELECT entry FROM waitlist ORDER BY entry_id")?,
+                versions: read_json(&conn, "SELECT version FROM versions ORDER BY table_id")?,
+                idempotency: read_json(&conn, "SELECT response FROM idempotency ORDER BY rowid")?,
+            },
+            ops: read_json(&conn, "SELECT op FROM log ORDER BY seq")?,
+        };
+        let storage = SqliteStorage {
+            conn: Mutex::new(conn),
+        };
+        Ok((storage, recovery))
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn append(&self, op: Op) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO log (op) VALUES (?1)",
+            params![serde_json::to_string(&op).unwrap()],
+        )
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn snapshot(&self, state: State) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(db_error)?;
+
+        replace_rows(&tx, "layout", &state.tables, |t| vec![t.table_id.into()])?;
+        replace_rows(&tx, "menu", &state.menu, |i| vec![i.item_id.into()])?;
+        replace_rows(&tx, "lines", &state.items, |i| {
+            vec![i.line_id().into(), i.table_id().into()]
+        })?;
+        replace_rows(&tx, "parties", &state.parties, |p| vec![p.table_id.into()])?;
+        replace_rows(&tx, "merges", &state.groups, |g| vec![g.table_id.into()])?;
+        replace_rows(&tx, "versions", &state.versions, |v| {
+            vec![v.table_id.into()]
+        })?;
+        replace_rows(&tx, "bills", &state.bills, |b| {
+            vec![b.id().into(), b.table_id().into()]
+        })?;
+        replace_rows(&tx, "reservations", &state.reservations, |r| {
+            vec![r.reservation_id.into()]
+        })?;
+        replace_rows(&tx, "waitlist", &state.waitlist, |e| {
+            vec![e.entry_id.into()]
+        })?;
+        replace_rows(&tx, "idempotency", &state.idempotency, |r| {
+            vec![r.key.clone().into()]
+        })?;
+        tx.execute("DELETE FROM log", []).map_err(db_error)?;
+
+        tx.commit().map_err(db_error)
+    }
+}
+
+fn db_error(e: rusqlite::Error) -> Error {
+    Error::Storage(e.to_string())
+}
+
+/// Empties `table` and writes `rows` to it, each as the keys `keys` picks
+/// from it followed by its JSON, in the order the schema has the columns.
+fn replace_rows<T: Serialize>(
+    tx: &Transaction<'_>,
+    table: &str,
+    rows: &[T],
+    keys: impl Fn(&T) -> Vec<Value>,
+) -> Result<(), Error> {
+    tx.execute(&format!("DELETE FROM {}", table), [])
+        .map_err(db_error)?;
+    for row in rows {
+        let mut values = keys(row);
+        values.push(serde_json::to_string(row).unwrap().into());
+        let slots = vec!["?"; values.len()].join(", ");
+        tx.prepare_cached(&format!("INSERT INTO {} VALUES ({})", table, slots))
+            .and_then(|mut insert| insert.execute(params_from_iter(values)))
+            .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+/// Decodes the single JSON column of every row `sql` returns.
+fn read_json<T: DeserializeOwned>(conn: &Connection, sql: &str) -> Result<Vec<T>, Error> {
+    let mut stmt = conn.prepare(sql).map_err(db_error)?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(db_error)?;
+
+    rows.map(|row| {
+        let json = row.map_err(db_error)?;
+        serde_json::from_str(&json).map_err(|e| Error::Storage(e.to_string()))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::Item;
+    use crate::menu::MenuItem;
+    use crate::storage::TestDir;
+
+    fn item(line_id: u32) -> Item {
+        Item::new(line_id, &MenuItem::sample(1), 0, 5, 0)
+    }
+
+    #[test]
+    fn test_sqlite_reopen() {
+        let dir = TestDir::new("sqlite");
+        let path = dir.0.join("test.db");
+
+        let (db, recovery) = SqliteStorage::open(&path).unwrap();
+        assert!(recovery.state.items.is_empty() && recovery.ops.is_empty());
+        db.append(Op::Add { item: item(0) }).unwrap();
+        db.snapshot(State {
+            items: vec![item(0)],
+            ..Default::default()
+        })
+        .unwrap();
+        db.append(Op::Add { item: item(1) }).unwrap();
+        drop(db);
+
+        let (_, recovery) = SqliteStorage::open(&path).unwrap();
+        assert_eq!(recovery.state.items, vec![item(0)]);
+        assert_eq!(recovery.ops, vec![Op::Add { item: item(1) }]);
+    }
+}
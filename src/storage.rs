@@ -0,0 +1,504 @@
+//! Where table changes are kept so they survive a restart.
+//!
+//! The tables always live in memory. A `Storage` backend records every
+//! change to them as an `Op`, and at startup hands back a `Recovery` to
+//! rebuild them from: the lines of the last snapshot plus the changes made
+//! since. `restore` then replays it the same way for every backend.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::bill::Bill;
+use super::error::Error;
+use super::idempotency::KeptResponse;
+use super::item::{reserve_line_ids, Item, ItemState};
+use super::kitchen::Ticket;
+use super::layout::{Layout, TableConfig};
+use super::menu::{Menu, MenuItem};
+use super::reservation::Reservation;
+use super::restaurant::Restaurant;
+use super::table::{self, Party, TableGroup, TableVersion};
+use super::waitlist::WaitlistEntry;
+use super::wal::WalStorage;
+
+#[cfg(feature = "sqlite")]
+use super::sqlite::SqliteStorage;
+
+/// One change to a table.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Op {
+    Add {
+        item: Item,
+    },
+    /// A line was changed before the kitchen started on it. It replaces
+    /// the line as logged before.
+    Update {
+        item: Item,
+    },
+    SetState {
+        table_id: u32,
+        item_id: u32,
+        line_id: u32,
+        state: ItemState,
+        at: u64,
+    },
+    /// The table was closed and its session archived in `bill`.
+    Checkout {
+        bill: Bill,
+    },
+    /// A menu entry was added, changed or retired.
+    Menu {
+        item: MenuItem,
+    },
+    /// Table `table_id` was added or changed, and may have a new id now.
+    Table {
+        table_id: u32,
+        table: TableConfig,
+    },
+    Seat {
+        party: Party,
+    },
+    Unseat {
+        table_id: u32,
+    },
+    /// A booking was made or changed.
+    Reservation {
+        reservation: Reservation,
+    },
+    /// Lines moved from one table to another.
+    Transfer {
+        from: u32,
+        to: u32,
+        line_ids: Vec<u32>,
+    },
+    /// The tables were pushed together, into the first one.
+    Merge {
+        table_ids: Vec<u32>,
+    },
+    /// The group held by `table_id` was taken apart, moving lines to the
+    /// tables they map to.
+    Split {
+        table_id: u32,
+        lines: BTreeMap<u32, u32>,
+    },
+    /// A walk-in joined the waitlist or left it.
+    Waitlist {
+        entry: WaitlistEntry,
+    },
+    /// Changes made in one go, which come back all together or not at all.
+    Batch {
+        ops: Vec<Op>,
+    },
+    /// The response to a change sent with an idempotency key, logged right
+    /// after the change itself.
+    Idempotency {
+        response: KeptResponse,
+    },
+}
+
+impl Op {
+    /// Records that `item` has just moved to its current state.
+    pub fn set_state(item: &Item, at: u64) -> Op {
+        Op::SetState {
+            table_id: item.table_id(),
+            item_id: item.id(),
+            line_id: item.line_id(),
+            state: item.state(),
+            at,
+        }
+    }
+}
+
+/// Everything a snapshot holds.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    /// The floor plan. Empty in snapshots taken before it was kept, then
+    /// the layout file is used.
+    #[serde(default)]
+    pub tables: Vec<TableConfig>,
+    /// The menu, retired entries included. Empty in snapshots taken before
+    /// it was kept, then the menu file is used.
+    #[serde(default)]
+    pub menu: Vec<MenuItem>,
+    /// The lines on every table.
+    pub items: Vec<Item>,
+    /// The parties seated at the tables.
+    #[serde(default)]
+    pub parties: Vec<Party>,
+    /// Every bill closed so far.
+    #[serde(default)]
+    pub bills: Vec<Bill>,
+    /// Tables pushed together, whose lines are all on the first one.
+    #[serde(default)]
+    pub groups: Vec<TableGroup>,
+    #[serde(default)]
+    pub reservations: Vec<Reservation>,
+    #[serde(default)]
+    pub waitlist: Vec<WaitlistEntry>,
+    /// The version of every table that has changed at all.
+    #[serde(default)]
+    pub versions: Vec<TableVersion>,
+    /// The responses kept for idempotency keys still within the window.
+    #[serde(default)]
+    pub idempotency: Vec<KeptResponse>,
+}
+
+/// What a backend found at startup: the last snapshot and the changes
+/// since.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    pub state: State,
+    pub ops: Vec<Op>,
+}
+
+pub trait Storage: Send + Sync {
+    /// Makes `op` durable before returning.
+    fn append(&self, op: Op) -> Result<(), Error>;
+
+    /// Replaces everything stored with `state`. The caller has to make sure
+    /// nothing changes meanwhile.
+    fn snapshot(&self, state: State) -> Result<(), Error>;
+}
+
+/// Keeps nothing, every order is gone after a restart.
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn append(&self, _op: Op) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn snapshot(&self, _state: State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StorageKind {
+    Memory,
+    Wal,
+    Sqlite,
+}
+
+impl StorageKind {
+    /// Every backend this build supports.
+    #[cfg(test)]
+    pub fn all() -> Vec<StorageKind> {
+        let mut kinds = vec![StorageKind::Memory, StorageKind::Wal];
+        if cfg!(feature = "sqlite") {
+            kinds.push(StorageKind::Sqlite);
+        }
+        kinds
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<StorageKind, ()> {
+        match s {
+            "memory" => Ok(StorageKind::Memory),
+            "wal" => Ok(StorageKind::Wal),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            StorageKind::Memory => "memory",
+            StorageKind::Wal => "wal",
+            StorageKind::Sqlite => "sqlite",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Opens the backend `kind`, keeping its files in `dir`.
+pub fn open(kind: StorageKind, dir: &Path) -> Result<(Arc<dyn Storage>, Recovery), Error> {
+    match kind {
+        StorageKind::Memory => Ok((Arc::new(MemoryStorage), Recovery::default())),
+        StorageKind::Wal => {
+            let (storage, recovery) = WalStorage::open(dir)?;
+            Ok((Arc::new(storage), recovery))
+        }
+        #[cfg(feature = "sqlite")]
+        StorageKind::Sqlite => {
+            let (storage, recovery) = SqliteStorage::open(&dir.join("restaurant.db"))?;
+            Ok((Arc::new(storage), recovery))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        StorageKind::Sqlite => Err(Error::Config(
+            "built without the sqlite feature".to_string(),
+        )),
+    }
+}
+
+/// Rebuilds the tables from `recovery` and hands unfinished lines back to
+/// the kitchen.
+pub fn restore(restaurant: &Restaurant, recovery: Recovery) -> Result<(), Error> {
+    if !recovery.state.tables.is_empty() {
+        restaurant.set_layout(Layout::new(recovery.state.tables)?);
+    }
+    if !recovery.state.menu.is_empty() {
+        *restaurant.menu().write().unwrap() = Menu::restored(recovery.state.menu);
+    }
+    for item in recovery.state.items {
+        restore_item(restaurant, item)?;
+    }
+    for party in recovery.state.parties {
+        let t = restaurant.get_table(party.table_id)?;
+        t.lock().unwrap().restore_party(party);
+    }
+    for group in recovery.state.groups {
+        for &tid in [&[group.table_id], &group.members[..]].concat().iter() {
+            let t = restaurant.get_table(tid)?;
+            t.lock()
+                .unwrap()
+                .restore_group(group.table_id, &group.members);
+        }
+    }
+    // what restoring the tables did to their versions does not count
+    for version in recovery.state.versions {
+        let t = restaurant.get_table(version.table_id)?;
+        t.lock().unwrap().restore_version(version.version);
+    }
+    for bill in recovery.state.bills {
+        restaurant.bills().lock().unwrap().insert(bill);
+    }
+    for reservation in recovery.state.reservations {
+        restaurant
+            .reservations()
+            .lock()
+            .unwrap()
+            .insert(reservation);
+    }
+    for entry in recovery.state.waitlist {
+        restaurant.waitlist().lock().unwrap().insert(entry);
+    }
+    for response in recovery.state.idempotency {
+        restaurant.idempotency().lock().unwrap().finish(response);
+    }
+    for op in recovery.ops {
+        replay(restaurant, op)?;
+    }
+
+    requeue(restaurant)
+}
+
+/// Applies one logged change again.
+fn replay(restaurant: &Restaurant, op: Op) -> Result<(), Error> {
+    match op {
+        Op::Add { item } | Op::Update { item } => restore_item(restaurant, item)?,
+        Op::SetState {
+            table_id,
+            item_id,
+            line_id,
+            state,
+            at,
+        } => {
+            let t = restaurant.get_table(table_id)?;
+            let mut t = t.lock().unwrap();
+            t.set_state(item_id, line_id, state, at)?;
+        }
+        Op::Checkout { bill } => {
+            restaurant.with_session(bill.table_id(), |tables| {
+                tables.iter_mut().for_each(|t| t.clear());
+                Ok(())
+            })?;
+            restaurant.bills().lock().unwrap().insert(bill);
+        }
+        Op::Menu { item } => restaurant.menu().write().unwrap().restore(item),
+        Op::Table { table_id, table } => restaurant.restore_table(table_id, table)?,
+        Op::Seat { party } => {
+            let t = restaurant.get_table(party.table_id)?;
+            t.lock().unwrap().restore_party(party);
+        }
+        Op::Unseat { table_id } => {
+            let t = restaurant.get_table(table_id)?;
+            t.lock().unwrap().unseat()?;
+        }
+        Op::Reservation { reservation } => {
+            restaurant
+                .reservations()
+                .lock()
+                .unwrap()
+                .insert(reservation);
+        }
+        Op::Transfer { from, to, line_ids } => {
+            restaurant.with_table_pair(from, to, |src, dst| {
+                dst.put_lines(src.take_lines(&line_ids));
+                Ok(())
+            })?
+        }
+        Op::Merge { table_ids } => {
+            restaurant.with_tables(&table_ids, |tables| table::merge(tables).map(drop))?
+        }
+        Op::Split { table_id, lines } => {
+            restaurant.with_session(table_id, |tables| table::split(tables, &lines).map(drop))?
+        }
+        Op::Waitlist { entry } => restaurant.waitlist().lock().unwrap().insert(entry),
+        Op::Batch { ops } => {
+            for op in ops {
+                replay(restaurant, op)?;
+            }
+        }
+        Op::Idempotency { response } => restaurant.idempotency().lock().unwrap().finish(response),
+    }
+
+    Ok(())
+}
+
+fn restore_item(restaurant: &Restaurant, item: Item) -> Result<(), Error> {
+    reserve_line_ids(item.line_id());
+    let t = restaurant.get_table(item.table_id())?;
+    t.lock().unwrap().restore(item);
+    Ok(())
+}
+
+/// Queues every line that is not ready yet, the ones that were being cooked
+/// first and the rest in the order they came in.
+fn requeue(restaurant: &Restaurant) -> Result<(), Error> {
+    let now = restaurant.now();
+
+    let mut pending = vec![];
+    for t in restaurant.tables() {
+        let t = t.lock().unwrap();
+        for i in t.lines() {
+            if matches!(i.state(), ItemState::Ordered | ItemState::Cooking) {
+                pending.push((i.state() != ItemState::Cooking, i.line_id(), i.clone()));
+            }
+        }
+    }
+    pending.sort_by_key(|(waiting, line_id, _)| (*waiting, *line_id));
+
+    for (_, _, item) in pending {
+        let t = restaurant.get_table(item.table_id())?;
+        let mut t = t.lock().unwrap();
+        let ready_at = restaurant
+            .kitchen()
+            .lock()
+            .unwrap()
+            .enqueue(Ticket::new(&item), now);
+        t.set_ready_at(item.line_id(), ready_at)?;
+    }
+
+    Ok(())
+}
+
+/// Takes a snapshot every `interval`.
+pub async fn run(restaurant: Restaurant, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // the first tick fires at once, right after the startup snapshot
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = restaurant.snapshot() {
+            println!("snapshot failed: {}", e);
+        }
+    }
+}
+
+/// A scratch directory, removed again when dropped.
+#[cfg(test)]
+pub struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "restaurant-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        TestDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Keeps nothing, and refuses every change once `failing` is set, like a
+/// disk that has filled up.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FailingStorage {
+    pub failing: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl Storage for FailingStorage {
+    fn append(&self, _op: Op) -> Result<(), Error> {
+        if self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(Error::Storage("disk full".to_string()));
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self, _state: State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::menu::{Menu, MenuItem};
+
+    #[test]
+    fn test_storage_kind() {
+        assert_eq!("sqlite".parse(), Ok(StorageKind::Sqlite));
+        assert_eq!("disk".parse::<StorageKind>(), Err(()));
+        assert_eq!(StorageKind::Wal.to_string(), "wal");
+    }
+
+    #[test]
+    fn test_storage_restore() {
+        let r = Restaurant::new(Layout::sample(2), Menu::sample(2));
+        let served = Item::new(7, &MenuItem::sample(1), 1, 5, 0);
+        let mut ops = vec![Op::Add {
+            item: Item::new(8, &MenuItem::sample(0), 1, 5, 0),
+        }];
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            ops.push(Op::SetState {
+                table_id: 1,
+                item_id: 1,
+                line_id: 7,
+                state,
+                at: 1,
+            });
+        }
+        let recovery = Recovery {
+            state: State {
+                items: vec![served],
+                ..Default::default()
+            },
+            ops,
+        };
+
+        restore(&r, recovery).unwrap();
+        let t = r.get_table(1).unwrap();
+        let t = t.lock().unwrap();
+        assert_eq!(t.check_line(1, 7).unwrap().state(), ItemState::Served);
+        assert_eq!(t.check_line(0, 8).unwrap().state(), ItemState::Ordered);
+        // only the line still to cook goes back to the kitchen
+        assert_eq!(r.kitchen().lock().unwrap().queue(0).len(), 1);
+    }
+}
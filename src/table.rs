@@ -1,127 +1,965 @@
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-use super::item::Item;
+use super::error::Error;
+use super::item::{next_line_id, Item, ItemState, ItemView, LineSpec};
+use super::layout::TableConfig;
+use super::menu::MenuItem;
 
+#[derive(Clone)]
 pub struct Table {
-    table_id: u32,
-    items: HashMap<u32, Item>,
+    config: TableConfig,
+    // keyed by order-line id, so each serving of the same menu item is kept
+    items: BTreeMap<u32, Item>,
+    party: Option<Party>,
+    // the table this one is pushed together with, which takes its orders
+    joined: Option<u32>,
+    // the tables pushed together with this one
+    members: Vec<u32>,
+    // bumped on every change to the orders, party or group, see `version`
+    version: u64,
     rng: StdRng,
 }
 
+/// The guests currently seated at a table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Party {
+    pub table_id: u32,
+    pub size: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    pub seated_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableStatus {
+    Free,
+    Occupied,
+    /// Everything ordered has been served.
+    AwaitingCheckout,
+}
+
+/// One table on the floor overview.
+#[derive(Serialize)]
+pub struct FloorView {
+    #[serde(flatten)]
+    config: TableConfig,
+    status: TableStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    party: Option<Party>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged_into: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    members: Vec<u32>,
+}
+
+/// A table as reported to clients at a given moment.
+#[derive(Serialize)]
+pub struct TableView<'a> {
+    table_id: u32,
+    version: u64,
+    /// The tables pushed together with this one, sharing its order.
+    #[serde(skip_serializing_if = "<[u32]>::is_empty")]
+    members: &'a [u32],
+    items: Vec<ItemView<'a>>,
+}
+
+/// The version a table is at, kept in snapshots.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableVersion {
+    pub table_id: u32,
+    pub version: u64,
+}
+
+/// Tables pushed together into one session, kept in snapshots.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableGroup {
+    /// The table holding the session's orders and combined party.
+    pub table_id: u32,
+    pub members: Vec<u32>,
+}
+
 impl Table {
-    pub fn new(tid: u32) -> Table {
+    pub fn new(config: TableConfig) -> Table {
         Table {
-            table_id: tid,
-            items: HashMap::new(),
+            config,
+            items: BTreeMap::new(),
+            party: None,
+            joined: None,
+            members: vec![],
+            version: 0,
             rng: StdRng::from_entropy(),
         }
     }
 
-    #[cfg(test)]
     pub fn id(&self) -> u32 {
-        self.table_id
+        self.config.table_id
+    }
+
+    pub fn config(&self) -> &TableConfig {
+        &self.config
+    }
+
+    /// Goes up with every change to the table, so a client can tell whether
+    /// it has seen the latest. Ready time estimates do not count.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Refuses a change based on another version than the current one.
+    pub fn check_version(&self, expected: Option<u64>) -> Result<(), Error> {
+        match expected {
+            Some(expected) if expected != self.version => Err(Error::VersionMismatch {
+                table_id: self.id(),
+                expected,
+                version: self.version,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the version read from storage.
+    pub fn restore_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    fn touch(&mut self) {
+        self.version += 1;
+    }
+
+    /// Replaces the table's place on the floor plan. Renumbering is refused
+    /// while the table is in use, as every line and the party carry its
+    /// table id.
+    pub fn set_config(&mut self, config: TableConfig) -> Result<(), Error> {
+        let in_use = !self.items.is_empty() || self.party.is_some() || self.is_merged();
+        if config.table_id != self.id() && in_use {
+            return Err(Error::TableInUse(self.id()));
+        }
+        self.config = config;
+        self.touch();
+        Ok(())
+    }
+
+    /// Refuses new orders on a disabled table, one nobody is seated at or
+    /// one whose orders go to the table it is pushed together with.
+    pub fn check_open(&self) -> Result<(), Error> {
+        if self.config.disabled {
+            return Err(Error::TableDisabled(self.id()));
+        }
+        if self.joined.is_some() {
+            return Err(Error::TableMerged(self.id()));
+        }
+        if self.party.is_none() {
+            return Err(Error::TableNotSeated(self.id()));
+        }
+        Ok(())
+    }
+
+    pub fn party(&self) -> Option<&Party> {
+        self.party.as_ref()
+    }
+
+    /// Seats a party of `size` at the free table.
+    pub fn seat(&mut self, size: u32, server: Option<String>, now: u64) -> Result<&Party, Error> {
+        if self.config.disabled {
+            return Err(Error::TableDisabled(self.id()));
+        }
+        if self.party.is_some() || !self.items.is_empty() || self.joined.is_some() {
+            return Err(Error::TableOccupied(self.id()));
+        }
+        if size == 0 {
+            return Err(Error::MalformedBody(
+                "party size must be positive".to_string(),
+            ));
+        }
+        if size > self.config.capacity {
+            return Err(Error::PartyTooLarge {
+                table_id: self.id(),
+                size,
+            });
+        }
+
+        self.touch();
+        Ok(self.party.insert(Party {
+            table_id: self.id(),
+            size,
+            server,
+            seated_at: now,
+        }))
+    }
+
+    /// Frees the table again. A party that ordered leaves through checkout
+    /// instead.
+    pub fn unseat(&mut self) -> Result<Party, Error> {
+        if self.is_merged() {
+            return Err(Error::TableMerged(self.id()));
+        }
+        if !self.items.is_empty() {
+            return Err(Error::TableInUse(self.id()));
+        }
+        let party = self.party.take().ok_or(Error::TableNotSeated(self.id()))?;
+        self.touch();
+        Ok(party)
+    }
+
+    /// Puts back a party recovered from storage.
+    pub fn restore_party(&mut self, party: Party) {
+        self.party = Some(party);
+        self.touch();
+    }
+
+    pub fn status(&self) -> TableStatus {
+        let mut active = self.active_items().peekable();
+        if self.party.is_none() && self.items.is_empty() && self.joined.is_none() {
+            TableStatus::Free
+        } else if active.peek().is_some() && active.all(|i| i.state() == ItemState::Served) {
+            TableStatus::AwaitingCheckout
+        } else {
+            TableStatus::Occupied
+        }
+    }
+
+    pub fn floor(&self) -> FloorView {
+        FloorView {
+            config: self.config.clone(),
+            status: self.status(),
+            party: self.party.clone(),
+            merged_into: self.joined,
+            members: self.members.clone(),
+        }
+    }
+
+    /// The table whose session this one is part of, itself unless it was
+    /// pushed together with another.
+    pub fn session_id(&self) -> u32 {
+        self.joined.unwrap_or(self.id())
     }
 
+    pub fn members(&self) -> &[u32] {
+        &self.members
+    }
+
+    fn is_merged(&self) -> bool {
+        self.joined.is_some() || !self.members.is_empty()
+    }
+
+    /// The group this table holds the session of, if any.
+    pub fn group(&self) -> Option<TableGroup> {
+        (!self.members.is_empty()).then(|| TableGroup {
+            table_id: self.id(),
+            members: self.members.clone(),
+        })
+    }
+
+    /// Links a group read from storage back up. The lines and parties are
+    /// restored on their own.
+    pub fn restore_group(&mut self, lead: u32, members: &[u32]) {
+        if self.id() == lead {
+            self.members = members.to_vec();
+        } else {
+            self.joined = Some(lead);
+        }
+    }
+
+    /// Number of servings that are not cancelled.
     #[cfg(test)]
     pub fn items_size(&self) -> usize {
-        self.items.len()
+        self.active_items().count()
     }
 
-    pub fn add_item(&mut self, item_id: u32) {
-        let item = Item::new(item_id, self.table_id, self.rng.gen_range(5..15));
-        self.items.insert(item_id, item);
+    /// Every line on the table, cancelled ones included.
+    pub fn lines(&self) -> impl Iterator<Item = &Item> {
+        self.items.values()
     }
 
-    pub fn check_item(&self, item_id: u32) -> Option<&Item> {
-        self.items.get(&item_id)
+    fn active_items(&self) -> impl Iterator<Item = &Item> {
+        self.items
+            .values()
+            .filter(|i| i.state() != ItemState::Cancelled)
     }
 
-    pub fn remove_item(&mut self, item_id: u32) -> Option<Item> {
-        self.items.remove(&item_id)
+    /// Adds `menu_item`, ordered at `now` as `spec` says, and returns its
+    /// order-line id.
+    pub fn add_line(&mut self, menu_item: &MenuItem, spec: LineSpec, now: u64) -> u32 {
+        let line_id = next_line_id();
+        let prepare_time = self
+            .rng
+            .gen_range(menu_item.prep_time_min..=menu_item.prep_time_max);
+        let item =
+            Item::new(line_id, menu_item, self.id(), prepare_time, now).with_spec(menu_item, spec);
+        self.items.insert(line_id, item);
+        self.touch();
+
+        line_id
     }
 
-    pub fn print_item(&self, item_id: u32) -> String {
-        let item = self.check_item(item_id);
+    /// Adds one serving of `menu_item` ordered at `now`, for `seat` if
+    /// given, and returns its order-line id.
+    #[cfg(test)]
+    pub fn add_item(&mut self, menu_item: &MenuItem, seat: Option<u32>, now: u64) -> u32 {
+        let spec = LineSpec {
+            seat,
+            ..Default::default()
+        };
+        self.add_line(menu_item, spec, now)
+    }
 
-        match item {
-            Some(item) => {
-                return item.print();
+    /// Puts back a line read from storage as it was.
+    pub fn restore(&mut self, item: Item) {
+        self.items.insert(item.line_id(), item);
+        self.touch();
+    }
+
+    /// Swaps in a changed copy of one of the lines, see `Item::updated`.
+    pub fn replace(&mut self, item: Item) -> &Item {
+        let line_id = item.line_id();
+        self.items.insert(line_id, item);
+        self.touch();
+        &self.items[&line_id]
+    }
+
+    /// The lines to move to another table: the single serving `line_id` of
+    /// `item_id`, every serving of `item_id`, or everything on the table.
+    /// Cancelled lines go along as well.
+    pub fn pick_lines(
+        &self,
+        item_id: Option<u32>,
+        line_id: Option<u32>,
+    ) -> Result<Vec<u32>, Error> {
+        let lines = match (item_id, line_id) {
+            (Some(item_id), Some(line_id)) => {
+                let item = self
+                    .check_line(item_id, line_id)
+                    .ok_or(Error::LineNotFound(line_id))?;
+                vec![item.line_id()]
             }
-            None => {
-                return "{\"msg\": \"not found\"}".to_owned();
+            (Some(item_id), None) => {
+                let lines = self
+                    .lines()
+                    .filter(|i| i.id() == item_id)
+                    .map(|i| i.line_id())
+                    .collect::<Vec<_>>();
+                if lines.is_empty() {
+                    return Err(Error::ItemNotFound(item_id));
+                }
+                lines
             }
+            (None, Some(_)) => {
+                return Err(Error::MalformedBody(
+                    "a line_id needs its item_id".to_string(),
+                ))
+            }
+            (None, None) => self.items.keys().copied().collect(),
+        };
+        if lines.is_empty() {
+            return Err(Error::NothingToTransfer(self.id()));
         }
+
+        Ok(lines)
     }
 
-    pub fn print_items(&self) -> String {
-        let mut output = String::from("[");
+    /// Removes the lines `line_ids` from the table and hands them out.
+    pub fn take_lines(&mut self, line_ids: &[u32]) -> Vec<Item> {
+        let taken = line_ids
+            .iter()
+            .filter_map(|line_id| self.items.remove(line_id))
+            .collect::<Vec<_>>();
+        if !taken.is_empty() {
+            self.touch();
+        }
+        taken
+    }
 
-        for (_, item) in self.items.iter() {
-            let s = format!("{}", item.print());
-            output += &s;
-            output += ", ";
+    /// Takes over lines from another table, keeping their ids, order times
+    /// and history.
+    pub fn put_lines(&mut self, items: Vec<Item>) {
+        if items.is_empty() {
+            return;
+        }
+        for mut item in items {
+            item.move_to(self.id());
+            self.items.insert(item.line_id(), item);
         }
-        // pop the last ", "
-        output.pop();
-        output.pop();
+        self.touch();
+    }
 
-        output += "]";
+    /// All servings of `item_id` on this table that are not cancelled.
+    pub fn check_item(&self, item_id: u32) -> Vec<&Item> {
+        self.active_items().filter(|i| i.id() == item_id).collect()
+    }
+
+    /// The single serving `line_id`, if it is an order of `item_id`. Cancelled
+    /// lines are still found here.
+    pub fn check_line(&self, item_id: u32, line_id: u32) -> Option<&Item> {
+        self.items.get(&line_id).filter(|i| i.id() == item_id)
+    }
 
-        output
+    /// Moves the serving `line_id` of `item_id` to `state`.
+    pub fn set_state(
+        &mut self,
+        item_id: u32,
+        line_id: u32,
+        state: ItemState,
+        now: u64,
+    ) -> Result<&Item, Error> {
+        let item = self
+            .items
+            .get_mut(&line_id)
+            .filter(|i| i.id() == item_id)
+            .ok_or(Error::ItemNotFound(item_id))?;
+        item.transition(state, now)?;
+        self.touch();
+
+        Ok(&self.items[&line_id])
+    }
+
+    pub fn set_ready_at(&mut self, line_id: u32, ready_at: u64) -> Result<(), Error> {
+        let item = self
+            .items
+            .get_mut(&line_id)
+            .ok_or(Error::LineNotFound(line_id))?;
+        item.set_ready_at(ready_at);
+
+        Ok(())
+    }
+
+    /// The lines removing `item_id` cancels: the single serving `line_id`
+    /// if given, otherwise every serving that has not been served yet.
+    pub fn cancellable(&self, item_id: u32, line_id: Option<u32>) -> Result<Vec<u32>, Error> {
+        let lines = match line_id {
+            Some(line_id) => self
+                .check_line(item_id, line_id)
+                .map(|i| vec![(i.line_id(), i.state())])
+                .unwrap_or_default(),
+            None => self
+                .check_item(item_id)
+                .iter()
+                .map(|i| (i.line_id(), i.state()))
+                .collect(),
+        };
+        if lines.is_empty() {
+            return Err(Error::ItemNotFound(item_id));
+        }
+
+        let cancellable = lines
+            .iter()
+            .filter(|(_, state)| state.can_transition(ItemState::Cancelled))
+            .map(|(line_id, _)| *line_id)
+            .collect::<Vec<u32>>();
+        if cancellable.is_empty() {
+            // everything left has been served
+            let (line_id, from) = lines[0];
+            return Err(Error::InvalidTransition {
+                line_id,
+                from,
+                to: ItemState::Cancelled,
+            });
+        }
+
+        Ok(cancellable)
+    }
+
+    /// Cancels the lines `cancellable` picked, skipping any cancelled since.
+    /// Each line cancelled counts as a change of its own, the way each is
+    /// logged and replayed.
+    pub fn cancel_lines(&mut self, line_ids: &[u32], now: u64) -> Vec<&Item> {
+        for line_id in line_ids.iter() {
+            let cancelled = self
+                .items
+                .get_mut(line_id)
+                .is_some_and(|item| item.transition(ItemState::Cancelled, now).is_ok());
+            if cancelled {
+                self.touch();
+            }
+        }
+
+        line_ids.iter().filter_map(|l| self.items.get(l)).collect()
+    }
+
+    /// Cancels every serving of `item_id` that has not been served yet.
+    pub fn cancel_item(&mut self, item_id: u32, now: u64) -> Result<Vec<&Item>, Error> {
+        let lines = self.cancellable(item_id, None)?;
+        Ok(self.cancel_lines(&lines, now))
+    }
+
+    /// Cancels the single serving `line_id` of `item_id`.
+    pub fn cancel_line(&mut self, item_id: u32, line_id: u32, now: u64) -> Result<&Item, Error> {
+        self.set_state(item_id, line_id, ItemState::Cancelled, now)
+    }
+
+    /// Every line of the current session, once it can be billed: refused
+    /// while something is still to be served.
+    pub fn session(&self) -> Result<Vec<Item>, Error> {
+        if self.items.is_empty() {
+            return Err(Error::NothingToBill(self.id()));
+        }
+        if self.active_items().any(|i| i.state() != ItemState::Served) {
+            return Err(Error::ItemsPending(self.id()));
+        }
+
+        Ok(self.lines().cloned().collect())
+    }
+
+    /// Clears the table for the next party.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.party = None;
+        self.joined = None;
+        self.members.clear();
+        self.touch();
+    }
+
+    /// The table's current order, cancelled lines left out.
+    pub fn view(&self, now: u64) -> TableView<'_> {
+        TableView {
+            table_id: self.id(),
+            version: self.version,
+            members: &self.members,
+            items: self.active_items().map(|i| i.view(now)).collect(),
+        }
+    }
+
+    /// Every line ever ordered on the table, with its state history.
+    pub fn history(&self, now: u64) -> TableView<'_> {
+        TableView {
+            table_id: self.id(),
+            version: self.version,
+            members: &self.members,
+            items: self.lines().map(|i| i.view(now)).collect(),
+        }
     }
 }
 
+/// Pushes `tables[1..]` together with `tables[0]`, which takes over their
+/// orders and seats their guests as part of its party. Each table keeps its
+/// own party as well, to get it back on `split`. Returns the lines moved.
+pub fn merge(tables: &mut [&mut Table]) -> Result<Vec<u32>, Error> {
+    let (lead, members) = match tables.split_first_mut() {
+        Some((lead, members)) if !members.is_empty() => (lead, members),
+        _ => {
+            return Err(Error::MalformedBody(
+                "it takes two tables to merge".to_string(),
+            ))
+        }
+    };
+    lead.check_open()?;
+    if lead.is_merged() {
+        return Err(Error::TableMerged(lead.id()));
+    }
+    for m in members.iter() {
+        if m.config.disabled {
+            return Err(Error::TableDisabled(m.id()));
+        }
+        if m.is_merged() {
+            return Err(Error::TableMerged(m.id()));
+        }
+        if m.config.group.is_none() || m.config.group != lead.config.group {
+            return Err(Error::TablesApart {
+                table_id: lead.id(),
+                other: m.id(),
+            });
+        }
+    }
+
+    let mut moved = vec![];
+    for m in members.iter_mut() {
+        let lines = m.items.keys().copied().collect::<Vec<_>>();
+        lead.put_lines(m.take_lines(&lines));
+        moved.extend(lines);
+        if let (Some(party), Some(guests)) = (lead.party.as_mut(), m.party.as_ref()) {
+            party.size += guests.size;
+        }
+        m.joined = Some(lead.id());
+        m.touch();
+        lead.members.push(m.id());
+    }
+    lead.touch();
+
+    Ok(moved)
+}
+
+/// Takes the group held by `tables[0]` apart again, `tables[1..]` being
+/// its members. Each line in `lines` goes to the table it maps to, the rest
+/// stay where they are, and every table is left with its own party. Returns
+/// the lines moved, with the table each went to.
+pub fn split(
+    tables: &mut [&mut Table],
+    lines: &BTreeMap<u32, u32>,
+) -> Result<Vec<(u32, u32)>, Error> {
+    let (lead, members) = tables
+        .split_first_mut()
+        .expect("the group's own table comes first");
+    if members.is_empty() {
+        return Err(Error::TableNotMerged(lead.id()));
+    }
+    for (&line_id, &to) in lines.iter() {
+        if !lead.items.contains_key(&line_id) {
+            return Err(Error::LineNotFound(line_id));
+        }
+        if to == lead.id() {
+            continue;
+        }
+        let target = members
+            .iter()
+            .find(|m| m.id() == to)
+            .ok_or(Error::TableNotMerged(to))?;
+        // a table that came without guests has nobody to order for
+        if target.party.is_none() {
+            return Err(Error::TableNotSeated(to));
+        }
+    }
+
+    let mut moved = vec![];
+    for m in members.iter_mut() {
+        let picked = lines
+            .iter()
+            .filter(|(_, &to)| to == m.id())
+            .map(|(&line_id, _)| line_id)
+            .collect::<Vec<_>>();
+        m.put_lines(lead.take_lines(&picked));
+        moved.extend(picked.into_iter().map(|line_id| (line_id, m.id())));
+        if let (Some(party), Some(guests)) = (lead.party.as_mut(), m.party.as_ref()) {
+            party.size = party.size.saturating_sub(guests.size);
+        }
+        m.joined = None;
+        m.touch();
+    }
+    lead.members.clear();
+    lead.touch();
+
+    Ok(moved)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// A table with a party of two seated.
+    fn seated(table_id: u32) -> Table {
+        let mut t = Table::new(TableConfig::sample(table_id));
+        t.seat(2, None, 0).unwrap();
+        t
+    }
+
+    #[test]
+    fn test_table_seat() {
+        let mut t = Table::new(TableConfig::sample(1));
+        assert_eq!(t.status(), TableStatus::Free);
+        assert_eq!(t.check_open(), Err(Error::TableNotSeated(1)));
+        assert_eq!(
+            t.seat(5, None, 0).err(),
+            Some(Error::PartyTooLarge {
+                table_id: 1,
+                size: 5
+            })
+        );
+
+        let party = t.seat(3, Some("kim".to_string()), 10).unwrap();
+        assert_eq!(party.seated_at, 10);
+        assert_eq!(t.seat(1, None, 20).err(), Some(Error::TableOccupied(1)));
+        assert_eq!(t.status(), TableStatus::Occupied);
+
+        let line_id = t.add_item(&MenuItem::sample(1), None, 20);
+        assert_eq!(t.unseat(), Err(Error::TableInUse(1)));
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            t.set_state(1, line_id, state, 30).unwrap();
+        }
+        assert_eq!(t.status(), TableStatus::AwaitingCheckout);
+
+        t.clear();
+        assert_eq!(t.status(), TableStatus::Free);
+        assert_eq!(t.unseat(), Err(Error::TableNotSeated(1)));
+        t.seat(2, None, 40).unwrap();
+        assert_eq!(t.unseat().unwrap().size, 2);
+    }
+
+    #[test]
+    fn test_table_config() {
+        let mut t = seated(1);
+        assert_eq!(t.check_open(), Ok(()));
+
+        let mut config = TableConfig::sample(1);
+        config.disabled = true;
+        t.set_config(config).unwrap();
+        assert_eq!(t.check_open(), Err(Error::TableDisabled(1)));
+
+        t.add_item(&MenuItem::sample(1), None, 0);
+        assert_eq!(
+            t.set_config(TableConfig::sample(2)),
+            Err(Error::TableInUse(1))
+        );
+        t.clear();
+        t.set_config(TableConfig::sample(2)).unwrap();
+        assert_eq!(t.check_open(), Err(Error::TableNotSeated(2)));
+        assert_eq!(t.id(), 2);
+    }
+
     #[test]
     fn test_table_add_item() -> Result<(), String> {
         let table_id = 12;
 
-        let mut t = Table::new(table_id);
+        let mut t = Table::new(TableConfig::sample(table_id));
 
         let item_id = 4;
 
-        t.add_item(item_id);
+        let line_id = t.add_item(&MenuItem::sample(item_id), None, 0);
 
-        t.items.get(&item_id).unwrap();
+        assert_eq!(t.items.get(&line_id).unwrap().id(), item_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_add_same_item_twice() -> Result<(), String> {
+        let mut t = Table::new(TableConfig::sample(3));
+
+        let first = t.add_item(&MenuItem::sample(5), None, 0);
+        let second = t.add_item(&MenuItem::sample(5), None, 0);
+
+        assert_ne!(first, second);
+        assert_eq!(t.items_size(), 2);
+        assert_eq!(t.check_item(5).len(), 2);
 
         Ok(())
     }
 
     #[test]
     fn test_table_check_item() -> Result<(), String> {
-        let mut t = Table::new(1);
+        let mut t = Table::new(TableConfig::sample(1));
 
         let item_id = 7;
 
-        t.add_item(item_id);
-        let i = t.check_item(item_id).unwrap();
+        let line_id = t.add_item(&MenuItem::sample(item_id), None, 0);
+        let i = t.check_line(item_id, line_id).unwrap();
         assert_eq!(i.id(), item_id);
 
-        let i2 = t.check_item(123);
-        assert_eq!(i2, None);
+        assert!(t.check_line(item_id + 1, line_id).is_none());
+        assert!(t.check_item(123).is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_table_remove_item() -> Result<(), String> {
-        let mut t = Table::new(1);
+    fn test_table_cancel_item() -> Result<(), String> {
+        let mut t = Table::new(TableConfig::sample(1));
 
         let item_id = 11;
 
-        t.add_item(item_id);
+        t.add_item(&MenuItem::sample(item_id), None, 0);
+        t.add_item(&MenuItem::sample(item_id), None, 0);
 
-        let i = t.remove_item(item_id).unwrap();
-        assert_eq!(i.id(), item_id);
+        let cancelled = t.cancel_item(item_id, 1).unwrap();
+        assert_eq!(cancelled.len(), 2);
+        assert_eq!(cancelled[0].state(), ItemState::Cancelled);
+
+        assert_eq!(t.cancel_item(item_id, 2), Err(Error::ItemNotFound(item_id)));
+        assert_eq!(t.items_size(), 0);
+        assert_eq!(t.history(2).items.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_cancel_served_item() -> Result<(), String> {
+        let mut t = Table::new(TableConfig::sample(1));
+
+        let served = t.add_item(&MenuItem::sample(3), None, 0);
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            t.set_state(3, served, state, 1).unwrap();
+        }
+        assert!(matches!(
+            t.cancel_item(3, 2),
+            Err(Error::InvalidTransition { .. })
+        ));
+
+        // only the serving not yet served is cancelled
+        let pending = t.add_item(&MenuItem::sample(3), None, 2);
+        let cancelled = t.cancel_item(3, 3).unwrap();
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(cancelled[0].line_id(), pending);
+        assert_eq!(t.check_line(3, served).unwrap().state(), ItemState::Served);
 
-        let i2 = t.remove_item(item_id);
-        assert_eq!(i2, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_cancel_line() -> Result<(), String> {
+        let mut t = Table::new(TableConfig::sample(1));
+
+        let keep = t.add_item(&MenuItem::sample(11), None, 0);
+        let drop = t.add_item(&MenuItem::sample(11), None, 0);
+
+        let i = t.cancel_line(11, drop, 1).unwrap();
+        assert_eq!(i.line_id(), drop);
+        assert!(matches!(
+            t.cancel_line(11, drop, 2),
+            Err(Error::InvalidTransition { .. })
+        ));
+        assert_eq!(t.check_line(11, keep).unwrap().state(), ItemState::Ordered);
+        assert_eq!(
+            t.cancel_line(12, keep, 2).err(),
+            Some(Error::ItemNotFound(12))
+        );
 
         Ok(())
     }
+
+    #[test]
+    fn test_table_merge() {
+        let mut lead = seated(1);
+        let mut guests = seated(2);
+        let mut empty = Table::new(TableConfig::sample(3));
+        let line_id = guests.add_item(&MenuItem::sample(11), None, 0);
+
+        let mut apart = Table::new(TableConfig {
+            group: None,
+            ..TableConfig::sample(4)
+        });
+        assert_eq!(
+            merge(&mut [&mut lead, &mut apart]),
+            Err(Error::TablesApart {
+                table_id: 1,
+                other: 4
+            })
+        );
+        assert_eq!(
+            merge(&mut [&mut empty, &mut lead]),
+            Err(Error::TableNotSeated(3))
+        );
+
+        let moved = merge(&mut [&mut lead, &mut guests, &mut empty]).unwrap();
+        assert_eq!(moved, vec![line_id]);
+        assert_eq!(lead.check_line(11, line_id).unwrap().table_id(), 1);
+        assert_eq!(lead.party().unwrap().size, 4);
+        assert_eq!(lead.members(), &[2, 3]);
+        assert_eq!(guests.session_id(), 1);
+        assert_eq!(guests.check_open(), Err(Error::TableMerged(2)));
+        assert_eq!(empty.status(), TableStatus::Occupied);
+        assert!(empty.seat(2, None, 0).is_err());
+        assert_eq!(lead.unseat().err(), Some(Error::TableMerged(1)));
+        assert_eq!(
+            merge(&mut [&mut lead, &mut apart]),
+            Err(Error::TableMerged(1))
+        );
+
+        // nobody sat at the empty table to take the line
+        let to_empty = BTreeMap::from([(line_id, 3)]);
+        assert_eq!(
+            split(&mut [&mut lead, &mut guests, &mut empty], &to_empty),
+            Err(Error::TableNotSeated(3))
+        );
+        let back = BTreeMap::from([(line_id, 2)]);
+        let moved = split(&mut [&mut lead, &mut guests, &mut empty], &back).unwrap();
+        assert_eq!(moved, vec![(line_id, 2)]);
+        assert_eq!(guests.check_line(11, line_id).unwrap().table_id(), 2);
+        assert_eq!(lead.party().unwrap().size, 2);
+        assert_eq!(guests.party().unwrap().size, 2);
+        assert_eq!(empty.status(), TableStatus::Free);
+        assert!(lead.members().is_empty());
+        assert_eq!(
+            split(&mut [&mut lead], &BTreeMap::new()),
+            Err(Error::TableNotMerged(1))
+        );
+    }
+
+    #[test]
+    fn test_table_move_lines() {
+        let mut from = Table::new(TableConfig::sample(1));
+        let mut to = Table::new(TableConfig::sample(2));
+        let first = from.add_item(&MenuItem::sample(11), Some(3), 5);
+        let second = from.add_item(&MenuItem::sample(11), None, 6);
+        let other = from.add_item(&MenuItem::sample(12), None, 7);
+        from.cancel_line(11, second, 8).unwrap();
+
+        assert_eq!(from.pick_lines(Some(11), None), Ok(vec![first, second]));
+        assert_eq!(from.pick_lines(Some(12), Some(other)), Ok(vec![other]));
+        assert_eq!(
+            from.pick_lines(Some(12), Some(first)),
+            Err(Error::LineNotFound(first))
+        );
+        assert!(from.pick_lines(None, Some(first)).is_err());
+
+        let mut before = from.check_line(11, first).unwrap().clone();
+        let lines = from.pick_lines(Some(11), None).unwrap();
+        to.put_lines(from.take_lines(&lines));
+        // the same line, order time and history, only at another table
+        before.move_to(2);
+        assert_eq!(to.check_line(11, first), Some(&before));
+        assert_eq!(
+            to.check_line(11, second).unwrap().state(),
+            ItemState::Cancelled
+        );
+        assert_eq!(from.pick_lines(None, None), Ok(vec![other]));
+
+        from.take_lines(&[other]);
+        assert_eq!(
+            from.pick_lines(None, None),
+            Err(Error::NothingToTransfer(1))
+        );
+    }
+
+    #[test]
+    fn test_table_session() {
+        let mut t = Table::new(TableConfig::sample(1));
+        assert_eq!(t.session(), Err(Error::NothingToBill(1)));
+
+        let line_id = t.add_item(&MenuItem::sample(3), None, 0);
+        assert_eq!(t.session(), Err(Error::ItemsPending(1)));
+
+        for state in [ItemState::Cooking, ItemState::Ready, ItemState::Served] {
+            t.set_state(3, line_id, state, 1).unwrap();
+        }
+        t.add_item(&MenuItem::sample(4), None, 1);
+        t.cancel_item(4, 2).unwrap();
+
+        assert_eq!(t.session().unwrap().len(), 2);
+        t.clear();
+        assert!(t.lines().next().is_none());
+    }
+
+    #[test]
+    fn test_table_version() {
+        let mut t = Table::new(TableConfig::sample(1));
+        assert_eq!(t.version(), 0);
+        t.seat(2, None, 0).unwrap();
+        let line_id = t.add_item(&MenuItem::sample(2), None, 0);
+        assert_eq!(t.version(), 2);
+
+        // estimates and refused changes leave it alone
+        t.set_ready_at(line_id, 5).unwrap();
+        assert!(t.seat(2, None, 0).is_err());
+        assert!(t.cancel_item(7, 0).is_err());
+        assert_eq!(t.version(), 2);
+
+        t.cancel_item(2, 1).unwrap();
+        assert_eq!(t.version(), 3);
+        assert_eq!(t.check_version(Some(3)), Ok(()));
+        assert_eq!(t.check_version(None), Ok(()));
+        assert_eq!(
+            t.check_version(Some(2)),
+            Err(Error::VersionMismatch {
+                table_id: 1,
+                expected: 2,
+                version: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_table_serialize() {
+        let mut t = Table::new(TableConfig::sample(1));
+
+        assert_eq!(
+            serde_json::to_string(&t.view(0)).unwrap(),
+            "{\"table_id\":1,\"version\":0,\"items\":[]}"
+        );
+
+        t.add_item(&MenuItem::sample(2), None, 0);
+        let v = serde_json::to_value(t.view(0)).unwrap();
+        assert_eq!(v["items"][0]["item_id"], 2);
+        assert_eq!(v["items"][0]["status"], "pending");
+    }
 }
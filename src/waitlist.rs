@@ -0,0 +1,277 @@
+//! Walk-in parties waiting for a table, first come first served.
+//!
+//! Like `Reservations`, `Waitlist` never locks a table itself. Lock a table
+//! first if both are needed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::clock::MS_PER_MINUTE;
+use super::error::Error;
+
+/// How long a party is expected to stay when no bill tells yet.
+pub const DEFAULT_DINING_MS: u64 = 60 * MS_PER_MINUTE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitState {
+    Waiting,
+    Seated,
+    /// The party gave up and went.
+    Left,
+}
+
+impl fmt::Display for WaitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            WaitState::Waiting => "waiting",
+            WaitState::Seated => "seated",
+            WaitState::Left => "left",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// One party on the waitlist.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WaitlistEntry {
+    pub entry_id: u32,
+    pub party_size: u32,
+    /// Who to call when the table is ready, e.g. a name or phone number.
+    pub contact: String,
+    pub joined_at: u64,
+    /// The wait the party was told when joining.
+    pub quoted_minutes: u64,
+    pub state: WaitState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table_id: Option<u32>,
+    /// How long the party really waited, once seated or gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waited_minutes: Option<u64>,
+}
+
+/// Body of `POST /waitlist`.
+#[derive(Debug, Deserialize)]
+pub struct WaitlistRequest {
+    pub party_size: u32,
+    pub contact: String,
+}
+
+/// A table as far as the wait estimate is concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Occupancy {
+    pub capacity: u32,
+    /// When the party there sat down, `None` if the table is free.
+    pub seated_at: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct Waitlist {
+    next_id: u32,
+    entries: BTreeMap<u32, WaitlistEntry>,
+}
+
+impl Waitlist {
+    pub fn get(&self, entry_id: u32) -> Result<&WaitlistEntry, Error> {
+        self.entries
+            .get(&entry_id)
+            .ok_or(Error::WaitlistEntryNotFound(entry_id))
+    }
+
+    /// An entry that is still waiting.
+    pub fn waiting(&self, entry_id: u32) -> Result<&WaitlistEntry, Error> {
+        let entry = self.get(entry_id)?;
+        if entry.state != WaitState::Waiting {
+            return Err(Error::NotWaiting {
+                entry_id,
+                state: entry.state,
+            });
+        }
+        Ok(entry)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &WaitlistEntry> {
+        self.entries.values()
+    }
+
+    pub fn insert(&mut self, entry: WaitlistEntry) {
+        let entry_id = entry.entry_id;
+        self.next_id = self.next_id.max(entry_id + 1);
+        self.entries.insert(entry_id, entry);
+    }
+
+    /// Puts a party at the end of the line, quoting a wait from how long
+    /// the tables it fits at have been taken and how long parties usually
+    /// stay.
+    pub fn join(
+        &mut self,
+        req: WaitlistRequest,
+        tables: &[Occupancy],
+        dining_ms: u64,
+        now: u64,
+    ) -> Result<WaitlistEntry, Error> {
+        if req.party_size == 0 {
+            return Err(Error::MalformedBody(
+                "party size must be positive".to_string(),
+            ));
+        }
+        if req.contact.trim().is_empty() {
+            return Err(Error::MalformedBody("party needs a contact".to_string()));
+        }
+
+        let mut free_in = tables
+            .iter()
+            .filter(|t| t.capacity >= req.party_size)
+            .map(|t| match t.seated_at {
+                Some(seated_at) => dining_ms.saturating_sub(now.saturating_sub(seated_at)),
+                None => 0,
+            })
+            .collect::<Vec<_>>();
+        free_in.sort();
+        let largest = tables
+            .iter()
+            .map(|t| t.capacity)
+            .filter(|&c| c >= req.party_size)
+            .max()
+            .ok_or(Error::NoTableAvailable(req.party_size))?;
+
+        // everyone waiting who could take one of the same tables is ahead
+        let ahead = self
+            .list()
+            .filter(|e| e.state == WaitState::Waiting && e.party_size <= largest)
+            .count();
+        let rounds = (ahead / free_in.len()) as u64;
+        let wait = free_in[ahead % free_in.len()] + rounds * dining_ms;
+
+        let entry = WaitlistEntry {
+            entry_id: self.next_id,
+            party_size: req.party_size,
+            contact: req.contact,
+            joined_at: now,
+            quoted_minutes: wait.div_ceil(MS_PER_MINUTE),
+            state: WaitState::Waiting,
+            table_id: None,
+            waited_minutes: None,
+        };
+        self.insert(entry.clone());
+        Ok(entry)
+    }
+
+    /// The party that has waited longest among those that fit at a table
+    /// seating `capacity`.
+    pub fn next_for(&self, capacity: u32) -> Option<&WaitlistEntry> {
+        self.list()
+            .find(|e| e.state == WaitState::Waiting && e.party_size <= capacity)
+    }
+
+    /// Records that the party got `table_id`. Seating them is up to the
+    /// caller.
+    pub fn seat(&mut self, entry_id: u32, table_id: u32, now: u64) -> Result<WaitlistEntry, Error> {
+        self.waiting(entry_id)?;
+        self.close(entry_id, WaitState::Seated, Some(table_id), now)
+    }
+
+    pub fn leave(&mut self, entry_id: u32, now: u64) -> Result<WaitlistEntry, Error> {
+        self.waiting(entry_id)?;
+        self.close(entry_id, WaitState::Left, None, now)
+    }
+
+    fn close(
+        &mut self,
+        entry_id: u32,
+        state: WaitState,
+        table_id: Option<u32>,
+        now: u64,
+    ) -> Result<WaitlistEntry, Error> {
+        let entry = self
+            .entries
+            .get_mut(&entry_id)
+            .ok_or(Error::WaitlistEntryNotFound(entry_id))?;
+        entry.state = state;
+        entry.table_id = table_id;
+        entry.waited_minutes = Some(now.saturating_sub(entry.joined_at) / MS_PER_MINUTE);
+
+        Ok(entry.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: u64 = MS_PER_MINUTE;
+
+    fn request(party_size: u32) -> WaitlistRequest {
+        WaitlistRequest {
+            party_size,
+            contact: "kim".to_string(),
+        }
+    }
+
+    fn taken(capacity: u32, seated_at: u64) -> Occupancy {
+        Occupancy {
+            capacity,
+            seated_at: Some(seated_at),
+        }
+    }
+
+    #[test]
+    fn test_waitlist_quote() {
+        let mut waitlist = Waitlist::default();
+        let tables = [taken(2, 0), taken(4, 20 * M), taken(4, 50 * M)];
+
+        // the four-tops free up in 30 and 60 minutes with an hour long stay
+        let entry = waitlist.join(request(3), &tables, 60 * M, 50 * M).unwrap();
+        assert_eq!(entry.quoted_minutes, 30);
+        let entry = waitlist.join(request(4), &tables, 60 * M, 50 * M).unwrap();
+        assert_eq!(entry.quoted_minutes, 60);
+        // two parties ahead, the third waits for a second round
+        let entry = waitlist.join(request(4), &tables, 60 * M, 50 * M).unwrap();
+        assert_eq!(entry.quoted_minutes, 90);
+
+        let free = Occupancy {
+            capacity: 8,
+            seated_at: None,
+        };
+        // a free table and nobody in line means no wait
+        let entry = Waitlist::default()
+            .join(request(8), &[free], 60 * M, 50 * M)
+            .unwrap();
+        assert_eq!(entry.quoted_minutes, 0);
+        // but the three parties waiting go first, one stay each
+        let entry = waitlist.join(request(2), &[free], 60 * M, 50 * M).unwrap();
+        assert_eq!(entry.quoted_minutes, 180);
+        assert_eq!(
+            waitlist.join(request(9), &tables, 60 * M, 0).err(),
+            Some(Error::NoTableAvailable(9))
+        );
+    }
+
+    #[test]
+    fn test_waitlist_seat() {
+        let mut waitlist = Waitlist::default();
+        let tables = [taken(2, 0), taken(4, 0)];
+        waitlist.join(request(4), &tables, 60 * M, 0).unwrap();
+        waitlist.join(request(2), &tables, 60 * M, 0).unwrap();
+
+        // a two-top goes to the first party that fits
+        assert_eq!(waitlist.next_for(2).unwrap().entry_id, 1);
+        assert_eq!(waitlist.next_for(4).unwrap().entry_id, 0);
+
+        let entry = waitlist.seat(0, 7, 25 * M + 10).unwrap();
+        assert_eq!(entry.waited_minutes, Some(25));
+        assert_eq!(entry.table_id, Some(7));
+        assert_eq!(waitlist.next_for(4).unwrap().entry_id, 1);
+
+        waitlist.leave(1, 30 * M).unwrap();
+        assert!(waitlist.next_for(4).is_none());
+        assert_eq!(
+            waitlist.seat(1, 7, 30 * M).err(),
+            Some(Error::NotWaiting {
+                entry_id: 1,
+                state: WaitState::Left
+            })
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! Storage backend keeping a write-ahead log plus snapshots in plain files.
+//!
+//! Every change to a table is appended to `wal.log` as one JSON line and
+//! fsynced before the response goes out. A snapshot writes all lines to
+//! `snapshot.json` and empties the log. On startup the snapshot is loaded
+//! and the log replayed on top of it.
+//!
+//! A record only counts once its trailing newline is on disk. If the server
+//! died halfway through a write, the torn last record is dropped and cut off
+//! the file; a broken record anywhere else is an error. A write that fails
+//! while the server keeps running is cut off straight away, so a change the
+//! client was told had failed never comes back on replay.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::error::Error;
+use super::storage::{Op, Recovery, State, Storage};
+
+const LOG_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    op: Op,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    // the last record already contained in the snapshot
+    seq: u64,
+    #[serde(flatten)]
+    state: State,
+}
+
+pub struct WalStorage {
+    dir: PathBuf,
+    log: Mutex<Log>,
+}
+
+struct Log {
+    file: Box<dyn LogFile>,
+    // the last record written
+    seq: u64,
+    // set when a failed write could not be cut off the file
+    poisoned: bool,
+}
+
+/// The file operations the log needs, so tests can make them fail.
+trait LogFile: Write + Send {
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn len(&self) -> io::Result<u64> {
+        self.metadata().map(|m| m.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl WalStorage {
+    /// Opens the log in `dir`, creating it if needed, and reads back
+    /// everything stored so far.
+    pub fn open(dir: &Path) -> Result<(WalStorage, Recovery), Error> {
+        fs::create_dir_all(dir).map_err(|e| io_error(dir, e))?;
+
+        let (snapshot_seq, state) = match read_snapshot(&dir.join(SNAPSHOT_FILE))? {
+            Some(s) => (s.seq, s.state),
+            None => (0, State::default()),
+        };
+
+        let log_path = dir.join(LOG_FILE);
+        let records = read_log(&log_path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| io_error(&log_path, e))?;
+
+        let seq = records.last().map(|r| r.seq).unwrap_or(0).max(snapshot_seq);
+        // records up to the snapshot are left over from a crash between
+        // writing the snapshot and emptying the log
+        let ops = records
+            .into_iter()
+            .filter(|r| r.seq > snapshot_seq)
+            .map(|r| r.op)
+            .collect();
+
+        let wal = WalStorage {
+            dir: dir.to_path_buf(),
+            log: Mutex::new(Log {
+                file: Box::new(file),
+                seq,
+                poisoned: false,
+            }),
+        };
+        Ok((wal, Recovery { state, ops }))
+    }
+}
+
+impl Storage for WalStorage {
+    fn append(&self, op: Op) -> Result<(), Error> {
+        let mut log = self.log.lock().unwrap();
+        let path = self.dir.join(LOG_FILE);
+        if log.poisoned {
+            return Err(Error::Storage(format!(
+                "{}: a failed write is still in the log",
+                path.display()
+            )));
+        }
+        let record = Record {
+            seq: log.seq + 1,
+            op,
+        };
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+
+        let len = log.file.len().map_err(|e| io_error(&path, e))?;
+        let written = log
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| log.file.sync());
+        if let Err(e) = written {
+            // cut off whatever part of the record made it to the file
+            if log.file.set_len(len).and_then(|_| log.file.sync()).is_err() {
+                log.poisoned = true;
+            }
+            return Err(io_error(&path, e));
+        }
+
+        log.seq = record.seq;
+        Ok(())
+    }
+
+    fn snapshot(&self, state: State) -> Result<(), Error> {
+        let mut log = self.log.lock().unwrap();
+        let snapshot = Snapshot {
+            seq: log.seq,
+            state,
+        };
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+
+        // write aside and rename, so a crash leaves either snapshot intact
+        File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(serde_json::to_string(&snapshot).unwrap().as_bytes())?;
+                f.sync_all()
+            })
+            .map_err(|e| io_error(&tmp, e))?;
+        fs::rename(&tmp, &path).map_err(|e| io_error(&path, e))?;
+        File::open(&self.dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| io_error(&self.dir, e))?;
+
+        let log_path = self.dir.join(LOG_FILE);
+        log.file
+            .set_len(0)
+            .and_then(|_| log.file.sync())
+            .map_err(|e| io_error(&log_path, e))?;
+        // the snapshot holds everything, whatever the log had is gone
+        log.poisoned = false;
+        Ok(())
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::Storage(format!("{}: {}", path.display(), e))
+}
+
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read_to_string(path).map_err(|e| io_error(path, e))?;
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(|e| Error::Storage(format!("{}: {}", path.display(), e)))
+}
+
+/// Reads every complete record and cuts a torn last record off the file.
+fn read_log(path: &Path) -> Result<Vec<Record>, Error> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data = fs::read(path).map_err(|e| io_error(path, e))?;
+
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = match data[offset..].iter().position(|b| *b == b'\n') {
+            Some(n) => offset + n,
+            // no newline, the last write never finished
+            None => break,
+        };
+        match serde_json::from_slice::<Record>(&data[offset..end]) {
+            Ok(record) => records.push(record),
+            Err(_) if end + 1 == data.len() => break,
+            Err(e) => {
+                return Err(Error::Storage(format!(
+                    "{}: corrupt record at byte {}: {}",
+                    path.display(),
+                    offset,
+                    e
+                )))
+            }
+        }
+        offset = end + 1;
+    }
+
+    if offset < data.len() {
+        println!(
+            "storage: dropping a torn record at byte {} of {}",
+            offset,
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| {
+                f.set_len(offset as u64)?;
+                f.sync_all()
+            })
+            .map_err(|e| io_error(path, e))?;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::{Item, ItemState};
+    use crate::menu::MenuItem;
+    use crate::storage::TestDir;
+
+    fn item(line_id: u32, table_id: u32) -> Item {
+        Item::new(line_id, &MenuItem::sample(1), table_id, 5, 0)
+    }
+
+    fn state(items: Vec<Item>) -> State {
+        State {
+            items,
+            ..Default::default()
+        }
+    }
+
+    /// A log file whose writes fail after `budget` more bytes.
+    struct FailingFile {
+        file: File,
+        budget: usize,
+        truncate_fails: bool,
+    }
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let n = buf.len().min(self.budget);
+            self.budget -= n;
+            self.file.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl LogFile for FailingFile {
+        fn len(&self) -> io::Result<u64> {
+            self.file.len()
+        }
+
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            if self.truncate_fails {
+                return Err(io::Error::other("read-only"));
+            }
+            LogFile::set_len(&self.file, len)
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            self.file.sync()
+        }
+    }
+
+    fn fail_writes(wal: &WalStorage, budget: usize, truncate_fails: bool) {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(wal.dir.join(LOG_FILE))
+            .unwrap();
+        wal.log.lock().unwrap().file = Box::new(FailingFile {
+            file,
+            budget,
+            truncate_fails,
+        });
+    }
+
+    fn cancel(line_id: u32, table_id: u32) -> Op {
+        Op::SetState {
+            table_id,
+            item_id: 1,
+            line_id,
+            state: ItemState::Cancelled,
+            at: 1,
+        }
+    }
+
+    #[test]
+    fn test_wal_reopen() {
+        let dir = TestDir::new("wal-reopen");
+
+        let (wal, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert!(recovery.state.items.is_empty() && recovery.ops.is_empty());
+        wal.append(Op::Add { item: item(0, 1) }).unwrap();
+        wal.append(cancel(0, 1)).unwrap();
+        drop(wal);
+
+        let (wal, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(wal.log.lock().unwrap().seq, 2);
+        assert_eq!(
+            recovery.ops,
+            vec![Op::Add { item: item(0, 1) }, cancel(0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_wal_torn_record() {
+        let dir = TestDir::new("wal-torn");
+
+        let (wal, _) = WalStorage::open(&dir.0).unwrap();
+        wal.append(Op::Add { item: item(0, 1) }).unwrap();
+        drop(wal);
+
+        let log_path = dir.0.join(LOG_FILE);
+        let whole = fs::read(&log_path).unwrap();
+        let mut f = OpenOptions::new().append(true).open(&log_path).unwrap();
+        f.write_all(b"{\"seq\":2,\"op\":{\"ty").unwrap();
+        drop(f);
+
+        let (wal, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(recovery.ops.len(), 1);
+        assert_eq!(fs::read(&log_path).unwrap(), whole);
+
+        // the log keeps working after the torn record is cut off
+        wal.append(cancel(0, 1)).unwrap();
+        drop(wal);
+        let (_, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(recovery.ops.len(), 2);
+    }
+
+    #[test]
+    fn test_wal_corrupt_record() {
+        let dir = TestDir::new("wal-corrupt");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join(LOG_FILE), "garbage\n{\"seq\":1}\n").unwrap();
+
+        assert!(matches!(WalStorage::open(&dir.0), Err(Error::Storage(_))));
+    }
+
+    #[test]
+    fn test_wal_snapshot() {
+        let dir = TestDir::new("wal-snapshot");
+
+        let (wal, _) = WalStorage::open(&dir.0).unwrap();
+        wal.append(Op::Add { item: item(0, 1) }).unwrap();
+        wal.snapshot(state(vec![item(0, 1)])).unwrap();
+        wal.append(Op::Add { item: item(1, 2) }).unwrap();
+        drop(wal);
+
+        let (_, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(recovery.state.items, vec![item(0, 1)]);
+        assert_eq!(recovery.ops, vec![Op::Add { item: item(1, 2) }]);
+    }
+
+    #[test]
+    fn test_wal_skips_records_in_snapshot() {
+        let dir = TestDir::new("wal-skip");
+
+        let (wal, _) = WalStorage::open(&dir.0).unwrap();
+        wal.append(Op::Add { item: item(0, 1) }).unwrap();
+        let before = fs::read(dir.0.join(LOG_FILE)).unwrap();
+        wal.snapshot(state(vec![item(0, 1)])).unwrap();
+        drop(wal);
+
+        // as if the server died before the log was emptied
+        fs::write(dir.0.join(LOG_FILE), before).unwrap();
+        let (_, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(recovery.state.items.len(), 1);
+        assert!(recovery.ops.is_empty());
+    }
+
+    #[test]
+    fn test_wal_failed_write() {
+        let dir = TestDir::new("wal-failed-write");
+
+        let (wal, _) = WalStorage::open(&dir.0).unwrap();
+        wal.append(Op::Add { item: item(0, 1) }).unwrap();
+        let before = fs::read(dir.0.join(LOG_FILE)).unwrap();
+
+        // half a record is written, then cut off again
+        fail_writes(&wal, 10, false);
+        assert!(matches!(wal.append(cancel(0, 1)), Err(Error::Storage(_))));
+        assert_eq!(fs::read(dir.0.join(LOG_FILE)).unwrap(), before);
+
+        fail_writes(&wal, usize::MAX, false);
+        wal.append(Op::Add { item: item(1, 2) }).unwrap();
+        drop(wal);
+        let (wal, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(
+            recovery.ops,
+            vec![Op::Add { item: item(0, 1) }, Op::Add { item: item(1, 2) }]
+        );
+
+        // a write that cannot be cut off stops the log until a snapshot
+        fail_writes(&wal, 10, true);
+        assert!(wal.append(cancel(0, 1)).is_err());
+        fail_writes(&wal, usize::MAX, false);
+        assert!(wal.append(cancel(1, 2)).is_err());
+        wal.snapshot(state(vec![item(0, 1), item(1, 2)])).unwrap();
+        wal.append(cancel(1, 2)).unwrap();
+        drop(wal);
+        let (_, recovery) = WalStorage::open(&dir.0).unwrap();
+        assert_eq!(recovery.ops, vec![cancel(1, 2)]);
+    }
+}
@@ -0,0 +1,300 @@
+//! The parts of the WebSocket protocol (RFC 6455) the server speaks: the
+//! opening handshake and the frames that follow it.
+//!
+//! Like `http`, this works on byte buffers only. The server sends single
+//! unmasked frames and reads the masked ones clients send.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fmt;
+
+use super::http::{Request, Response, StatusCode};
+
+/// Appended to the client's key to prove the server understood the
+/// handshake.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Upper bound on a frame from a client. Clients have little to say.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum WsError {
+    Unmasked,
+    ReservedBits,
+    BadOpcode(u8),
+    BadControlFrame,
+    TooLarge,
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Unmasked => write!(f, "client frame is not masked"),
+            WsError::ReservedBits => write!(f, "reserved bits are set"),
+            WsError::BadOpcode(op) => write!(f, "unknown opcode {}", op),
+            WsError::BadControlFrame => write!(f, "malformed control frame"),
+            WsError::TooLarge => write!(f, "frame too large"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Result<Opcode, WsError> {
+        match bits {
+            0x0 => Ok(Opcode::Continuation),
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xa => Ok(Opcode::Pong),
+            _ => Err(WsError::BadOpcode(bits)),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn text(s: &str) -> Frame {
+        Frame {
+            fin: true,
+            opcode: Opcode::Text,
+            payload: s.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn pong(payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode: Opcode::Pong,
+            payload,
+        }
+    }
+
+    /// A close frame with a status code, e.g. 1000 for a normal close.
+    pub fn close(code: u16) -> Frame {
+        Frame {
+            fin: true,
+            opcode: Opcode::Close,
+            payload: code.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Encodes the frame as the server sends it, unmasked.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(len + 10);
+        out.push((self.fin as u8) << 7 | self.opcode.bits());
+        if len < 126 {
+            out.push(len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// Parses one client frame from the front of `buf`.
+///
+/// Returns `Ok(None)` while the frame is still incomplete, otherwise the
+/// unmasked frame together with the number of bytes it used.
+pub fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, WsError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    if buf[0] & 0x70 != 0 {
+        return Err(WsError::ReservedBits);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = Opcode::from_bits(buf[0] & 0x0f)?;
+    if buf[1] & 0x80 == 0 {
+        return Err(WsError::Unmasked);
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > MAX_FRAME_SIZE as u64 {
+        return Err(WsError::TooLarge);
+    }
+    let len = len as usize;
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(WsError::BadControlFrame);
+    }
+
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        pos + len,
+    )))
+}
+
+/// Whether `req` asks to switch the connection over to WebSocket.
+pub fn is_upgrade(req: &Request) -> bool {
+    let has_token = |name: &str, token: &str| {
+        req.header(name)
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    has_token("upgrade", "websocket") && has_token("connection", "upgrade")
+}
+
+/// The `Sec-WebSocket-Accept` value answering the client's key.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(sha1.digest().bytes())
+}
+
+/// The `101 Switching Protocols` response to an upgrade request, or `None`
+/// if the handshake is not one this server can take.
+pub fn handshake(req: &Request) -> Option<Response> {
+    if req.method != "GET" || req.header("sec-websocket-version") != Some("13") {
+        return None;
+    }
+    let key = req.header("sec-websocket-key")?;
+
+    let res = Response::new(StatusCode::SwitchingProtocols, String::new())
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key));
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MAX_REQUEST_SIZE;
+    use crate::http;
+
+    /// Masks `frame` the way a client would.
+    fn client_bytes(frame: &Frame) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut out = frame.to_bytes();
+        let start = out.len() - frame.payload.len();
+        out[1] |= 0x80;
+        let masked = out
+            .split_off(start)
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect::<Vec<_>>();
+        out.extend_from_slice(&mask);
+        out.extend(masked);
+        out
+    }
+
+    #[test]
+    fn test_websocket_handshake() {
+        // the example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let raw = "GET /subscribe HTTP/1.1\r\nUpgrade: websocket\r\n\
+                   Connection: keep-alive, Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                   Sec-WebSocket-Version: 13\r\n\r\n";
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        assert!(is_upgrade(&req));
+        let res = handshake(&req).unwrap();
+        assert_eq!(res.status, StatusCode::SwitchingProtocols);
+        assert!(res.headers.contains(&(
+            "Sec-WebSocket-Accept".to_string(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()
+        )));
+
+        let raw = "GET /subscribe HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let (req, _) = http::parse_request(raw.as_bytes(), MAX_REQUEST_SIZE)
+            .unwrap()
+            .unwrap();
+        assert!(is_upgrade(&req));
+        assert_eq!(handshake(&req), None);
+    }
+
+    #[test]
+    fn test_websocket_frames() {
+        let frame = Frame::text("hello");
+        let bytes = client_bytes(&frame);
+        assert_eq!(parse_frame(&bytes[..4]), Ok(None));
+        assert_eq!(parse_frame(&bytes), Ok(Some((frame, bytes.len()))));
+
+        let long = Frame::text(&"x".repeat(300));
+        let bytes = long.to_bytes();
+        assert_eq!(&bytes[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(parse_frame(&bytes), Err(WsError::Unmasked));
+        let bytes = client_bytes(&long);
+        assert_eq!(parse_frame(&bytes).unwrap().unwrap().0, long);
+
+        let ping = Frame {
+            fin: false,
+            opcode: Opcode::Ping,
+            payload: vec![],
+        };
+        assert_eq!(
+            parse_frame(&client_bytes(&ping)),
+            Err(WsError::BadControlFrame)
+        );
+        assert_eq!(Frame::close(1000).to_bytes(), vec![0x88, 2, 0x03, 0xe8]);
+    }
+}